@@ -30,6 +30,15 @@ MNT_DIR=/root
 # Command-line parameters
 ROOT=/dev/sda
 BOOT=normal
+# Listen address of the attestation server. With a vsock address, e.g. vsock:8080,
+# the guest does not need any networking for attestation
+ATTESTATION_LISTEN=0.0.0.0:80
+# If set, the VM pulls its disk key from the key broker at this URL instead of
+# waiting for the owner, e.g. https://kbs.example.com or vsock://2:8080
+ATTESTATION_PULL=
+# If set, only accept secrets signed by this owner key: a hex encoded Ed25519
+# public key or host_data to use the HOST_DATA of the attestation report
+ATTESTATION_OWNER_KEY=
 
 # Parse command line options
 # shellcheck disable=SC2013
@@ -47,6 +56,15 @@ for x in $(cat /proc/cmdline); do
 	verity_roothash=*)
 		VERITY_ROOT_HASH=${x#verity_roothash=}
 		;;
+	attestation_listen=*)
+		ATTESTATION_LISTEN=${x#attestation_listen=}
+		;;
+	attestation_pull=*)
+		ATTESTATION_PULL=${x#attestation_pull=}
+		;;
+	attestation_owner_key=*)
+		ATTESTATION_OWNER_KEY=${x#attestation_owner_key=}
+		;;
 	esac
 done
 
@@ -62,19 +80,36 @@ boot_encrypted() {
     #used for getting the attestation report
     modprobe sev-guest
 
-    #kernel module for networking
-    modprobe virtio_net
+    if [ -n "$ATTESTATION_PULL" ]; then
+        ATTESTATION_ARGS="--pull $ATTESTATION_PULL"
+    else
+        ATTESTATION_ARGS="--listen $ATTESTATION_LISTEN"
+    fi
+    if [ -n "$ATTESTATION_OWNER_KEY" ]; then
+        ATTESTATION_ARGS="$ATTESTATION_ARGS --owner-public-key $ATTESTATION_OWNER_KEY"
+    fi
+
+    case ${ATTESTATION_PULL:-$ATTESTATION_LISTEN} in
+    vsock:*)
+        #kernel module for host-guest communication without networking
+        modprobe vmw_vsock_virtio_transport
+        ;;
+    *)
+        #kernel module for networking
+        modprobe virtio_net
+
+        # assign IP address
+        dhclient
+        ;;
+    esac
 
-    # assign IP address
-    dhclient
+    ROOT_FS_CRYPTDEV="$(basename $ROOT)_crypt"
 
     #start network server handle attestation + disk pw receival
-    /bin/server || exit 1
-    PW=$(cat ./disk_key.txt)
-    shred -u ./disk_key.txt
-
-    ROOT_FS_CRYPTDEV="$(basename $ROOT)_crypt"
-    echo "${PW}" | cryptsetup luksOpen "$ROOT" "$ROOT_FS_CRYPTDEV"
+    #the disk pw is piped straight into cryptsetup and never touches the filesystem
+    #--key-file=- makes cryptsetup use the input byte for byte, so binary keys work as well
+    # shellcheck disable=SC2086
+    /bin/server $ATTESTATION_ARGS --secret-output stdout | cryptsetup luksOpen --key-file=- "$ROOT" "$ROOT_FS_CRYPTDEV" || exit 1
 
     #activate lvm2 (used by ubuntu as default when using crypto disk)
     # vgscan --mknodes
@@ -114,7 +149,7 @@ boot_verity() {
 
     # generate attestation report with SSH fingerprint as user data
     FINGERPRINT=`ssh-keygen -lf $MNT_DIR/etc/ssh/ssh_host_ecdsa_key.pub | awk '{ print $2 }' | cut -d ":" -f 2`
-    /bin/get_report --report-data $FINGERPRINT --out $MNT_DIR/etc/report.json
+    /bin/get_report --ssh-fingerprint $FINGERPRINT --out $MNT_DIR/etc/report.json
 }
 
 #default launch config for sev uses virto as device driver
@@ -2,29 +2,45 @@
 //! provision a disk encryption key to the VM
 use std::{
     fs::{self, File},
-    io::Write, str::FromStr, time::Duration,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
 };
 
 use attestation_server::{
+    broker::{KeyBroker, ReleasePolicy},
     calc_expected_ld::VMDescription,
-    req_resp_ds::{aead_enc, AttestationRequest, WrappedDiskKey},
-    snp_attestation::ReportData,
+    control_channel::{ControlChannel, ControlRequest, ControlResponse, Role, CHANNEL_KEY_BYTES},
+    key_source::KeySource,
+    owner_auth::OwnerSigningKey,
+    ra_tls::{ra_tls_client_config, RaTlsVerifier},
+    req_resp_ds::{
+        AttestationRequest, AttestationResponse, Capability, KeyRotation, RaTlsDiskKey,
+        ReportBinding, RequestMessage, ResponseMessage, RotationConfirmation, SecretPayload,
+        WrappedChannelKey, WrappedDiskKey, WrappedKeyRotation, WrappedSecretBundle, WrappingScheme,
+        MIN_PROTOCOL_VERSION,
+    },
+    secret::{set_mlock, SecretBytes},
+    secret_bundle::{SecretBundle, SecretManifest},
+    snp_attestation::ReportClaims,
     snp_validate_report::{
-        parse_id_block_data, verify_and_check_report, CachingVCEKDownloader, ReportDataMismatchSnafu, ReportVerificationError
+        evaluate_report, parse_id_block_data, verify_and_check_report, CachingVCEKDownloader,
+        ProductName, ReportVerificationError, VerificationPolicy,
+    },
+    transport::{
+        ClientTransport, Connection, HttpClientTransport, ListenAddr, VsockClientTransport,
+        VSOCK_SCHEME,
     },
+    verdict::{Verdict, VerdictFormat},
 };
 
-use clap::Parser;
+use clap::{ArgGroup, Parser, ValueEnum};
 use indicatif::ProgressBar;
 use reqwest::{blocking::Client, Url};
-use ring::{
-    agreement,
-    rand::{SecureRandom, SystemRandom},
-};
-use sev::
-    firmware::guest::AttestationReport;
-use snafu::{ FromString, ResultExt, Whatever};
+use ring::rand::{SecureRandom, SystemRandom};
+use sev::firmware::guest::AttestationReport;
 use snafu::prelude::*;
+use snafu::{FromString, ResultExt, Whatever};
 
 #[derive(Debug, Snafu)]
 enum UserError {
@@ -41,14 +57,83 @@ enum UserError {
 }
 
 #[derive(Parser, Debug)]
+#[command(group(ArgGroup::new("payload")))]
+#[command(group(ArgGroup::new("current_key").conflicts_with_all(["ra_tls", "secrets", "broker_listen"])))]
 struct Args {
     #[arg(long, default_value = "http://localhost:8080")]
-    ///URL of the Server running in the VM that we want to attest
+    ///URL of the Server running in the VM that we want to attest. Use `vsock://<cid>:<port>`
+    ///to connect via vsock
     server_url: String,
 
     #[arg(long)]
-    ///Disk encryption key that should be injected into the VM
-    disk_key: String,
+    ///Attest the server via its RA-TLS certificate and send the disk key through the
+    ///TLS channel. Requires an https `server_url` and the server running with `RA_TLS`
+    ra_tls: bool,
+
+    #[arg(long, group = "payload")]
+    ///Disk encryption passphrase that should be injected into the VM. Shows up in `ps` and
+    ///the shell history, prefer `disk_key_source`
+    disk_key: Option<String>,
+
+    #[arg(long, group = "payload")]
+    ///File with the disk encryption key that should be injected into the VM. The content is
+    ///used as is and may be binary, e.g. a random LUKS key file
+    disk_key_file: Option<PathBuf>,
+
+    #[arg(long, group = "payload")]
+    ///Read the disk encryption key that should be injected into the VM from `stdin`,
+    ///`file:<path>`, `env:<VAR>`, `keystore:<path>#<name>` or `pkcs11:<module>#<label>`.
+    ///Keystore passphrase and PKCS#11 PIN are prompted for unless set via
+    ///`SNPGUARD_KEYSTORE_PASSPHRASE` or `SNPGUARD_PKCS11_PIN`
+    disk_key_source: Option<KeySource>,
+
+    #[arg(long, group = "payload")]
+    ///Path to a TOML manifest with several named secrets that should be injected into the VM.
+    ///See `examples/secrets-manifest.toml`
+    secrets: Option<PathBuf>,
+
+    #[arg(long, group = "current_key")]
+    ///Rotate the disk key of a running VM instead of injecting it: the key given via `disk_key`,
+    ///`disk_key_file` or `disk_key_source` is added to the LUKS device of the server, which must run with
+    ///`LUKS_DEVICE`. This is the key that currently unlocks the device
+    current_disk_key: Option<String>,
+
+    #[arg(long, group = "current_key")]
+    ///Like `current_disk_key`, but read the current key from a file
+    current_disk_key_file: Option<PathBuf>,
+
+    #[arg(long, group = "current_key")]
+    ///Like `current_disk_key`, but read the current key from a source, see `disk_key_source`
+    current_disk_key_source: Option<KeySource>,
+
+    #[arg(long, requires("current_key"))]
+    ///Do not confirm the key rotation, i.e. keep the keyslot of the current key
+    keep_old_key: bool,
+
+    #[arg(long, value_enum, conflicts_with_all(["payload", "ra_tls", "broker_listen", "current_key"]))]
+    ///Open an encrypted control channel to a server that runs with `CONTROL_CHANNEL` and send
+    ///these owner commands in the given order. May be passed several times. Requires `owner_key`
+    control: Vec<ControlCommand>,
+
+    #[arg(long, conflicts_with_all(["payload", "ra_tls", "broker_listen", "current_key"]))]
+    ///Deliver the secrets from this manifest via the control channel, before running the
+    ///commands from `control`
+    control_secrets: Option<PathBuf>,
+
+    #[arg(long, conflicts_with_all(["ra_tls", "secrets"]))]
+    ///Instead of connecting to a VM, act as key broker for VMs in pull mode: serve the disk key
+    ///on this address to every VM whose attestation report matches `vm_definition`.
+    ///Runs until interrupted
+    broker_listen: Option<ListenAddr>,
+
+    #[arg(long)]
+    ///Lock buffers with secrets into memory, so that they are never written to swap
+    mlock_secrets: bool,
+
+    #[arg(long, env = "OWNER_KEY")]
+    ///PEM file with the Ed25519 owner key. Secrets are signed with it, which is required if
+    ///the VM pins the owner public key
+    owner_key: Option<PathBuf>,
 
     #[arg(long)]
     ///Config file used to compute the expected vm hash
@@ -74,23 +159,145 @@ struct Args {
     ///that the attestation report contains the corresponding data. If used, you also need to
     ///specify `id_block_path`
     author_block_path: Option<String>,
+
+    #[arg(long)]
+    ///TOML file with verification rules, see examples/verification-policy.toml. Its rules replace
+    ///the checks derived from `vm_definition` and the id block for the fields they cover
+    policy: Option<PathBuf>,
+
+    #[arg(long)]
+    ///Evaluate all checks, including the signature, instead of stopping at the first failure,
+    ///and print the result of each
+    all_checks: bool,
+
+    #[arg(long)]
+    ///Write a machine readable verdict with the decoded report, every check, the certificate
+    ///chain and the final result to this file. Implies evaluating all checks. In broker mode,
+    ///the file contains the verdict for the most recent VM
+    verdict: Option<PathBuf>,
+
+    #[arg(long, default_value_t = VerdictFormat::Json)]
+    ///Format of `verdict`: json or junit
+    verdict_format: VerdictFormat,
+
+    #[arg(long, default_value_t = MIN_PROTOCOL_VERSION)]
+    ///Lowest protocol version to accept. Versions below 3 lack the transcript binding and the
+    ///hashed report claims, and a man in the middle can force them by rewriting the server info.
+    ///Only lower this for servers that cannot be updated
+    min_protocol_version: u32,
+}
+
+///Owner command sent via the control channel
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ControlCommand {
+    ///Print the uptime and state of the server
+    Status,
+    ///Request a fresh attestation report and verify it
+    Report,
+    ///Stop the server
+    Shutdown,
+}
+
+///Secrets that are provisioned to the VM
+enum Payload {
+    DiskKey(SecretPayload),
+    Bundle(SecretBundle),
+    Rotation(KeyRotation),
+    ///Owner commands for a server that keeps running after secret injection
+    Control {
+        secrets: Option<SecretBundle>,
+        commands: Vec<ControlCommand>,
+    },
+}
+
+impl Payload {
+    fn from_args(args: &Args) -> Result<Self, Whatever> {
+        if !args.control.is_empty() || args.control_secrets.is_some() {
+            let secrets = match &args.control_secrets {
+                Some(manifest) => Some(SecretManifest::load(manifest)?),
+                None => None,
+            };
+            return Ok(Payload::Control {
+                secrets,
+                commands: args.control.clone(),
+            });
+        }
+        if args.disk_key_source == Some(KeySource::Stdin)
+            && args.current_disk_key_source == Some(KeySource::Stdin)
+        {
+            whatever!("only one key can be read from stdin");
+        }
+        let disk_key = load_key(&args.disk_key, &args.disk_key_file, &args.disk_key_source)
+            .whatever_context("failed to load disk key")?;
+        let payload = match (disk_key, &args.secrets) {
+            (Some(disk_key), None) => Payload::DiskKey(disk_key),
+            (None, Some(manifest)) => Payload::Bundle(SecretManifest::load(manifest)?),
+            _ => whatever!("specify exactly one of --disk-key, --disk-key-file, --disk-key-source and --secrets, or a control command"),
+        };
+        let current_key = match load_key(
+            &args.current_disk_key,
+            &args.current_disk_key_file,
+            &args.current_disk_key_source,
+        )
+        .whatever_context("failed to load current disk key")?
+        {
+            Some(v) => v,
+            None => return Ok(payload),
+        };
+        let rotation = match payload {
+            Payload::DiskKey(new_key) => KeyRotation {
+                current_key,
+                new_key,
+            },
+            _ => whatever!(
+                "key rotation requires --disk-key, --disk-key-file or --disk-key-source as new key"
+            ),
+        };
+        rotation.validate()?;
+        Ok(Payload::Rotation(rotation))
+    }
+
+    ///Server capability required to inject this payload
+    fn capability(&self) -> Capability {
+        match self {
+            Payload::DiskKey(_) => Capability::SecretInjection,
+            Payload::Bundle(_) => Capability::SecretBundle,
+            Payload::Rotation(_) => Capability::KeyRotation,
+            Payload::Control { .. } => Capability::ControlChannel,
+        }
+    }
+}
+
+///Key from the literal `value`, the content of `file` or `source`, whichever is set
+fn load_key(
+    value: &Option<String>,
+    file: &Option<PathBuf>,
+    source: &Option<KeySource>,
+) -> Result<Option<SecretPayload>, Whatever> {
+    let key = match (value, file, source) {
+        (Some(v), None, None) => SecretPayload::utf8(v),
+        (None, Some(path), None) => KeySource::File(path.clone()).load()?,
+        (None, None, Some(source)) => source.load()?,
+        (None, None, None) => return Ok(None),
+        _ => whatever!("the key must be given only once"),
+    };
+    key.validate()?;
+    Ok(Some(key))
 }
 
 #[snafu::report]
 fn main() -> Result<(), UserError> {
     let args = Args::parse();
+    set_mlock(args.mlock_secrets);
     match run(&args) {
         Ok(_) => {
             println!("Success!");
             Ok(())
         }
         Err(e) => {
-            match e {
-                UserError::InvalidReport { .. } => {
-                    println!("Program executed successfully but attestation report was invalid.\nIn case of mismatching values, verify that the data in the vm config file {} matches your host.
-                    \nAfter updating the config file, you may simply run this command again.\nPlease find more details on the verification error below.",&args.vm_definition);
-                }
-                _ => (),
+            if let UserError::InvalidReport { .. } = e {
+                println!("Program executed successfully but attestation report was invalid.\nIn case of mismatching values, verify that the data in the vm config file {} matches your host.
+                \nAfter updating the config file, you may simply run this command again.\nPlease find more details on the verification error below.",&args.vm_definition);
             }
             Err(e)
         }
@@ -110,7 +317,9 @@ fn run(args: &Args) -> Result<(), UserError> {
         vm_description.kernel_cmdline = cmdline_override.clone();
     }
 
-    let expected_ld = vm_description.compute_expected_hash().whatever_context("failed to compute the expected launch digest based on the vm config")?;
+    let expected_ld = vm_description
+        .compute_expected_hash()
+        .whatever_context("failed to compute the expected launch digest based on the vm config")?;
 
     //If both the id block and the id auth block flag were specified, this contains the parsed data
     //as well as a representation for checking the attestation report
@@ -118,9 +327,9 @@ fn run(args: &Args) -> Result<(), UserError> {
     if let (Some(id_block_path), Some(id_auth_block_path)) =
         (&args.id_block_path, &args.author_block_path)
     {
-        let raw_id_block = fs::read(&id_block_path)
+        let raw_id_block = fs::read(id_block_path)
             .whatever_context(format!("failed to read id block from {}", &id_block_path))?;
-        let raw_id_auth_block = fs::read(&id_auth_block_path).whatever_context(format!(
+        let raw_id_auth_block = fs::read(id_auth_block_path).whatever_context(format!(
             "failed to read id auth block from {}",
             &id_auth_block_path
         ))?;
@@ -132,6 +341,68 @@ fn run(args: &Args) -> Result<(), UserError> {
         id_data = None;
     }
 
+    let owner_key = match &args.owner_key {
+        Some(path) => {
+            Some(OwnerSigningKey::load(path).whatever_context("failed to load owner key")?)
+        }
+        None => None,
+    };
+
+    let mut policy = VerificationPolicy::from_vm_description(&vm_description, expected_ld)
+        .whatever_context("failed to derive verification policy from the vm config")?;
+    if let Some((_, _, id_block_data)) = &id_data {
+        policy = policy.with_id_block(id_block_data);
+    }
+    if vm_description.owner_key_in_host_data {
+        let owner_key = match &owner_key {
+            Some(v) => v,
+            None => {
+                whatever!("the vm config sets owner_key_in_host_data, which requires --owner-key")
+            }
+        };
+        policy = policy.with_host_data(
+            owner_key
+                .public_key()
+                .whatever_context("failed to get owner public key")?
+                .0,
+        );
+    }
+    if let Some(path) = &args.policy {
+        policy = VerificationPolicy::load(path)
+            .whatever_context("failed to load verification policy")?
+            .or(policy);
+    }
+    let checker = ReportChecker {
+        host_cpu_family: vm_description.host_cpu_family,
+        policy,
+        expected_ld,
+        all_checks: args.all_checks,
+        verdict: args.verdict.clone(),
+        verdict_format: args.verdict_format,
+    };
+
+    let payload = Payload::from_args(args).whatever_context("failed to load secrets")?;
+
+    if let Some(listen) = &args.broker_listen {
+        let disk_key = match payload {
+            Payload::DiskKey(v) => v,
+            Payload::Bundle(_) | Payload::Rotation(_) | Payload::Control { .. } => {
+                whatever!("the key broker only serves a disk key")
+            }
+        };
+        return run_broker(
+            listen,
+            disk_key,
+            owner_key,
+            &checker,
+            args.min_protocol_version,
+        );
+    }
+
+    if args.ra_tls {
+        return run_ra_tls(args, &payload, owner_key.as_ref(), &checker);
+    }
+
     //Phase1: Request attestation report from server and validate it
     //As part of the report, we get a public key agreement key
 
@@ -139,125 +410,538 @@ fn run(args: &Args) -> Result<(), UserError> {
 
     let mut buffer = [0u8; 8];
 
-    rng.fill(&mut buffer).map_err(|_| Whatever::without_source("failed to sample randomness".to_string())).whatever_context("failed to sample nonce")?;
+    rng.fill(&mut buffer)
+        .map_err(|_| Whatever::without_source("failed to sample randomness".to_string()))
+        .whatever_context("failed to sample nonce")?;
 
     // Convert the bytes to u64
     let nonce = u64::from_le_bytes(buffer);
     let att_req = AttestationRequest { nonce };
-    let client = Client::new();
 
-    //This is the first network request that we send. The VM might still be booting up, show some 
-    let wait_for_request_bar = ProgressBar::new_spinner().with_message("Waiting for attestation server").with_elapsed(Duration::from_secs(0));
+    //This is the first network request that we send. The VM might still be booting up, show some
+    let wait_for_request_bar = ProgressBar::new_spinner()
+        .with_message("Waiting for attestation server")
+        .with_elapsed(Duration::from_secs(0));
     wait_for_request_bar.enable_steady_tick(Duration::from_millis(100));
-    let reset_endpoint = Url::from_str(&args.server_url).whatever_context(format!("Failed to parse server url {}",&args.server_url))?.join("reset").whatever_context("failed to assemble reset endpoint URL")?;
-    client.post(reset_endpoint.clone()).send().whatever_context(format!("failed to send init request to attestation server at {:?}",reset_endpoint))?;
+    let server_url = Url::from_str(&args.server_url)
+        .whatever_context(format!("Failed to parse server url {}", &args.server_url))?;
+    let transport: Box<dyn ClientTransport> = if server_url.scheme() == VSOCK_SCHEME {
+        Box::new(VsockClientTransport::from_url(&server_url).whatever_context("invalid vsock url")?)
+    } else {
+        Box::new(
+            HttpClientTransport::new(Client::new(), &server_url)
+                .whatever_context("invalid server url")?,
+        )
+    };
+    let conn = Connection::connect(transport, payload.capability(), args.min_protocol_version)
+        .whatever_context(format!(
+            "failed to connect to attestation server at {}",
+            &server_url
+        ))?;
     wait_for_request_bar.finish();
 
     println!("Requesting attestation report from {}", &args.server_url);
-    let attestation_report: AttestationReport = client
-        .post(&args.server_url)
-        .json(&att_req)
-        .send()
-        .whatever_context("failed to send nonce request")?
-        .json()
-        .whatever_context("failed to parse attestation report request result as json")?;
+    let mut att_resp = match conn
+        .call(RequestMessage::Report(att_req))
+        .whatever_context("failed to request attestation report")?
+    {
+        ResponseMessage::Report(v) => v,
+        other => whatever!("unexpected response to report request: {:?}", other),
+    };
+    let attestation_report = att_resp.report;
 
     println!("Received report");
 
-    if let Some(dump_path) = &args.dump_report {
-        let f = File::create(dump_path).whatever_context(format!("failed to create report dump file at {}",dump_path))?;
-        serde_json::to_writer_pretty(f, &attestation_report)
-            .whatever_context(format!("failed to serialize attestation report to file {}",&dump_path))?;
+    dump_report(args, &attestation_report)?;
+
+    let binding = ReportBinding::for_version(conn.version(), args.min_protocol_version)
+        .whatever_context("refusing the negotiated protocol version")?;
+    //servers before version 3 do not send the public key next to the report
+    att_resp.public_key = binding.public_key(&attestation_report.report_data, att_resp.public_key);
+    let report_data_validator =
+        |vm_data: [u8; 64]| binding.check(&vm_data, nonce, att_resp.public_key);
+    checker.check(&attestation_report, report_data_validator)?;
+
+    //Phase2: Derive shared secret and send encrypted secrets to server
+
+    //Verified report and have pulic key agreement key from server (authenticity attested by report signature)
+    //Generate our key pair and encrypt the secrets with a key derived from the shared secret and the report
+    //Then send encrypted secrets + our public key to server
+    let scheme = WrappingScheme::for_version(conn.version());
+    let message = match &payload {
+        Payload::DiskKey(disk_key) => {
+            println!("Wrapping disk encryption key");
+            let (client_public_key, wrapped_disk_key) = scheme
+                .wrap(
+                    &attestation_report,
+                    &att_resp.public_key,
+                    disk_key.data.expose(),
+                )
+                .whatever_context("failed to wrap disk encryption key")?;
+            let mut wrapped_key = WrappedDiskKey {
+                session_id: att_resp.session_id,
+                wrapped_disk_key,
+                client_public_key,
+                encoding: disk_key.encoding,
+                owner_signature: None,
+            };
+            if let Some(owner_key) = &owner_key {
+                owner_key
+                    .sign(&mut wrapped_key, &attestation_report)
+                    .whatever_context("failed to sign disk encryption key")?;
+            }
+            RequestMessage::InjectSecret(wrapped_key)
+        }
+        Payload::Bundle(bundle) => {
+            println!("Wrapping bundle of {} secrets", bundle.secrets.len());
+            let raw_bundle = SecretBytes::to_json(bundle)
+                .whatever_context("failed to serialize secret bundle")?;
+            let (client_public_key, wrapped_bundle) = scheme
+                .wrap(
+                    &attestation_report,
+                    &att_resp.public_key,
+                    raw_bundle.expose(),
+                )
+                .whatever_context("failed to wrap secret bundle")?;
+            let mut wrapped_bundle = WrappedSecretBundle {
+                session_id: att_resp.session_id,
+                wrapped_bundle,
+                client_public_key,
+                owner_signature: None,
+            };
+            if let Some(owner_key) = &owner_key {
+                owner_key
+                    .sign(&mut wrapped_bundle, &attestation_report)
+                    .whatever_context("failed to sign secret bundle")?;
+            }
+            RequestMessage::InjectSecretBundle(wrapped_bundle)
+        }
+        Payload::Rotation(rotation) => {
+            println!("Wrapping current and new disk encryption key");
+            let raw_rotation = SecretBytes::to_json(rotation)
+                .whatever_context("failed to serialize key rotation")?;
+            let (client_public_key, wrapped_rotation) = scheme
+                .wrap(
+                    &attestation_report,
+                    &att_resp.public_key,
+                    raw_rotation.expose(),
+                )
+                .whatever_context("failed to wrap key rotation")?;
+            let mut wrapped_rotation = WrappedKeyRotation {
+                session_id: att_resp.session_id,
+                wrapped_rotation,
+                client_public_key,
+                owner_signature: None,
+            };
+            if let Some(owner_key) = &owner_key {
+                owner_key
+                    .sign(&mut wrapped_rotation, &attestation_report)
+                    .whatever_context("failed to sign key rotation")?;
+            }
+            return rotate_key(
+                &conn,
+                wrapped_rotation,
+                owner_key.as_ref(),
+                &attestation_report,
+                args.keep_old_key,
+            );
+        }
+        Payload::Control { secrets, commands } => {
+            let owner_key = match &owner_key {
+                Some(v) => v,
+                None => whatever!("the control channel requires --owner-key"),
+            };
+            let verify_report = |report: &AttestationReport, nonce: u64| {
+                let claims = ReportClaims::new().with_nonce(nonce);
+                checker.check(report, |vm_data: [u8; 64]| claims.check(&vm_data))
+            };
+            return run_control(
+                &conn,
+                scheme,
+                &att_resp,
+                owner_key,
+                secrets.as_ref(),
+                commands,
+                verify_report,
+            );
+        }
+    };
+
+    println!("Sending wrapped secrets to server");
+    match conn
+        .call(message)
+        .whatever_context("failed to send wrapped secrets")?
+    {
+        ResponseMessage::SecretAccepted => (),
+        other => whatever!("unexpected response to secret injection: {:?}", other),
     }
 
-    println!("Verifying Report");
-    let vcek_resolver =
-    CachingVCEKDownloader::new().whatever_context("failed to instantiate vcek downloader")?;
-let vcek_cert = vcek_resolver
-    .get_vceck_cert(
-        attestation_report.chip_id,
-        vm_description.host_cpu_family,
-        &attestation_report.committed_tcb,
-    )
-    .whatever_context(format!(
-        "failed to download vcek cert for cpu family {}, chip_id 0x{}",
-        &vm_description.host_cpu_family,
-        hex::encode(attestation_report.chip_id)
-    ))?;
+    Ok(())
+}
 
-    let report_data_validator = |vm_data: [u8; 64]| {
-        let report_data: ReportData = vm_data.clone().into();
-        if nonce != report_data.nonce {
-            return ReportDataMismatchSnafu{
-                expected:format!("0x{:x}",report_data.nonce),
-                got: format!("0x{:x}",nonce),
-            }.fail();
+///Open a control channel in the attested session of `att_resp`, deliver `secrets` and run `commands`.
+///`verify_report` checks fresh reports against the vm config
+fn run_control<F>(
+    conn: &Connection,
+    scheme: WrappingScheme,
+    att_resp: &AttestationResponse,
+    owner_key: &OwnerSigningKey,
+    secrets: Option<&SecretBundle>,
+    commands: &[ControlCommand],
+    verify_report: F,
+) -> Result<(), UserError>
+where
+    F: Fn(&AttestationReport, u64) -> Result<(), UserError>,
+{
+    let rng = SystemRandom::new();
+    let mut channel_key = SecretBytes::new(vec![0u8; CHANNEL_KEY_BYTES]);
+    rng.fill(channel_key.expose_mut())
+        .map_err(|_| Whatever::without_source("failed to sample randomness".to_string()))
+        .whatever_context("failed to sample channel key")?;
+    let (client_public_key, wrapped_channel_key) = scheme
+        .wrap(&att_resp.report, &att_resp.public_key, channel_key.expose())
+        .whatever_context("failed to wrap channel key")?;
+    let mut wrapped_key = WrappedChannelKey {
+        session_id: att_resp.session_id.clone(),
+        wrapped_channel_key,
+        client_public_key,
+        owner_signature: None,
+    };
+    owner_key
+        .sign(&mut wrapped_key, &att_resp.report)
+        .whatever_context("failed to sign channel key")?;
+    println!("Opening control channel");
+    let channel_id = match conn
+        .call(RequestMessage::OpenControlChannel(wrapped_key))
+        .whatever_context("failed to open control channel")?
+    {
+        ResponseMessage::ControlChannelOpened(v) => v.channel_id,
+        other => whatever!(
+            "unexpected response to control channel request: {:?}",
+            other
+        ),
+    };
+    let channel_key = channel_key
+        .expose()
+        .try_into()
+        .whatever_context("unexpected channel key length")?;
+    let mut channel = ControlChannel::new(channel_key, &att_resp.report, Role::Client)
+        .whatever_context("failed to set up control channel")?;
+    let mut call = |request: ControlRequest| -> Result<ControlResponse, Whatever> {
+        let sealed = channel.seal(&channel_id, &request)?;
+        match conn.call(RequestMessage::Control(sealed))? {
+            ResponseMessage::Control(v) => channel.open(v),
+            other => whatever!("unexpected response on control channel: {:?}", other),
         }
-        Ok(())
     };
 
-    let id_block_data = if let Some((_, _, v)) = id_data {
-        Some(v)
-    } else {
-        None
+    if let Some(bundle) = secrets {
+        println!(
+            "Sending {} secrets via control channel",
+            bundle.secrets.len()
+        );
+        match call(ControlRequest::InjectSecretBundle(bundle.clone()))
+            .whatever_context("failed to send secrets")?
+        {
+            ControlResponse::SecretAccepted => println!("Secrets were delivered"),
+            ControlResponse::Error(e) => whatever!("server failed to deliver secrets: {}", e),
+            other => whatever!("unexpected response to secrets: {:?}", other),
+        }
+    }
+    for command in commands {
+        let mut raw_nonce = [0u8; 8];
+        rng.fill(&mut raw_nonce)
+            .map_err(|_| Whatever::without_source("failed to sample randomness".to_string()))
+            .whatever_context("failed to sample nonce")?;
+        let nonce = u64::from_le_bytes(raw_nonce);
+        let request = match command {
+            ControlCommand::Status => ControlRequest::Status,
+            ControlCommand::Report => ControlRequest::Report(AttestationRequest { nonce }),
+            ControlCommand::Shutdown => ControlRequest::Shutdown,
+        };
+        match call(request).whatever_context(format!("failed to send {:?} command", command))? {
+            ControlResponse::Status(status) => println!(
+                "Server is up for {}s, secret injected: {}, failed attempts: {}, open control channels: {}",
+                status.uptime_secs, status.secret_injected, status.failed_attempts, status.open_channels
+            ),
+            ControlResponse::Report(report) => {
+                verify_report(&report, nonce)?;
+                println!("Fresh attestation report is valid");
+            }
+            ControlResponse::ShuttingDown => println!("Server is shutting down"),
+            ControlResponse::Error(e) => whatever!("{:?} command failed: {}", command, e),
+            other => whatever!("unexpected response to {:?} command: {:?}", command, other),
+        }
+    }
+    Ok(())
+}
+
+///Send the new key to the server and, unless `keep_old_key` is set, confirm the removal of
+///the current key once the new key was added
+fn rotate_key(
+    conn: &Connection,
+    wrapped_rotation: WrappedKeyRotation,
+    owner_key: Option<&OwnerSigningKey>,
+    attestation_report: &AttestationReport,
+    keep_old_key: bool,
+) -> Result<(), UserError> {
+    println!("Sending key rotation to server");
+    let pending = match conn
+        .call(RequestMessage::RotateKey(wrapped_rotation))
+        .whatever_context("failed to send key rotation")?
+    {
+        ResponseMessage::RotationPending(v) => v,
+        other => whatever!("unexpected response to key rotation: {:?}", other),
     };
-    verify_and_check_report(
-        &attestation_report,
-        vm_description.host_cpu_family,
-        vcek_cert,
-        id_block_data,
-        Some(vm_description.guest_policy),
-        Some(vm_description.min_commited_tcb),
-        Some(vm_description.platform_info),
-        Some(report_data_validator),
-        None, //We don't use host data right now
-        Some(expected_ld),
-    )
-    .context(InvalidReportSnafu {})?;
+    println!("New key was added to the LUKS device");
+    if keep_old_key {
+        println!("Keeping the old key as requested");
+        return Ok(());
+    }
+    let mut confirmation = RotationConfirmation {
+        rotation_id: pending.rotation_id,
+        owner_signature: None,
+    };
+    if let Some(owner_key) = owner_key {
+        owner_key
+            .sign(&mut confirmation, attestation_report)
+            .whatever_context("failed to sign rotation confirmation")?;
+    }
+    match conn
+        .call(RequestMessage::ConfirmRotation(confirmation))
+        .whatever_context("failed to confirm key rotation")?
+    {
+        ResponseMessage::RotationComplete => println!("Old key was removed from the LUKS device"),
+        other => whatever!("unexpected response to rotation confirmation: {:?}", other),
+    }
+    Ok(())
+}
 
-    let user_report_data: ReportData = attestation_report.report_data.into();
+///If requested, store the attestation report under the path specified in `args`
+fn dump_report(args: &Args, attestation_report: &AttestationReport) -> Result<(), UserError> {
+    if let Some(dump_path) = &args.dump_report {
+        let f = File::create(dump_path).whatever_context(format!(
+            "failed to create report dump file at {}",
+            dump_path
+        ))?;
+        serde_json::to_writer_pretty(f, &attestation_report).whatever_context(format!(
+            "failed to serialize attestation report to file {}",
+            &dump_path
+        ))?;
+    }
+    Ok(())
+}
 
-    //Phase2: Derive Shared secret and send encrypted disk encryption key to server
+///Checks reports against the policy derived from the vm config and the command line arguments
+#[derive(Clone)]
+struct ReportChecker {
+    host_cpu_family: ProductName,
+    policy: VerificationPolicy,
+    expected_ld: [u8; 48],
+    ///Output options, see `Args`
+    all_checks: bool,
+    verdict: Option<PathBuf>,
+    verdict_format: VerdictFormat,
+}
 
-    //Verified report and have pulic key agreement key from server (authenticity attested by report signature)
-    //Generate our key pair and encrypt the password for the disk with the shared secret
-    //Then send encrypted disk key + our public key to server
-    println!("Deriving shared secret");
-    let client_private_key = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng).map_err(|_| Whatever::without_source("failed to generate private DH key".to_string())).whatever_context("failed to generate private DH key for client")?;
-    let client_public_key = client_private_key
-        .compute_public_key().map_err(|_| Whatever::without_source("failed to derive public dh key from private key".to_string())).whatever_context("failed to generate public DH key for client")?;
-
-    let mut shared_secret = Vec::new();
-    agreement::agree_ephemeral(
-        client_private_key,
-        &user_report_data.server_public_key,
-        |key_material| -> Result<(),Whatever>{
-            shared_secret
-                .write_all(key_material).whatever_context("failed to store derived shared secret in buffer")
-            // In a real application, we'd apply a KDF to the key material and the
-            // public keys (as recommended in RFC 7748) and then derive session
-            // keys from the result. We omit all that here.
-        },
-    ).map_err(|_| Whatever::without_source("failed to compute shared secret from DH keys".to_string())).whatever_context("failed to derive shared secret")?.whatever_context("internal error")?;
-
-    println!("Wrapping disk encryption key");
-    let disk_encryption_key = args.disk_key.as_bytes();
-    let wrapped_disk_key = aead_enc(&shared_secret, nonce, disk_encryption_key).whatever_context("failed to encrypt disk encryption key")?;
-
-    let wrapped_disk_key = WrappedDiskKey {
-        wrapped_disk_key,
-        client_public_key: client_public_key
-            .as_ref()
-            .try_into()
-            .whatever_context("failed to serialize public client DH key")?,
+impl ReportChecker {
+    ///Download the VCEK and check the report against the policy
+    fn check<F>(
+        &self,
+        attestation_report: &AttestationReport,
+        report_data_validator: F,
+    ) -> Result<(), UserError>
+    where
+        F: Fn([u8; 64]) -> Result<(), ReportVerificationError>,
+    {
+        println!("Verifying Report");
+        let vcek_resolver = CachingVCEKDownloader::new()
+            .whatever_context("failed to instantiate vcek downloader")?;
+        let vcek_cert = vcek_resolver
+            .get_vceck_cert(
+                attestation_report.chip_id,
+                self.host_cpu_family,
+                &attestation_report.committed_tcb,
+            )
+            .whatever_context(format!(
+                "failed to download vcek cert for cpu family {}, chip_id 0x{}",
+                &self.host_cpu_family,
+                hex::encode(attestation_report.chip_id)
+            ))?;
+
+        if !self.all_checks && self.verdict.is_none() {
+            return verify_and_check_report(
+                attestation_report,
+                self.host_cpu_family,
+                vcek_cert,
+                &self.policy,
+                Some(report_data_validator),
+            )
+            .context(InvalidReportSnafu {});
+        }
+        let summary = evaluate_report(
+            attestation_report,
+            self.host_cpu_family,
+            vcek_cert.clone(),
+            &self.policy,
+            Some(report_data_validator),
+        );
+        if self.all_checks {
+            print!("{}", summary);
+        }
+        if let Some(path) = &self.verdict {
+            Verdict::new(
+                attestation_report,
+                self.host_cpu_family,
+                &vcek_cert,
+                Some(self.expected_ld),
+                summary.clone(),
+            )
+            .and_then(|v| v.write(path, self.verdict_format))
+            .whatever_context("failed to create verdict")?;
+        }
+        summary.into_result().context(InvalidReportSnafu {})
+    }
+}
+
+///Attest the server via its RA-TLS certificate and send the disk key through the attested TLS channel
+fn run_ra_tls(
+    args: &Args,
+    payload: &Payload,
+    owner_key: Option<&OwnerSigningKey>,
+    checker: &ReportChecker,
+) -> Result<(), UserError> {
+    let server_url = Url::from_str(&args.server_url)
+        .whatever_context(format!("Failed to parse server url {}", &args.server_url))?;
+    if server_url.scheme() != "https" {
+        whatever!(
+            "RA-TLS mode requires an https server url, got {}",
+            &args.server_url
+        );
+    }
+
+    let wait_for_request_bar = ProgressBar::new_spinner()
+        .with_message("Waiting for attestation server")
+        .with_elapsed(Duration::from_secs(0));
+    wait_for_request_bar.enable_steady_tick(Duration::from_millis(100));
+    //The report in the certificate is checked during the handshake, so that we never talk to
+    //a server that fails the check
+    let report_checker = checker.clone();
+    let verifier = RaTlsVerifier::new(move |cert| {
+        println!("Received report");
+        report_checker
+            .check(&cert.report, |vm_data: [u8; 64]| {
+                cert.claims.check(&vm_data)
+            })
+            .map_err(|e| format!("{:#}", e))
+    });
+    let client = Client::builder()
+        .use_preconfigured_tls(ra_tls_client_config(verifier.clone()))
+        .build()
+        .whatever_context("failed to build RA-TLS client")?;
+    let transport =
+        HttpClientTransport::new(client, &server_url).whatever_context("invalid server url")?;
+    let conn = Connection::connect(
+        Box::new(transport),
+        Capability::RaTls,
+        args.min_protocol_version,
+    );
+    wait_for_request_bar.finish();
+    if let Some(report) = verifier.last_report() {
+        dump_report(args, &report)?;
+    }
+    if let Some(failure) = verifier.failure() {
+        whatever!(
+            "RA-TLS certificate of {} was rejected: {}",
+            &server_url,
+            failure
+        );
+    }
+    let conn = conn.whatever_context(format!(
+        "failed to connect to attestation server at {}",
+        &server_url
+    ))?;
+    let report = verifier
+        .last_report()
+        .whatever_context("server did not present an RA-TLS certificate")?;
+    let message = match payload {
+        Payload::DiskKey(disk_key) => {
+            let mut disk_key = RaTlsDiskKey {
+                disk_key: disk_key.clone(),
+                owner_signature: None,
+            };
+            if let Some(owner_key) = owner_key {
+                owner_key
+                    .sign(&mut disk_key, &report)
+                    .whatever_context("failed to sign disk encryption key")?;
+            }
+            RequestMessage::InjectSecretRaTls(disk_key)
+        }
+        Payload::Bundle(bundle) => {
+            conn.require(payload.capability())
+                .whatever_context("server cannot receive secret bundles")?;
+            if owner_key.is_some() {
+                whatever!("secret bundles sent via RA-TLS cannot be signed by the owner, use the default mode");
+            }
+            RequestMessage::InjectSecretBundleRaTls(bundle.clone())
+        }
+        Payload::Rotation(_) => whatever!("key rotation is not supported via RA-TLS"),
+        Payload::Control { .. } => whatever!("the control channel is not supported via RA-TLS"),
     };
+    println!("Sending secrets to server");
+    match conn
+        .call(message)
+        .whatever_context("failed to send secrets")?
+    {
+        ResponseMessage::SecretAccepted => (),
+        other => whatever!("unexpected response to secret injection: {:?}", other),
+    }
+
+    Ok(())
+}
+
+///Release policy of the broker mode: every VM that matches the vm config gets the disk key
+struct VmDefinitionPolicy<'a> {
+    disk_key: &'a SecretPayload,
+    checker: &'a ReportChecker,
+}
 
-    println!("Sending wrapped disk encryption key to server");
-    client
-        .post(&args.server_url)
-        .json(&wrapped_disk_key)
-        .send()
-        .whatever_context("failed to send wrapped disk key")?;
+impl ReleasePolicy for VmDefinitionPolicy<'_> {
+    fn release(&self, report: &AttestationReport) -> Result<SecretPayload, Whatever> {
+        //The broker already checked that the report data contains its challenge nonce
+        self.checker
+            .check(report, |_| Ok(()))
+            .whatever_context("attestation report does not match the vm config")?;
+        println!(
+            "Releasing disk key to VM with chip id 0x{}",
+            hex::encode(report.chip_id)
+        );
+        Ok(self.disk_key.clone())
+    }
+}
 
+///Serve the disk key to VMs that pull it, see `broker_listen` in `Args`
+fn run_broker(
+    listen: &ListenAddr,
+    disk_key: SecretPayload,
+    owner_key: Option<OwnerSigningKey>,
+    checker: &ReportChecker,
+    min_protocol_version: u32,
+) -> Result<(), UserError> {
+    let mut transport = listen
+        .bind(None, None)
+        .whatever_context(format!("failed to listen on {}", listen))?;
+    println!("Serving disk key to attested VMs on {}", listen);
+    let release_policy = VmDefinitionPolicy {
+        disk_key: &disk_key,
+        checker,
+    };
+    let mut broker = KeyBroker::new(release_policy).with_min_protocol_version(min_protocol_version);
+    if let Some(owner_key) = owner_key {
+        broker = broker.with_owner_key(owner_key);
+    }
+    broker
+        .serve(transport.as_mut())
+        .whatever_context("key broker failed")?;
     Ok(())
 }
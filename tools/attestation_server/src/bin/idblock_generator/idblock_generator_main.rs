@@ -11,10 +11,11 @@ use clap::Parser;
 use snafu::{ResultExt, Whatever};
 
 use sev::measurement::{
-        idblock::snp_calculate_id,
-        idblock_types::{FamilyId, IdMeasurements, ImageId},
-        large_array::LargeArray, snp::SnpLaunchDigest,
-    };
+    idblock::snp_calculate_id,
+    idblock_types::{FamilyId, IdMeasurements, ImageId},
+    large_array::LargeArray,
+    snp::SnpLaunchDigest,
+};
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -117,8 +118,14 @@ fn compute_id_block(
             .whatever_context("converting to id block digest failed")?,
     );
 
-    let family_id : FamilyId = bincode::deserialize(&vm_def.family_id).whatever_context(format!("failed to deserizalize family id {:x?}", &vm_def.family_id))?;
-    let image_id : ImageId = bincode::deserialize(&vm_def.image_id).whatever_context(format!("failed to deserialize image id {:x?}", &vm_def.image_id))?;
+    let family_id: FamilyId = bincode::deserialize(&vm_def.family_id).whatever_context(format!(
+        "failed to deserizalize family id {:x?}",
+        &vm_def.family_id
+    ))?;
+    let image_id: ImageId = bincode::deserialize(&vm_def.image_id).whatever_context(format!(
+        "failed to deserialize image id {:x?}",
+        &vm_def.image_id
+    ))?;
 
     // let id_block = IdBlock::new(, , , , )
     let block_calculations = snp_calculate_id(
@@ -0,0 +1,69 @@
+//! Key broker service: long-running, owner side service that releases secrets to VMs in
+//! pull mode, based on a database of VM identities and release policies
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use attestation_server::{
+    broker::KeyBroker, owner_auth::OwnerSigningKey, policy_db::PolicyDb,
+    req_resp_ds::MIN_PROTOCOL_VERSION, transport::ListenAddr,
+};
+use clap::Parser;
+use snafu::{whatever, ResultExt, Whatever};
+use tiny_http::SslConfig;
+
+#[derive(Parser, Debug)]
+struct Args {
+    ///Database with the secrets and their release policies, see `examples/kbs-db.toml`
+    #[arg(long, env = "KBS_DB")]
+    db: PathBuf,
+
+    ///Address to listen on. Overrides `listen` from the database
+    #[arg(long, env = "KBS_LISTEN")]
+    listen: Option<ListenAddr>,
+
+    ///Lowest protocol version to accept, defaults to the current version. Only lower this for
+    ///VMs that cannot be updated
+    #[arg(long, default_value_t = MIN_PROTOCOL_VERSION)]
+    min_protocol_version: u32,
+}
+
+fn main() -> Result<(), Whatever> {
+    let args = Args::parse();
+    let db = PolicyDb::load(&args.db).whatever_context(format!(
+        "failed to load database from {}",
+        args.db.display()
+    ))?;
+    let listen = match args.listen.or(db.listen.clone()) {
+        Some(v) => v,
+        None => whatever!("no listen address, specify `listen` in the database or pass --listen"),
+    };
+    let ssl = match (&db.tls_certificate, &db.tls_private_key) {
+        (Some(certificate), Some(private_key)) => Some(SslConfig {
+            certificate: fs::read(certificate).whatever_context(format!(
+                "failed to read TLS certificate from {}",
+                certificate
+            ))?,
+            private_key: fs::read(private_key).whatever_context(format!(
+                "failed to read TLS private key from {}",
+                private_key
+            ))?,
+        }),
+        (None, None) => None,
+        _ => whatever!("tls_certificate and tls_private_key must be specified together"),
+    };
+
+    let owner_key = match &db.owner_signing_key {
+        Some(path) => Some(OwnerSigningKey::load(Path::new(path))?),
+        None => None,
+    };
+
+    let mut transport = listen.bind(ssl, None)?;
+    println!("Serving {} secrets on {}", db.secrets.len(), &listen);
+    let mut broker = KeyBroker::new(db).with_min_protocol_version(args.min_protocol_version);
+    if let Some(owner_key) = owner_key {
+        broker = broker.with_owner_key(owner_key);
+    }
+    broker.serve(transport.as_mut())
+}
@@ -0,0 +1,93 @@
+//! Tool for the VM Owner to manage the passphrase protected keystore, from which the client can
+//! read disk keys via `--disk-key-source keystore:<path>#<name>`
+use std::path::PathBuf;
+
+use attestation_server::{
+    key_source::{prompt_secret, read_secret, KeySource, KEYSTORE_PASSPHRASE_ENV},
+    keystore::{Keystore, ScryptParams},
+    req_resp_ds::{SecretEncoding, SecretPayload},
+    secret::{set_mlock, SecretBytes},
+};
+use clap::{Parser, Subcommand};
+use snafu::{whatever, Whatever};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(long, env = "SNPGUARD_KEYSTORE")]
+    ///Path to the keystore file. Created by `add` if it does not exist
+    keystore: PathBuf,
+
+    #[arg(long)]
+    ///Lock buffers with secrets into memory, so that they are never written to swap
+    mlock_secrets: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    ///Encrypt a key with a passphrase and add it to the keystore
+    Add {
+        ///Name under which the key is stored
+        name: String,
+        #[arg(long)]
+        ///Read the key from this source instead of prompting for it, see `--disk-key-source` of
+        ///the client. Use e.g. `file:<path>` to import a binary LUKS key file
+        source: Option<KeySource>,
+    },
+    ///Print the names of all keys
+    List,
+    ///Remove a key from the keystore
+    Remove { name: String },
+}
+
+///Ask for a secret twice, to catch typos
+fn prompt_confirmed(prompt: &str) -> Result<SecretBytes, Whatever> {
+    let first = prompt_secret(&format!("{}: ", prompt))?;
+    let second = prompt_secret(&format!("Repeat {}: ", prompt.to_lowercase()))?;
+    if first != second {
+        whatever!("inputs do not match");
+    }
+    Ok(first)
+}
+
+#[snafu::report]
+fn main() -> Result<(), Whatever> {
+    let args = Args::parse();
+    set_mlock(args.mlock_secrets);
+    match args.command {
+        Command::Add { name, source } => {
+            let mut keystore = Keystore::load_or_default(&args.keystore)?;
+            let key = match source {
+                Some(source) => source.load()?,
+                None => SecretPayload {
+                    data: prompt_confirmed("Disk key")?,
+                    encoding: SecretEncoding::Utf8,
+                },
+            };
+            let passphrase = match std::env::var_os(KEYSTORE_PASSPHRASE_ENV) {
+                Some(_) => read_secret(KEYSTORE_PASSPHRASE_ENV, "")?,
+                None => prompt_confirmed("Keystore passphrase")?,
+            };
+            keystore.add(&name, &key, passphrase.expose(), ScryptParams::default())?;
+            keystore.save(&args.keystore)?;
+            println!("Added {} to {}", name, args.keystore.display());
+        }
+        Command::List => {
+            for name in Keystore::load(&args.keystore)?.names() {
+                println!("{}", name);
+            }
+        }
+        Command::Remove { name } => {
+            let mut keystore = Keystore::load(&args.keystore)?;
+            if !keystore.remove(&name) {
+                whatever!("{} has no key named {}", args.keystore.display(), name);
+            }
+            keystore.save(&args.keystore)?;
+            println!("Removed {} from {}", name, args.keystore.display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,295 @@
+//! Command line and config file handling for the attestation server
+use std::{fs, net::SocketAddr, path::PathBuf, time::Duration};
+
+use attestation_server::{
+    owner_auth::{OwnerKeySource, OwnerSigned},
+    req_resp_ds::MIN_PROTOCOL_VERSION,
+    secret_sink::SecretSink,
+    transport::ListenAddr,
+};
+use clap::{builder::FalseyValueParser, Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use sev::firmware::guest::AttestationReport;
+use snafu::{whatever, ResultExt, Whatever};
+
+/// Server to run inside the VM to perform attestation and securely receive a (disk encryption) secret
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    ///Path to a TOML config file. Values passed on the command line take precedence.
+    ///See `examples/server-config.toml` for all options
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    ///Address to listen on, e.g. `0.0.0.0:80`, `[::]:80`, `unix:/run/snpguard.sock` or `vsock:8080`
+    #[arg(long, env = "LISTEN")]
+    listen: Option<ListenAddr>,
+
+    ///Destination of the injected secret: `<path>`, `stdout`, `fd:<N>`, `fifo:<path>` or
+    ///`keyring:[@u|@s|@us:]<description>`
+    #[arg(long)]
+    secret_output: Option<SecretSink>,
+
+    ///Time in seconds after which an attestation session without secret injection expires
+    #[arg(long)]
+    session_timeout: Option<u64>,
+
+    ///Give up if no secret was injected within this many seconds
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    ///Give up after this many failed secret injection attempts
+    #[arg(long)]
+    max_attempts: Option<u32>,
+
+    ///Source of attestation reports
+    #[arg(long, value_enum)]
+    backend: Option<AttestationBackend>,
+
+    ///Use the mock attestation backend. Same as `--backend mock`
+    #[arg(long, env = "MOCK", value_parser = FalseyValueParser::new())]
+    mock: bool,
+
+    ///Only serve attestation reports, do not accept secrets
+    #[arg(long, env = "NO_SECRET_INJECTION", value_parser = FalseyValueParser::new())]
+    no_secret_injection: bool,
+
+    ///Serve via TLS, using a certificate that is bound to the attestation report
+    #[arg(long, env = "RA_TLS", value_parser = FalseyValueParser::new())]
+    ra_tls: bool,
+
+    ///Pull mode: instead of waiting for the owner, fetch the secret from the key broker at this
+    ///URL, e.g. `https://kbs.example.com` or `vsock://2:8080`
+    #[arg(long)]
+    pull: Option<String>,
+
+    ///PEM file with the CA certificate(s) used to authenticate an https key broker
+    #[arg(long)]
+    broker_ca: Option<PathBuf>,
+
+    ///Only accept secrets signed by this Ed25519 owner key: the hex encoded public key or
+    ///`host_data` to use the HOST_DATA of the attestation report
+    #[arg(long, env = "OWNER_PUBLIC_KEY")]
+    owner_public_key: Option<OwnerKeySource>,
+
+    ///Allow the owner to rotate the key of this LUKS device via the attested channel. Combine
+    ///with `--no-secret-injection` to run a long-lived rotation service in the booted VM
+    #[arg(long, env = "LUKS_DEVICE")]
+    luks_device: Option<PathBuf>,
+
+    ///Keep running after secret injection and accept owner commands via an encrypted control
+    ///channel. Requires a pinned `--owner-public-key`, `host_data` is not sufficient
+    #[arg(long, env = "CONTROL_CHANNEL", value_parser = FalseyValueParser::new())]
+    control_channel: bool,
+
+    ///Time in seconds after which an idle control channel is closed
+    #[arg(long)]
+    control_channel_timeout: Option<u64>,
+
+    ///Lock buffers with secrets into memory, so that they are never written to swap. Requires a
+    ///sufficient `RLIMIT_MEMLOCK`, otherwise a warning is printed for each secret
+    #[arg(long, env = "MLOCK_SECRETS", value_parser = FalseyValueParser::new())]
+    mlock_secrets: bool,
+
+    ///Lowest protocol version to accept, defaults to the current version. Versions below 3
+    ///lack the transcript binding and the hashed report claims. Only lower this for peers that
+    ///cannot be updated
+    #[arg(long)]
+    min_protocol_version: Option<u32>,
+}
+
+///Where attestation reports come from
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum AttestationBackend {
+    ///Request reports from the AMD secure processor via the sev-guest device
+    Snp,
+    ///Return empty reports that only contain the report data. For testing only
+    Mock,
+}
+
+///Server configuration. Can be loaded from a TOML file, all fields are optional
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: ListenAddr,
+    ///Destination of the injected secret, see `SecretSink` for the syntax
+    pub secret_output: SecretSink,
+    ///Seconds after which an attestation session without secret injection expires
+    pub session_timeout: u64,
+    ///Upper bound on parallel attestation sessions. Protects against memory exhaustion
+    pub max_sessions: usize,
+    ///If set, give up if no secret was injected within this many seconds
+    pub timeout: Option<u64>,
+    ///If set, give up after this many failed secret injection attempts
+    pub max_attempts: Option<u32>,
+    pub backend: AttestationBackend,
+    ///Only serve attestation reports, do not accept secrets
+    pub no_secret_injection: bool,
+    ///Serve via TLS, using a certificate that is bound to the attestation report
+    pub ra_tls: bool,
+    ///If set, pull the secret from the key broker at this URL instead of serving requests
+    pub pull: Option<String>,
+    ///PEM file with the CA certificate(s) used to authenticate an https key broker
+    pub broker_ca: Option<PathBuf>,
+    ///Seconds to wait before retrying a failed pull
+    pub pull_interval: u64,
+    ///If set, only accept secrets that are signed by the owner key. See `OwnerKeySource`
+    pub owner_public_key: Option<OwnerKeySource>,
+    ///If set, offer key rotation for this LUKS device
+    pub luks_device: Option<PathBuf>,
+    ///Keep running after secret injection and accept owner commands via a control channel
+    pub control_channel: bool,
+    ///Seconds after which an idle control channel is closed
+    pub control_channel_timeout: u64,
+    ///Lock secrets into memory, see `SecretBytes`
+    pub mlock_secrets: bool,
+    ///Lowest accepted protocol version, see `MIN_PROTOCOL_VERSION`
+    pub min_protocol_version: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], 80))),
+            secret_output: SecretSink::File(PathBuf::from("./disk_key.txt")),
+            session_timeout: 300,
+            max_sessions: 32,
+            timeout: None,
+            max_attempts: None,
+            backend: AttestationBackend::Snp,
+            no_secret_injection: false,
+            ra_tls: false,
+            pull: None,
+            broker_ca: None,
+            pull_interval: 5,
+            owner_public_key: None,
+            luks_device: None,
+            control_channel: false,
+            control_channel_timeout: 3600,
+            mlock_secrets: false,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+
+impl Config {
+    ///Load the config file referenced in `args` (if any) and apply the command line overrides
+    pub fn from_args(args: Args) -> Result<Self, Whatever> {
+        let mut config: Config = match &args.config {
+            Some(path) => toml::from_str(
+                &fs::read_to_string(path)
+                    .whatever_context(format!("failed to read config from {}", path.display()))?,
+            )
+            .whatever_context("failed to parse server config as toml")?,
+            None => Config::default(),
+        };
+
+        if let Some(v) = args.listen {
+            config.listen = v;
+        }
+        if let Some(v) = args.secret_output {
+            config.secret_output = v;
+        }
+        if let Some(v) = args.session_timeout {
+            config.session_timeout = v;
+        }
+        if args.timeout.is_some() {
+            config.timeout = args.timeout;
+        }
+        if args.max_attempts.is_some() {
+            config.max_attempts = args.max_attempts;
+        }
+        if let Some(v) = args.backend {
+            config.backend = v;
+        }
+        if args.mock {
+            config.backend = AttestationBackend::Mock;
+        }
+        config.no_secret_injection |= args.no_secret_injection;
+        config.ra_tls |= args.ra_tls;
+        if args.pull.is_some() {
+            config.pull = args.pull;
+        }
+        if args.broker_ca.is_some() {
+            config.broker_ca = args.broker_ca;
+        }
+        if args.owner_public_key.is_some() {
+            config.owner_public_key = args.owner_public_key;
+        }
+        if args.luks_device.is_some() {
+            config.luks_device = args.luks_device;
+        }
+        config.control_channel |= args.control_channel;
+        if let Some(v) = args.control_channel_timeout {
+            config.control_channel_timeout = v;
+        }
+        config.mlock_secrets |= args.mlock_secrets;
+        if let Some(v) = args.min_protocol_version {
+            config.min_protocol_version = v;
+        }
+
+        if config.ra_tls && matches!(config.listen, ListenAddr::Vsock(_)) {
+            whatever!("RA-TLS is not supported via vsock");
+        }
+        if config.ra_tls && config.pull.is_some() {
+            whatever!("RA-TLS is not supported in pull mode");
+        }
+        if config.luks_device.is_some() && config.pull.is_some() {
+            whatever!("key rotation is not supported in pull mode");
+        }
+        if config.control_channel && config.pull.is_some() {
+            whatever!("the control channel is not supported in pull mode");
+        }
+        //the host chooses host_data, so with that key source it could sign its own commands
+        if config.control_channel
+            && !matches!(config.owner_public_key, Some(OwnerKeySource::Pinned(_)))
+        {
+            whatever!("the control channel requires a pinned owner_public_key, host_data is chosen by the host");
+        }
+        //http and vsock connections are controlled by the host, and so is host_data. Without a
+        //broker or owner key that the host cannot forge, it could serve a key of its choice
+        if let Some(pull) = &config.pull {
+            let authenticated_broker = pull.starts_with("https://") && config.broker_ca.is_some();
+            let pinned_owner = matches!(config.owner_public_key, Some(OwnerKeySource::Pinned(_)));
+            if !authenticated_broker && !pinned_owner {
+                whatever!(
+                    "pull mode requires a pinned owner_public_key or an https broker url with broker_ca, \
+                    otherwise the host can serve a secret of its choice"
+                );
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn session_timeout(&self) -> Duration {
+        Duration::from_secs(self.session_timeout)
+    }
+
+    pub fn control_channel_timeout(&self) -> Duration {
+        Duration::from_secs(self.control_channel_timeout)
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    pub fn pull_interval(&self) -> Duration {
+        Duration::from_secs(self.pull_interval)
+    }
+
+    ///Check the owner signature of `msg`, if an owner key is configured
+    pub fn check_owner_signature<M: OwnerSigned>(
+        &self,
+        msg: &M,
+        report: &AttestationReport,
+    ) -> Result<(), Whatever> {
+        match &self.owner_public_key {
+            Some(source) => source
+                .resolve(report)
+                .verify(msg, report)
+                .whatever_context("failed to authenticate the owner"),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Pull mode: instead of waiting for the owner, the VM contacts a key broker, proves its
+//! identity with an attestation report and receives its secret in response
+use std::{
+    fs,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use attestation_server::{
+    req_resp_ds::{Capability, PullRequest, RequestMessage, ResponseMessage},
+    transport::{
+        ClientTransport, Connection, HttpClientTransport, VsockClientTransport, VSOCK_SCHEME,
+    },
+};
+use reqwest::{blocking::Client, Certificate, Url};
+use snafu::{whatever, ResultExt, Whatever};
+
+use crate::{config::Config, process_injected_secret, send_report};
+
+///Upper bound for a single request to the key broker
+const BROKER_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+///Pull the secret from the broker at `broker_url` and store it in the configured sink.
+///Retries until it succeeds or the configured timeout or number of attempts is exceeded
+pub fn run(config: &Config, broker_url: &str) -> Result<(), Whatever> {
+    let broker_url = Url::from_str(broker_url)
+        .whatever_context(format!("failed to parse key broker url {}", broker_url))?;
+    let deadline = config.timeout().map(|v| Instant::now() + v);
+    let mut failed_attempts = 0;
+    loop {
+        match pull_once(config, &broker_url) {
+            Ok(_) => {
+                eprintln!("Received secret from key broker");
+                return Ok(());
+            }
+            Err(e) => {
+                failed_attempts += 1;
+                eprintln!("Failed to pull secret from {} : {:#?}", broker_url, e);
+            }
+        }
+        if let Some(max_attempts) = config.max_attempts {
+            if failed_attempts >= max_attempts {
+                whatever!(
+                    "giving up after {} failed attempts to pull the secret",
+                    failed_attempts
+                );
+            }
+        }
+        if deadline.is_some_and(|v| Instant::now() + config.pull_interval() >= v) {
+            whatever!("no secret was received within the configured timeout");
+        }
+        thread::sleep(config.pull_interval());
+    }
+}
+
+fn broker_transport(
+    config: &Config,
+    broker_url: &Url,
+) -> Result<Box<dyn ClientTransport>, Whatever> {
+    if broker_url.scheme() == VSOCK_SCHEME {
+        return Ok(Box::new(VsockClientTransport::from_url(broker_url)?));
+    }
+    let mut builder = Client::builder().timeout(BROKER_REQUEST_TIMEOUT);
+    if let Some(ca_path) = &config.broker_ca {
+        let pem = fs::read(ca_path).whatever_context(format!(
+            "failed to read broker CA from {}",
+            ca_path.display()
+        ))?;
+        for cert in Certificate::from_pem_bundle(&pem)
+            .whatever_context("failed to parse broker CA certificates")?
+        {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    let client = builder
+        .build()
+        .whatever_context("failed to build http client")?;
+    Ok(Box::new(HttpClientTransport::new(client, broker_url)?))
+}
+
+fn pull_once(config: &Config, broker_url: &Url) -> Result<(), Whatever> {
+    let conn = Connection::connect(
+        broker_transport(config, broker_url)?,
+        Capability::KeyBroker,
+        config.min_protocol_version,
+    )
+    .whatever_context(format!("failed to connect to key broker at {}", broker_url))?;
+    let challenge = match conn
+        .call(RequestMessage::PullChallenge)
+        .whatever_context("failed to request challenge")?
+    {
+        ResponseMessage::Challenge(v) => v,
+        other => whatever!("unexpected response to challenge request: {:?}", other),
+    };
+
+    let (report, params) = send_report(
+        challenge.nonce,
+        config.backend,
+        conn.version(),
+        config.min_protocol_version,
+    )?;
+    println!("Sending attestation report to key broker");
+    let wrapped_key = match conn
+        .call(RequestMessage::PullSecret(Box::new(PullRequest {
+            session_id: challenge.session_id,
+            report,
+            public_key: params.public_key,
+        })))
+        .whatever_context("failed to request secret")?
+    {
+        ResponseMessage::WrappedSecret(v) => v,
+        other => whatever!("unexpected response to secret request: {:?}", other),
+    };
+    config.check_owner_signature(&wrapped_key, &params.report)?;
+    process_injected_secret(wrapped_key, params, &config.secret_output)
+}
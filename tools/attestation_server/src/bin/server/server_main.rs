@@ -1,166 +1,729 @@
 //! Server to run inside the VM to perform attestation and securely receive a (disk encryption) secret
-use std::{env, fs::File, io::Write, str};
+mod config;
+mod pull;
+
+use std::{str, time::Instant};
 
 use attestation_server::{
-    req_resp_ds::{aead_dec, AttestationRequest, WrappedDiskKey},
-    snp_attestation::{MockSNPAttestation, QuerySNPAttestation, SNPAttestation},
-};
-use ring::{
-    agreement::{self, EphemeralPrivateKey},
-    rand,
+    control_channel::{
+        ControlChannel, ControlRequest, ControlResponse, Role, ServerStatus, CHANNEL_KEY_BYTES,
+    },
+    luks::LuksDevice,
+    ra_tls::RaTlsIdentity,
+    req_resp_ds::{
+        enabled_versions, AttestationRequest, AttestationResponse, Capability,
+        ControlChannelOpened, Envelope, KeyRotation, ProtocolError, ProtocolErrorKind,
+        RaTlsDiskKey, ReportBinding, RequestMessage, ResponseMessage, RotationConfirmation,
+        RotationPending, SealedControlMessage, ServerInfo, UnwrappingKey, WrappedChannelKey,
+        WrappedDiskKey, WrappedKeyRotation, WrappedSecretBundle, WrappingScheme, PROTOCOL_VERSION,
+    },
+    secret::SecretBytes,
+    secret_bundle::SecretBundle,
+    secret_sink::SecretSink,
+    session::SessionStore,
+    snp_attestation::{MockSNPAttestation, QuerySNPAttestation, ReportClaims, SNPAttestation},
+    transport::{Incoming, ServerTransport},
 };
+use clap::Parser;
+use config::{Args, AttestationBackend, Config};
 use sev::firmware::guest::AttestationReport;
-use snafu::{whatever, FromString, ResultExt, Whatever};
-use tiny_http::{Request, Response, Server};
-
-fn wait_for_request(server: &Server) -> Request {
-    loop {
-        match server.recv() {
-            Ok(rq) => return rq,
-            Err(e) => {
-                println!("error receiving request: {}", e);
-            }
-        };
-    }
-}
+use snafu::{whatever, ResultExt, Whatever};
+use tiny_http::SslConfig;
 
-struct Config {
-    no_secret_injection: bool,
-    mock_mode: bool,
-    listen : String,
+struct SecretInjectionParams {
+    ///Report that was sent to the peer. The wrapping key is bound to it
+    report: AttestationReport,
+    ///Public part of `unwrapping_key`, bound to `report`
+    public_key: [u8; 32],
+    unwrapping_key: UnwrappingKey,
 }
 
-struct SecretInjectionParams {
+///Fetch attestation report and generate the key for unwrapping the secret, both as defined by the
+///protocol `version`, which must be at least `min_version`
+fn send_report(
     nonce: u64,
-    eph_server_dh_key: EphemeralPrivateKey,
-}
-enum ServerState {
-    ///Ready to handle attestation report requests
-    Ready,
-    ///Send attestation report + public DH to client, waiting for response
-    WaitingForSecretInjection(SecretInjectionParams),
-}
+    backend: AttestationBackend,
+    version: u32,
+    min_version: u32,
+) -> Result<(AttestationReport, SecretInjectionParams), Whatever> {
+    let binding = ReportBinding::for_version(version, min_version)?;
+    println!("Requesting attestation report");
 
-///Fetch attestation report and generate key material the DH key deriviation used for secret injection
-fn send_report(mut req:  Request, config: &Config) -> Result<SecretInjectionParams, Whatever> {
-    let att_req: AttestationRequest =
-        serde_json::from_reader(req.as_reader()).whatever_context("failed to parse request body as json")?;
+    let unwrapping_key = UnwrappingKey::generate(WrappingScheme::for_version(version))
+        .whatever_context("failed to generate key for secret unwrapping")?;
+    let server_public_key = unwrapping_key.public_key()?;
 
-    println!("Requesting attestation report");
+    let report_data = binding.report_data(nonce, server_public_key);
+    let att_report = get_report(report_data, backend)?;
+    Ok((
+        att_report,
+        SecretInjectionParams {
+            report: att_report,
+            public_key: server_public_key,
+            unwrapping_key,
+        },
+    ))
+}
 
-    let rng = rand::SystemRandom::new();
-    let server_private_key = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
-    .map_err(|_| Whatever::without_source("failed to generate private DH key".to_string())).whatever_context("failed to generate private DH key for server")?;
-    let server_public_key = server_private_key
-        .compute_public_key()
-        .map_err(|_| Whatever::without_source("failed to derive public dh key from private key".to_string())).whatever_context("failed to generate public DH key for server")?
-        .as_ref()
-        .try_into()
-        .whatever_context("generated public dh key has unexpected length, expected 32 bytes")?;
-
-    let att_report: AttestationReport = if config.mock_mode {
-        MockSNPAttestation::get_report(att_req.nonce, server_public_key)
+///Fetch an attestation report with `report_data` from `backend`
+fn get_report(
+    report_data: [u8; 64],
+    backend: AttestationBackend,
+) -> Result<AttestationReport, Whatever> {
+    let att_report: AttestationReport = if backend == AttestationBackend::Mock {
+        MockSNPAttestation::get_report_raw(report_data)
             .whatever_context("failed to get mock attestation reort")?
     } else {
-        SNPAttestation::get_report(att_req.nonce, server_public_key)
+        SNPAttestation::get_report_raw(report_data)
             .whatever_context("failed to request attestation report from secure processor")?
     };
 
-    println!("Got attestation report. Sending it to client");
-
-    let att_report_json =
-        serde_json::to_string(&att_report).whatever_context("failed to serialize attestation report as json")?;
-    let header =
-        tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).expect("should never happen");
-    let resp = Response::from_string(att_report_json).with_header(header);
-    req.respond(resp).whatever_context("failed to send attestation report to client")?;
-
-    Ok(SecretInjectionParams{
-        nonce: att_req.nonce,
-        eph_server_dh_key: server_private_key,
-    })
+    println!("Got attestation report");
+    Ok(att_report)
 }
 
 ///Process secret injection request and write derived key to disk
 /// # Arguments
-/// - `req`: http request with the raw data
-/// - `key_material` : nonce from client + 
-fn process_injected_secret(mut req: Request, key_material: SecretInjectionParams) -> Result<(), Whatever> {
-    
-    let wrapped_key: WrappedDiskKey =
-        serde_json::from_reader(req.as_reader()).whatever_context("failed to deserialize")?;
-
-    let client_public_key =
-        agreement::UnparsedPublicKey::new(&agreement::X25519, wrapped_key.client_public_key);
-    let mut shared_secret = Vec::new();
-    agreement::agree_ephemeral(
-        key_material.eph_server_dh_key, &client_public_key,
-        |key_material| -> Result<(),Whatever>{
-            shared_secret
-                .write_all(key_material).whatever_context("failed to store derived shared secret in buffer")
-        },
-    ).map_err(|_| Whatever::without_source("failed to compute shared secret from DH keys".to_string())).whatever_context("failed to derive shared secret")?.whatever_context("internal error")?;
-
-    let unwrapped_disk_key = aead_dec(&shared_secret, key_material.nonce, wrapped_key.wrapped_disk_key).whatever_context("failed to decrypt wrapped disk encryption key")?;
+/// - `wrapped_key`: secret injection message from the client
+/// - `key_material` : report and our DH key from the corresponding session
+/// - `sink` : destination for the unwrapped disk key
+fn process_injected_secret(
+    wrapped_key: WrappedDiskKey,
+    key_material: SecretInjectionParams,
+    sink: &SecretSink,
+) -> Result<(), Whatever> {
+    let unwrapped_disk_key = key_material
+        .unwrapping_key
+        .unwrap(
+            &key_material.report,
+            wrapped_key.client_public_key,
+            wrapped_key.wrapped_disk_key,
+        )
+        .whatever_context("failed to decrypt wrapped disk encryption key")?;
     println!("Decrypted wrapped key");
-    let unwrapped_disk_key =
-        str::from_utf8(&unwrapped_disk_key).whatever_context("failed to convert unwrapped disk encryption key to string")?;
-    const OUT_KEY_FILE: &'static str = "./disk_key.txt";
-    let mut out_file = File::create(OUT_KEY_FILE).whatever_context(format!("failed to create output file for disk encryption key at {}",OUT_KEY_FILE))?;
-    out_file
-        .write_all(unwrapped_disk_key.as_bytes())
-        .whatever_context(format!("failed to write disk encrpytion key to file {}", OUT_KEY_FILE))?;
+    wrapped_key
+        .encoding
+        .validate(unwrapped_disk_key.expose())
+        .whatever_context("invalid disk encryption key")?;
+    store_disk_key(unwrapped_disk_key.expose(), sink)
+}
 
-    Ok(())
+///Process secret bundle injection request and deliver all secrets to their destinations
+fn process_injected_bundle(
+    wrapped_bundle: WrappedSecretBundle,
+    key_material: SecretInjectionParams,
+) -> Result<(), Whatever> {
+    let bundle = key_material
+        .unwrapping_key
+        .unwrap(
+            &key_material.report,
+            wrapped_bundle.client_public_key,
+            wrapped_bundle.wrapped_bundle,
+        )
+        .whatever_context("failed to decrypt wrapped secret bundle")?;
+    let bundle: SecretBundle = serde_json::from_slice(bundle.expose())
+        .whatever_context("failed to parse secret bundle")?;
+    println!(
+        "Decrypted secret bundle with {} secrets",
+        bundle.secrets.len()
+    );
+    bundle.deliver()
 }
 
-fn run(config: &Config) -> Result<(), Whatever> {
-    let mut state = ServerState::Ready;
-    let server = match tiny_http::Server::http(&config.listen) {
+///Unwrap the new and the current key and add the new key to `device`. Returns the current key,
+///which is removed once the owner confirms the rotation
+fn process_key_rotation(
+    wrapped_rotation: WrappedKeyRotation,
+    key_material: SecretInjectionParams,
+    device: &LuksDevice,
+) -> Result<SecretBytes, Whatever> {
+    let rotation = key_material
+        .unwrapping_key
+        .unwrap(
+            &key_material.report,
+            wrapped_rotation.client_public_key,
+            wrapped_rotation.wrapped_rotation,
+        )
+        .whatever_context("failed to decrypt wrapped key rotation")?;
+    let rotation: KeyRotation = serde_json::from_slice(rotation.expose())
+        .whatever_context("failed to parse key rotation")?;
+    rotation.validate()?;
+    let keyslot = device
+        .add_key(
+            rotation.current_key.data.expose(),
+            rotation.new_key.data.expose(),
+        )
+        .whatever_context("failed to add new key")?;
+    if let Err(e) = device.test_key(rotation.new_key.data.expose()) {
+        //do not leave a keyslot behind that the owner does not know about
+        if let Err(e) = device.kill_keyslot(rotation.current_key.data.expose(), keyslot) {
+            eprintln!(
+                "Failed to remove keyslot {} of the new key : {:#?}",
+                keyslot, e
+            );
+        }
+        return Err(e).whatever_context("new key does not unlock the device");
+    }
+    println!(
+        "Added new key to {}, waiting for confirmation to remove the old key",
+        device.0.display()
+    );
+    Ok(rotation.current_key.data)
+}
+
+///Unwrap the channel key and set up our end of the control channel
+fn open_control_channel(
+    wrapped_key: WrappedChannelKey,
+    key_material: SecretInjectionParams,
+) -> Result<ControlChannel, Whatever> {
+    let channel_key = key_material
+        .unwrapping_key
+        .unwrap(
+            &key_material.report,
+            wrapped_key.client_public_key,
+            wrapped_key.wrapped_channel_key,
+        )
+        .whatever_context("failed to decrypt wrapped channel key")?;
+    let channel_key = match channel_key.expose().try_into() {
         Ok(v) => v,
-        Err(e) => {whatever!("failed to start http server: {:#?}", e)},
+        Err(_) => whatever!("channel key must have {} bytes", CHANNEL_KEY_BYTES),
     };
-    loop {
-        let req = wait_for_request(&server);
-        println!("Req URL: {}", req.url());
-        if "/reset" == req.url() {
-            state = ServerState::Ready;
-            println!("Resetting attestation server state");
-            req.respond(Response::from_string("Ok")).whatever_context("failed to ack reset request")?;
-            continue;
-        }
-        match state {
-            ServerState::Ready => match send_report(req, config) {
-                Ok(secret_injectin_params) => {
-                    if !config.no_secret_injection {
-                        state = ServerState::WaitingForSecretInjection(secret_injectin_params)
-                    }
-                }
-                Err(e) => eprintln!("Error while serving attestation report request: {:#?}", e),
+    ControlChannel::new(channel_key, &key_material.report, Role::Server)
+}
+
+///Key rotation that waits for the confirmation of the owner
+struct PendingRotation {
+    ///Report of the session in which the rotation was requested
+    report: AttestationReport,
+    current_key: SecretBytes,
+}
+
+///Hand over the disk key to the configured sink
+fn store_disk_key(unwrapped_disk_key: &[u8], sink: &SecretSink) -> Result<(), Whatever> {
+    sink.deliver(unwrapped_disk_key)
+        .whatever_context(format!("failed to store disk encryption key in {}", sink))
+}
+
+///Transport independent protocol logic
+struct AttestationService<'a> {
+    config: &'a Config,
+    sessions: SessionStore<SecretInjectionParams>,
+    ///Key rotations that wait for `RequestMessage::ConfirmRotation`, indexed by rotation id
+    rotations: SessionStore<PendingRotation>,
+    ///Open control channels, indexed by channel id. Expire when idle
+    channels: SessionStore<ControlChannel>,
+    ///Report embedded in our TLS certificate. Only set in RA-TLS mode
+    ra_tls_report: Option<AttestationReport>,
+    started: Instant,
+    ///Set once a secret was injected successfully
+    secret_injected: bool,
+    ///Set once the server should stop, i.e. after secret injection or, with the control channel,
+    ///after a shutdown command
+    done: bool,
+    ///Number of secrets that we failed to unwrap
+    failed_attempts: u32,
+}
+
+impl<'a> AttestationService<'a> {
+    fn new(config: &'a Config, ra_tls_report: Option<AttestationReport>) -> Self {
+        AttestationService {
+            config,
+            sessions: SessionStore::new(config.session_timeout(), config.max_sessions),
+            rotations: SessionStore::new(config.session_timeout(), config.max_sessions),
+            channels: SessionStore::new(config.control_channel_timeout(), config.max_sessions),
+            ra_tls_report,
+            started: Instant::now(),
+            secret_injected: false,
+            done: false,
+            failed_attempts: 0,
+        }
+    }
+
+    fn info(&self) -> ServerInfo {
+        let mut capabilities = Vec::new();
+        if !self.config.no_secret_injection {
+            capabilities.push(Capability::SecretInjection);
+            capabilities.push(Capability::SecretBundle);
+        }
+        if self.ra_tls_report.is_some() {
+            capabilities.push(Capability::RaTls);
+        }
+        if self.config.luks_device.is_some() {
+            capabilities.push(Capability::KeyRotation);
+        }
+        if self.config.control_channel {
+            capabilities.push(Capability::ControlChannel);
+        }
+        ServerInfo {
+            supported_versions: enabled_versions(self.config.min_protocol_version),
+            capabilities,
+        }
+    }
+
+    ///Process a single message and build the response. The response uses the version of the
+    ///request, if we support it
+    fn handle(&mut self, req: Envelope<RequestMessage>) -> Envelope<ResponseMessage> {
+        if !enabled_versions(self.config.min_protocol_version).contains(&req.version) {
+            let resp = match req.message {
+                RequestMessage::Info => ResponseMessage::Info(self.info()),
+                _ => error_response(
+                    ProtocolErrorKind::UnsupportedVersion,
+                    format!(
+                        "protocol version {} is not supported, see info message",
+                        req.version
+                    ),
+                ),
+            };
+            return Envelope::new(PROTOCOL_VERSION, resp);
+        }
+        let resp = match req.message {
+            RequestMessage::Info => ResponseMessage::Info(self.info()),
+            RequestMessage::Report(att_req) => self.handle_report_request(&att_req, req.version),
+            RequestMessage::InjectSecret(wrapped_key) => self.handle_secret_injection(wrapped_key),
+            RequestMessage::InjectSecretRaTls(disk_key) => self.handle_ra_tls_secret(disk_key),
+            RequestMessage::InjectSecretBundle(wrapped_bundle) => {
+                let session_id = wrapped_bundle.session_id.clone();
+                let config = self.config;
+                self.handle_wrapped_secret(&session_id, |params| {
+                    config.check_owner_signature(&wrapped_bundle, &params.report)?;
+                    process_injected_bundle(wrapped_bundle, params)
+                })
+            }
+            RequestMessage::InjectSecretBundleRaTls(_)
+                if self.config.owner_public_key.is_some() =>
+            {
+                error_response(
+                    ProtocolErrorKind::UnexpectedMessage,
+                    "secret bundles via RA-TLS cannot be authenticated, send a wrapped bundle",
+                )
+            }
+            RequestMessage::InjectSecretBundleRaTls(bundle) => match bundle.validate() {
+                Ok(_) => self.handle_ra_tls_secret_with(|| bundle.deliver()),
+                Err(e) => error_response(ProtocolErrorKind::InvalidSecret, e.to_string()),
             },
-            ServerState::WaitingForSecretInjection(params) => {
-                match process_injected_secret(req, params) {
-                    Ok(_) => {
-                        eprintln!("Secret injection succeeded! Shutting down attestation server...");
-                        return Ok(());
-                    },
-                    Err(e) => {
-                        eprintln!("Error processing injected secret : {:#?}", e);
-                        eprintln!("Send another attestation report request to try again");
-                        state = ServerState::Ready;
-                    }
+            RequestMessage::RotateKey(wrapped_rotation) => {
+                self.handle_key_rotation(wrapped_rotation)
+            }
+            RequestMessage::ConfirmRotation(confirmation) => {
+                self.handle_rotation_confirmation(confirmation)
+            }
+            RequestMessage::OpenControlChannel(wrapped_key) => {
+                self.handle_open_control_channel(wrapped_key)
+            }
+            RequestMessage::Control(sealed) => self.handle_control(sealed),
+            RequestMessage::PullChallenge | RequestMessage::PullSecret(_) => error_response(
+                ProtocolErrorKind::UnexpectedMessage,
+                "pull mode messages must be sent to a key broker",
+            ),
+        };
+        Envelope::new(req.version, resp)
+    }
+
+    fn handle_report_request(
+        &mut self,
+        att_req: &AttestationRequest,
+        version: u32,
+    ) -> ResponseMessage {
+        let (report, params) = match send_report(
+            att_req.nonce,
+            self.config.backend,
+            version,
+            self.config.min_protocol_version,
+        ) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Error while serving attestation report request: {:#?}", e);
+                return error_response(ProtocolErrorKind::ReportUnavailable, e.to_string());
+            }
+        };
+        let public_key = params.public_key;
+        match self.sessions.insert(params) {
+            Ok(session_id) => ResponseMessage::Report(Box::new(AttestationResponse {
+                session_id,
+                report,
+                public_key,
+            })),
+            Err(e) => error_response(ProtocolErrorKind::TooManySessions, e.to_string()),
+        }
+    }
+
+    fn handle_secret_injection(&mut self, wrapped_key: WrappedDiskKey) -> ResponseMessage {
+        let session_id = wrapped_key.session_id.clone();
+        let config = self.config;
+        self.handle_wrapped_secret(&session_id, |params| {
+            config.check_owner_signature(&wrapped_key, &params.report)?;
+            process_injected_secret(wrapped_key, params, &config.secret_output)
+        })
+    }
+
+    ///Look up the key material of the session and pass it to `process`, which unwraps and stores the secret
+    fn handle_wrapped_secret<F>(&mut self, session_id: &str, process: F) -> ResponseMessage
+    where
+        F: FnOnce(SecretInjectionParams) -> Result<(), Whatever>,
+    {
+        if self.config.no_secret_injection {
+            return error_response(
+                ProtocolErrorKind::UnexpectedMessage,
+                "secret injection is disabled",
+            );
+        }
+        if self.secret_injected {
+            return error_response(
+                ProtocolErrorKind::UnexpectedMessage,
+                "a secret was already injected, use the control channel",
+            );
+        }
+        let params = match self.sessions.take(session_id) {
+            Some(v) => v,
+            None => {
+                eprintln!("Got secret injection for unknown or expired session");
+                return error_response(
+                    ProtocolErrorKind::UnknownSession,
+                    "unknown or expired session",
+                );
+            }
+        };
+        match process(params) {
+            Ok(_) => {
+                self.secret_accepted();
+                ResponseMessage::SecretAccepted
+            }
+            Err(e) => {
+                self.failed_attempts += 1;
+                eprintln!("Error processing injected secret : {:#?}", e);
+                eprintln!("Send another attestation report request to try again");
+                error_response(ProtocolErrorKind::InvalidSecret, e.to_string())
+            }
+        }
+    }
+
+    ///Add the new key and keep the old one until the owner confirms the rotation. Unlike secret
+    ///injection, a rotation does not stop the server
+    fn handle_key_rotation(&mut self, wrapped_rotation: WrappedKeyRotation) -> ResponseMessage {
+        let device = match &self.config.luks_device {
+            Some(v) => LuksDevice(v.clone()),
+            None => {
+                return error_response(
+                    ProtocolErrorKind::UnexpectedMessage,
+                    "key rotation is disabled",
+                )
+            }
+        };
+        let params = match self.sessions.take(&wrapped_rotation.session_id) {
+            Some(v) => v,
+            None => {
+                return error_response(
+                    ProtocolErrorKind::UnknownSession,
+                    "unknown or expired session",
+                )
+            }
+        };
+        let report = params.report;
+        let result = self
+            .config
+            .check_owner_signature(&wrapped_rotation, &report)
+            .and_then(|_| process_key_rotation(wrapped_rotation, params, &device));
+        let current_key = match result {
+            Ok(v) => v,
+            Err(e) => {
+                self.failed_attempts += 1;
+                eprintln!("Error processing key rotation : {:#?}", e);
+                return error_response(ProtocolErrorKind::InvalidSecret, e.to_string());
+            }
+        };
+        match self.rotations.insert(PendingRotation {
+            report,
+            current_key,
+        }) {
+            Ok(rotation_id) => ResponseMessage::RotationPending(RotationPending { rotation_id }),
+            Err(e) => error_response(ProtocolErrorKind::TooManySessions, e.to_string()),
+        }
+    }
+
+    fn handle_rotation_confirmation(
+        &mut self,
+        confirmation: RotationConfirmation,
+    ) -> ResponseMessage {
+        let device = match &self.config.luks_device {
+            Some(v) => LuksDevice(v.clone()),
+            None => {
+                return error_response(
+                    ProtocolErrorKind::UnexpectedMessage,
+                    "key rotation is disabled",
+                )
+            }
+        };
+        let pending = match self.rotations.take(&confirmation.rotation_id) {
+            Some(v) => v,
+            None => {
+                return error_response(
+                    ProtocolErrorKind::UnknownSession,
+                    "unknown or expired rotation",
+                )
+            }
+        };
+        if let Err(e) = self
+            .config
+            .check_owner_signature(&confirmation, &pending.report)
+        {
+            eprintln!("Rejected rotation confirmation : {:#?}", e);
+            return error_response(ProtocolErrorKind::InvalidSecret, e.to_string());
+        }
+        match device.remove_key(pending.current_key.expose()) {
+            Ok(_) => {
+                println!("Removed old key from {}", device.0.display());
+                ResponseMessage::RotationComplete
+            }
+            Err(e) => {
+                eprintln!("Error removing old key : {:#?}", e);
+                error_response(ProtocolErrorKind::Internal, e.to_string())
+            }
+        }
+    }
+
+    ///Stop after the first secret, unless the owner may send further commands via the control channel
+    fn secret_accepted(&mut self) {
+        self.secret_injected = true;
+        self.done = !self.config.control_channel;
+    }
+
+    fn handle_open_control_channel(&mut self, wrapped_key: WrappedChannelKey) -> ResponseMessage {
+        if !self.config.control_channel {
+            return error_response(
+                ProtocolErrorKind::UnexpectedMessage,
+                "the control channel is disabled",
+            );
+        }
+        let params = match self.sessions.take(&wrapped_key.session_id) {
+            Some(v) => v,
+            None => {
+                return error_response(
+                    ProtocolErrorKind::UnknownSession,
+                    "unknown or expired session",
+                )
+            }
+        };
+        let result = self
+            .config
+            .check_owner_signature(&wrapped_key, &params.report)
+            .and_then(|_| open_control_channel(wrapped_key, params));
+        let channel = match result {
+            Ok(v) => v,
+            Err(e) => {
+                self.failed_attempts += 1;
+                eprintln!("Error opening control channel : {:#?}", e);
+                return error_response(ProtocolErrorKind::InvalidSecret, e.to_string());
+            }
+        };
+        match self.channels.insert(channel) {
+            Ok(channel_id) => {
+                println!("Opened control channel");
+                ResponseMessage::ControlChannelOpened(ControlChannelOpened { channel_id })
+            }
+            Err(e) => error_response(ProtocolErrorKind::TooManySessions, e.to_string()),
+        }
+    }
+
+    ///Decrypt the command, run it and encrypt the result. A message that fails to decrypt closes
+    ///the channel, as it was either tampered with or is out of sequence
+    fn handle_control(&mut self, sealed: SealedControlMessage) -> ResponseMessage {
+        let channel_id = sealed.channel_id.clone();
+        let request = match self.channels.touch(&channel_id) {
+            Some(channel) => channel.open::<ControlRequest>(sealed),
+            None => {
+                return error_response(
+                    ProtocolErrorKind::UnknownSession,
+                    "unknown or expired control channel",
+                )
+            }
+        };
+        let request = match request {
+            Ok(v) => v,
+            Err(e) => {
+                self.channels.take(&channel_id);
+                eprintln!("Closing control channel : {:#?}", e);
+                return error_response(ProtocolErrorKind::MalformedRequest, e.to_string());
+            }
+        };
+        let response = self.handle_control_request(request);
+        let sealed = match self.channels.touch(&channel_id) {
+            Some(channel) => channel.seal(&channel_id, &response),
+            None => {
+                return error_response(ProtocolErrorKind::Internal, "control channel was closed")
+            }
+        };
+        match sealed {
+            Ok(v) => ResponseMessage::Control(v),
+            Err(e) => error_response(ProtocolErrorKind::Internal, e.to_string()),
+        }
+    }
+
+    fn handle_control_request(&mut self, request: ControlRequest) -> ControlResponse {
+        match request {
+            ControlRequest::Status => ControlResponse::Status(ServerStatus {
+                uptime_secs: self.started.elapsed().as_secs(),
+                secret_injected: self.secret_injected,
+                failed_attempts: self.failed_attempts,
+                open_channels: self.channels.len(),
+            }),
+            ControlRequest::Report(att_req) => {
+                match get_report(
+                    ReportClaims::new()
+                        .with_nonce(att_req.nonce)
+                        .to_report_data(),
+                    self.config.backend,
+                ) {
+                    Ok(v) => ControlResponse::Report(Box::new(v)),
+                    Err(e) => ControlResponse::Error(e.to_string()),
                 }
             }
+            ControlRequest::InjectSecretBundle(_) if self.config.no_secret_injection => {
+                ControlResponse::Error("secret injection is disabled".to_string())
+            }
+            ControlRequest::InjectSecretBundle(bundle) => match bundle.deliver() {
+                Ok(_) => ControlResponse::SecretAccepted,
+                Err(e) => {
+                    eprintln!("Error delivering secrets from control channel : {:#?}", e);
+                    ControlResponse::Error(e.to_string())
+                }
+            },
+            ControlRequest::Shutdown => {
+                println!("Shutdown requested via control channel");
+                self.done = true;
+                ControlResponse::ShuttingDown
+            }
+        }
+    }
+
+    ///The disk key is protected by the RA-TLS channel, thus there is no additional wrapping
+    fn handle_ra_tls_secret(&mut self, disk_key: RaTlsDiskKey) -> ResponseMessage {
+        let sink = self.config.secret_output.clone();
+        if let Err(e) = disk_key.disk_key.validate() {
+            return error_response(ProtocolErrorKind::InvalidSecret, e.to_string());
+        }
+        if let Some(report) = &self.ra_tls_report {
+            if let Err(e) = self.config.check_owner_signature(&disk_key, report) {
+                eprintln!("Rejected secret: {:#?}", e);
+                return error_response(ProtocolErrorKind::InvalidSecret, e.to_string());
+            }
+        }
+        self.handle_ra_tls_secret_with(|| store_disk_key(disk_key.disk_key.data.expose(), &sink))
+    }
+
+    ///Run `store` if secret injection via RA-TLS is enabled
+    fn handle_ra_tls_secret_with<F>(&mut self, store: F) -> ResponseMessage
+    where
+        F: FnOnce() -> Result<(), Whatever>,
+    {
+        if self.config.no_secret_injection || self.ra_tls_report.is_none() {
+            return error_response(
+                ProtocolErrorKind::UnexpectedMessage,
+                "secret injection via RA-TLS is disabled",
+            );
+        }
+        if self.secret_injected {
+            return error_response(
+                ProtocolErrorKind::UnexpectedMessage,
+                "a secret was already injected, use the control channel",
+            );
+        }
+        match store() {
+            Ok(_) => {
+                self.secret_accepted();
+                ResponseMessage::SecretAccepted
+            }
+            Err(e) => {
+                eprintln!("Error processing injected secret : {:#?}", e);
+                error_response(ProtocolErrorKind::Internal, e.to_string())
+            }
         }
     }
 }
 
-fn main() -> Result<(), Whatever>{
-    let config = Config{
-        no_secret_injection: env::var("NO_SECRET_INJECTION").is_ok(),
-        mock_mode: env::var("MOCK").is_ok(),
-        listen: env::var("LISTEN").unwrap_or("0.0.0.0:80".to_string()),
-    };
-    println!("Starting attestation server on {}",&config.listen);
+impl AttestationService<'_> {
+    ///Returns an error if the configured number of failed attempts was reached
+    fn check_attempts(&self) -> Result<(), Whatever> {
+        if let Some(max_attempts) = self.config.max_attempts {
+            if self.failed_attempts >= max_attempts {
+                whatever!(
+                    "giving up after {} failed secret injection attempts",
+                    self.failed_attempts
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn error_response(kind: ProtocolErrorKind, message: impl Into<String>) -> ResponseMessage {
+    ResponseMessage::Error(ProtocolError::new(kind, message))
+}
+
+///Serve protocol messages until a secret was injected or, with the control channel, until the
+///owner requests a shutdown
+fn serve(
+    transport: &mut dyn ServerTransport,
+    service: &mut AttestationService,
+) -> Result<(), Whatever> {
+    let deadline = service.config.timeout().map(|v| Instant::now() + v);
+    while !service.done {
+        service.check_attempts()?;
+        //the timeout only applies until the first secret was injected
+        let deadline = if service.secret_injected {
+            None
+        } else {
+            deadline
+        };
+        let Incoming { request, responder } = match transport.next_message(deadline) {
+            Some(v) => v,
+            None => whatever!("no secret was injected within the configured timeout"),
+        };
+        let resp = match request {
+            Ok(msg) => service.handle(msg),
+            Err(e) => Envelope::new(PROTOCOL_VERSION, ResponseMessage::Error(e)),
+        };
+        responder.send(&resp);
+    }
+    Ok(())
+}
+
+fn run(config: &Config) -> Result<(), Whatever> {
+    let ssl;
+    let ra_tls_report;
+    if config.ra_tls {
+        println!("Generating RA-TLS certificate");
+        let identity = match config.backend {
+            AttestationBackend::Mock => RaTlsIdentity::generate::<MockSNPAttestation>()?,
+            AttestationBackend::Snp => RaTlsIdentity::generate::<SNPAttestation>()?,
+        };
+        ssl = Some(SslConfig {
+            certificate: identity.certificate_pem,
+            private_key: identity.private_key_pem,
+        });
+        ra_tls_report = Some(identity.report);
+    } else {
+        ssl = None;
+        ra_tls_report = None;
+    }
+    let mut transport = config.listen.bind(ssl, ra_tls_report)?;
+    let mut service = AttestationService::new(config, ra_tls_report);
+    serve(transport.as_mut(), &mut service)?;
+    if config.control_channel {
+        eprintln!("Shutting down attestation server as requested via control channel...");
+    } else {
+        eprintln!("Secret injection succeeded! Shutting down attestation server...");
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Whatever> {
+    let mut config = Config::from_args(Args::parse())?;
+    attestation_server::secret::set_mlock(config.mlock_secrets);
+    config.secret_output = config.secret_output.detach_stdout()?;
+    if let Some(broker_url) = &config.pull {
+        println!("Pulling secret from key broker {}", broker_url);
+        return pull::run(&config, broker_url);
+    }
+    println!("Starting attestation server on {}", &config.listen);
     run(&config)
 }
@@ -1,17 +1,22 @@
 use std::{
     fs::{self, File},
+    path::PathBuf,
 };
 
 use attestation_server::{
     calc_expected_ld::VMDescription,
+    owner_auth::OwnerPublicKey,
+    snp_attestation::ReportClaims,
     snp_validate_report::{
-        parse_id_block_data, verify_and_check_report, CachingVCEKDownloader, ReportDataMismatchSnafu, ReportVerificationError,
+        evaluate_report, parse_id_block_data, verify_and_check_report, CachingVCEKDownloader,
+        ReportVerificationError, VerificationPolicy,
     },
+    verdict::{Verdict, VerdictFormat},
 };
 use base64::{engine::general_purpose, Engine};
 use clap::Parser;
 use sev::firmware::guest::AttestationReport;
-use snafu::{prelude::*};
+use snafu::prelude::*;
 
 #[derive(Debug, Snafu)]
 enum UserError {
@@ -59,13 +64,40 @@ struct Args {
     ///specify `id_block_path`
     author_block_path: Option<String>,
 
-    /// report data field, encoded in base64
-    #[arg(long, default_value = "")]
-    report_data: String,
+    #[arg(long)]
+    ///TOML file with verification rules, see examples/verification-policy.toml. Its rules replace
+    ///the checks derived from `vm_definition` and the id block for the fields they cover
+    policy: Option<PathBuf>,
+
+    #[arg(long)]
+    ///Evaluate all checks, including the signature, instead of stopping at the first failure,
+    ///and print the result of each
+    all_checks: bool,
+
+    #[arg(long)]
+    ///Write a machine readable verdict with the decoded report, every check, the certificate
+    ///chain and the final result to this file. Implies evaluating all checks
+    verdict: Option<PathBuf>,
+
+    #[arg(long, default_value_t = VerdictFormat::Json)]
+    ///Format of `verdict`: json or junit
+    verdict_format: VerdictFormat,
+
+    /// SSH host key fingerprint that the report must be bound to, see `get_report`
+    #[arg(long)]
+    ssh_fingerprint: Option<String>,
+
+    ///Hex encoded Ed25519 owner public key that HOST_DATA must contain. Required if the vm config
+    ///sets `owner_key_in_host_data`
+    #[arg(long)]
+    owner_public_key: Option<OwnerPublicKey>,
+
+    /// Application specific data that the report must be bound to, encoded in base64
+    #[arg(long)]
+    app_data: Option<String>,
 }
 
 fn run(args: &Args) -> Result<(), UserError> {
-
     //
     // Parse arguments
     //
@@ -97,9 +129,9 @@ fn run(args: &Args) -> Result<(), UserError> {
     if let (Some(id_block_path), Some(id_auth_block_path)) =
         (&args.id_block_path, &args.author_block_path)
     {
-        let raw_id_block = fs::read(&id_block_path)
+        let raw_id_block = fs::read(id_block_path)
             .whatever_context(format!("failed to read id block from {}", &id_block_path))?;
-        let raw_id_auth_block = fs::read(&id_auth_block_path).whatever_context(format!(
+        let raw_id_auth_block = fs::read(id_auth_block_path).whatever_context(format!(
             "failed to read id auth block from {}",
             &id_auth_block_path
         ))?;
@@ -111,20 +143,37 @@ fn run(args: &Args) -> Result<(), UserError> {
         id_data = None;
     }
 
-    let report_data_raw = general_purpose::STANDARD_NO_PAD
-        .decode(&args.report_data)
-        .whatever_context("failed to decode report_data as base64")?;
-    let report_data_len = report_data_raw.len();
-
-    if report_data_len > 64 {
-        whatever!(
-            "report data length should be <= 64 bytes but you provided {} bytes",
-            report_data_len
-        );
+    let mut policy = VerificationPolicy::from_vm_description(&vm_description, expected_ld)
+        .whatever_context("failed to derive verification policy from the vm config")?;
+    if let Some((_, _, id_block_data)) = &id_data {
+        policy = policy.with_id_block(id_block_data);
+    }
+    match (
+        &args.owner_public_key,
+        vm_description.owner_key_in_host_data,
+    ) {
+        (Some(owner_key), _) => policy = policy.with_host_data(owner_key.0),
+        (None, true) => whatever!(
+            "the vm config sets owner_key_in_host_data, which requires --owner-public-key"
+        ),
+        (None, false) => (),
+    }
+    if let Some(path) = &args.policy {
+        policy = VerificationPolicy::load(path)
+            .whatever_context("failed to load verification policy")?
+            .or(policy);
     }
 
-    let mut report_data = [0u8; 64];
-    report_data[..report_data_len].copy_from_slice(&report_data_raw);
+    let mut claims = ReportClaims::new();
+    if let Some(fingerprint) = &args.ssh_fingerprint {
+        claims = claims.with_ssh_fingerprint(fingerprint);
+    }
+    if let Some(app_data) = &args.app_data {
+        let app_data = general_purpose::STANDARD
+            .decode(app_data)
+            .whatever_context("failed to decode app_data as base64")?;
+        claims = claims.with_app_data(&app_data);
+    }
 
     //
     // Validate
@@ -147,44 +196,49 @@ fn run(args: &Args) -> Result<(), UserError> {
 
     //Veryfing content
     let report_data_validator = |vm_data: [u8; 64]| {
-        let report_data_b64 = general_purpose::STANDARD_NO_PAD.encode(&vm_data);
-
-        if args.report_data.is_empty() {
+        if claims == ReportClaims::default() {
             // just print it for info
             println!(
-                "Your config does not expect report data but VM provided: {}",
-                report_data_b64
+                "Your config does not expect report data but VM provided: 0x{}",
+                hex::encode(vm_data)
             );
-        } else {
-            // actually validate
-            if report_data != vm_data {
-                return ReportDataMismatchSnafu {
-                    expected: format!("0x{}",hex::encode(report_data)),
-                    got: format!("0x{}",hex::encode(vm_data)),
-                }
-                .fail();
-            }
+            return Ok(());
         }
-        Ok(())
+        claims.check(&vm_data)
     };
-    let id_block_data = if let Some((_, _, v)) = id_data {
-        Some(v)
+    if args.all_checks || args.verdict.is_some() {
+        let summary = evaluate_report(
+            &attestation_report,
+            vm_description.host_cpu_family,
+            vcek_cert.clone(),
+            &policy,
+            Some(report_data_validator),
+        );
+        if args.all_checks {
+            print!("{}", summary);
+        }
+        if let Some(path) = &args.verdict {
+            Verdict::new(
+                &attestation_report,
+                vm_description.host_cpu_family,
+                &vcek_cert,
+                Some(expected_ld),
+                summary.clone(),
+            )
+            .and_then(|v| v.write(path, args.verdict_format))
+            .whatever_context("failed to create verdict")?;
+        }
+        summary.into_result().context(InvalidReportSnafu {})?;
     } else {
-        None
-    };
-    verify_and_check_report(
-        &attestation_report,
-        vm_description.host_cpu_family,
-        vcek_cert,
-        id_block_data,
-        Some(vm_description.guest_policy),
-        Some(vm_description.min_commited_tcb),
-        Some(vm_description.platform_info),
-        Some(report_data_validator),
-        None, //We don't use host data right now
-        Some(expected_ld),
-    )
-    .context(InvalidReportSnafu {})?;
+        verify_and_check_report(
+            &attestation_report,
+            vm_description.host_cpu_family,
+            vcek_cert,
+            &policy,
+            Some(report_data_validator),
+        )
+        .context(InvalidReportSnafu {})?;
+    }
 
     Ok(())
 }
@@ -199,11 +253,8 @@ fn main() -> Result<(), UserError> {
             Ok(())
         }
         Err(e) => {
-            match e {
-                UserError::InvalidReport { .. } => {
-                    println!("Program executed successfully but attestation report was invalid.\nIn case of mismatching values, verify that the data in the vm config file {} matches your host.\nPlease find more details on the verification error below.",&args.vm_definition);
-                }
-                _ => (),
+            if let UserError::InvalidReport { .. } = e {
+                println!("Program executed successfully but attestation report was invalid.\nIn case of mismatching values, verify that the data in the vm config file {} matches your host.\nPlease find more details on the verification error below.",&args.vm_definition);
             }
             Err(e)
         }
@@ -0,0 +1,294 @@
+//! Key broker for the pull mode.
+//!
+//! Instead of the owner pushing the secret into the VM, the VM contacts the broker. It first
+//! requests a challenge nonce and then sends an attestation report that binds the nonce and a
+//! fresh public DH key. If the report is acceptable according to the `ReleasePolicy`, the broker
+//! answers with the secret, wrapped for the public key bound to the report
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use sev::firmware::guest::AttestationReport;
+use snafu::{whatever, Whatever};
+
+use crate::{
+    owner_auth::OwnerSigningKey,
+    req_resp_ds::{
+        enabled_versions, Capability, Envelope, ProtocolError, ProtocolErrorKind, PullChallenge,
+        PullRequest, ReportBinding, RequestMessage, ResponseMessage, SecretPayload, ServerInfo,
+        WrappedDiskKey, WrappingScheme, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    session::SessionStore,
+    transport::{Incoming, ServerTransport},
+};
+
+///Time in which the VM has to answer a challenge
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(60);
+///Upper bound on the number of concurrently open challenges
+pub const MAX_OPEN_CHALLENGES: usize = 64;
+
+///Decides whether a VM gets a secret
+pub trait ReleasePolicy {
+    ///Verify `report` and return the secret for the VM that produced it. The broker only checks
+    ///that the report data binds the challenge nonce and the public key, everything else is up to the policy
+    fn release(&self, report: &AttestationReport) -> Result<SecretPayload, Whatever>;
+}
+
+pub struct KeyBroker<P> {
+    policy: P,
+    ///Challenge nonces, indexed by session id
+    sessions: SessionStore<u64>,
+    rng: SystemRandom,
+    ///Signs released secrets, for VMs that pin the owner key
+    owner_key: Option<OwnerSigningKey>,
+    ///Requests with older protocol versions are rejected, see `MIN_PROTOCOL_VERSION`
+    min_version: u32,
+}
+
+fn error_response(kind: ProtocolErrorKind, message: impl Into<String>) -> ResponseMessage {
+    ResponseMessage::Error(ProtocolError::new(kind, message))
+}
+
+impl<P: ReleasePolicy> KeyBroker<P> {
+    pub fn new(policy: P) -> Self {
+        KeyBroker {
+            policy,
+            sessions: SessionStore::new(CHALLENGE_TIMEOUT, MAX_OPEN_CHALLENGES),
+            rng: SystemRandom::new(),
+            owner_key: None,
+            min_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    ///Sign all released secrets with `owner_key`. Required if the VMs pin the owner key
+    pub fn with_owner_key(mut self, owner_key: OwnerSigningKey) -> Self {
+        self.owner_key = Some(owner_key);
+        self
+    }
+
+    ///Also serve VMs that only speak protocol versions down to `min_version`
+    pub fn with_min_protocol_version(mut self, min_version: u32) -> Self {
+        self.min_version = min_version;
+        self
+    }
+
+    fn info(&self) -> ServerInfo {
+        ServerInfo {
+            supported_versions: enabled_versions(self.min_version),
+            capabilities: vec![Capability::KeyBroker],
+        }
+    }
+
+    ///Process a single protocol message
+    pub fn handle(&mut self, req: Envelope<RequestMessage>) -> Envelope<ResponseMessage> {
+        if !enabled_versions(self.min_version).contains(&req.version) {
+            let resp = match req.message {
+                RequestMessage::Info => ResponseMessage::Info(self.info()),
+                _ => error_response(
+                    ProtocolErrorKind::UnsupportedVersion,
+                    format!(
+                        "protocol version {} is not supported, see info message",
+                        req.version
+                    ),
+                ),
+            };
+            return Envelope::new(PROTOCOL_VERSION, resp);
+        }
+        let resp = match req.message {
+            RequestMessage::Info => ResponseMessage::Info(self.info()),
+            RequestMessage::PullChallenge => self.handle_challenge(),
+            RequestMessage::PullSecret(pull_req) => self.handle_pull(*pull_req, req.version),
+            _ => error_response(
+                ProtocolErrorKind::UnexpectedMessage,
+                "key broker only serves pull mode messages",
+            ),
+        };
+        Envelope::new(req.version, resp)
+    }
+
+    fn handle_challenge(&mut self) -> ResponseMessage {
+        let mut raw_nonce = [0u8; 8];
+        if self.rng.fill(&mut raw_nonce).is_err() {
+            return error_response(ProtocolErrorKind::Internal, "failed to sample nonce");
+        }
+        let nonce = u64::from_le_bytes(raw_nonce);
+        match self.sessions.insert(nonce) {
+            Ok(session_id) => ResponseMessage::Challenge(PullChallenge { session_id, nonce }),
+            Err(e) => error_response(ProtocolErrorKind::TooManySessions, e.to_string()),
+        }
+    }
+
+    fn handle_pull(&mut self, pull_req: PullRequest, version: u32) -> ResponseMessage {
+        let nonce = match self.sessions.take(&pull_req.session_id) {
+            Some(v) => v,
+            None => {
+                return error_response(
+                    ProtocolErrorKind::UnknownSession,
+                    "unknown or expired session, request a new challenge",
+                )
+            }
+        };
+        let binding = match ReportBinding::for_version(version, self.min_version) {
+            Ok(v) => v,
+            Err(e) => return error_response(ProtocolErrorKind::UnsupportedVersion, e.to_string()),
+        };
+        let vm_public_key = binding.public_key(&pull_req.report.report_data, pull_req.public_key);
+        if let Err(e) = binding.check(&pull_req.report.report_data, nonce, vm_public_key) {
+            return error_response(
+                ProtocolErrorKind::ReportRejected,
+                format!(
+                    "report data does not bind the challenge nonce and public key: {}",
+                    e
+                ),
+            );
+        }
+        let secret = match self.policy.release(&pull_req.report) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Rejected attestation report: {:#?}", e);
+                return error_response(ProtocolErrorKind::ReportRejected, e.to_string());
+            }
+        };
+        if let Err(e) = secret.validate() {
+            eprintln!("Refusing to release invalid secret: {:#?}", e);
+            return error_response(
+                ProtocolErrorKind::Internal,
+                "the configured secret is invalid",
+            );
+        }
+        let scheme = WrappingScheme::for_version(version);
+        let mut wrapped_key =
+            match scheme.wrap(&pull_req.report, &vm_public_key, secret.data.expose()) {
+                Ok((broker_public_key, wrapped)) => WrappedDiskKey {
+                    session_id: pull_req.session_id,
+                    wrapped_disk_key: wrapped,
+                    client_public_key: broker_public_key,
+                    encoding: secret.encoding,
+                    owner_signature: None,
+                },
+                Err(e) => return error_response(ProtocolErrorKind::Internal, e.to_string()),
+            };
+        if let Some(owner_key) = &self.owner_key {
+            if let Err(e) = owner_key.sign(&mut wrapped_key, &pull_req.report) {
+                return error_response(ProtocolErrorKind::Internal, e.to_string());
+            }
+        }
+        ResponseMessage::WrappedSecret(wrapped_key)
+    }
+
+    ///Serve protocol messages until the transport fails
+    pub fn serve(&mut self, transport: &mut dyn ServerTransport) -> Result<(), Whatever> {
+        loop {
+            let Incoming { request, responder } = match transport.next_message(None) {
+                Some(v) => v,
+                None => whatever!("transport stopped delivering messages"),
+            };
+            let resp = match request {
+                Ok(msg) => self.handle(msg),
+                Err(e) => Envelope::new(PROTOCOL_VERSION, ResponseMessage::Error(e)),
+            };
+            responder.send(&resp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sev::firmware::guest::AttestationReport;
+    use snafu::{whatever, Whatever};
+
+    use super::{KeyBroker, ReleasePolicy};
+    use crate::req_resp_ds::{
+        Envelope, PullRequest, ReportBinding, RequestMessage, ResponseMessage, SecretEncoding,
+        SecretPayload, UnwrappingKey, WrappingScheme, PROTOCOL_VERSION,
+        SUPPORTED_PROTOCOL_VERSIONS,
+    };
+
+    struct FixedSecret;
+
+    impl ReleasePolicy for FixedSecret {
+        fn release(&self, report: &AttestationReport) -> Result<SecretPayload, Whatever> {
+            if report.guest_svn != 1 {
+                whatever!("unexpected guest svn");
+            }
+            Ok(SecretPayload::binary(vec![0xff, 0x00, 0x42]))
+        }
+    }
+
+    fn call(
+        broker: &mut KeyBroker<FixedSecret>,
+        version: u32,
+        msg: RequestMessage,
+    ) -> ResponseMessage {
+        broker.handle(Envelope::new(version, msg)).message
+    }
+
+    fn pull(
+        broker: &mut KeyBroker<FixedSecret>,
+        version: u32,
+        guest_svn: u32,
+        nonce_offset: u64,
+    ) -> (ResponseMessage, AttestationReport, UnwrappingKey) {
+        let challenge = match call(broker, version, RequestMessage::PullChallenge) {
+            ResponseMessage::Challenge(v) => v,
+            other => panic!("unexpected response {:?}", other),
+        };
+        let private_key = UnwrappingKey::generate(WrappingScheme::for_version(version)).unwrap();
+        let public_key = private_key.public_key().unwrap();
+        let nonce = challenge.nonce.wrapping_add(nonce_offset);
+        let mut report = AttestationReport::default();
+        report.guest_svn = guest_svn;
+        report.report_data = ReportBinding::for_version(version, 1)
+            .unwrap()
+            .report_data(nonce, public_key);
+        let resp = call(
+            broker,
+            version,
+            RequestMessage::PullSecret(Box::new(PullRequest {
+                session_id: challenge.session_id,
+                report,
+                //like older peers, only send the key if it is not part of the report data
+                public_key: if version >= 3 { public_key } else { [0; 32] },
+            })),
+        );
+        (resp, report, private_key)
+    }
+
+    #[test]
+    fn pull_secret() {
+        let mut broker = KeyBroker::new(FixedSecret).with_min_protocol_version(1);
+        for &version in SUPPORTED_PROTOCOL_VERSIONS {
+            let (resp, report, private_key) = pull(&mut broker, version, 1, 0);
+            let wrapped = match resp {
+                ResponseMessage::WrappedSecret(v) => v,
+                other => panic!("unexpected response {:?} for version {}", other, version),
+            };
+            let secret = private_key
+                .unwrap(&report, wrapped.client_public_key, wrapped.wrapped_disk_key)
+                .unwrap();
+            assert_eq!(secret.expose(), [0xff, 0x00, 0x42]);
+            assert_eq!(wrapped.encoding, SecretEncoding::Binary);
+
+            assert!(matches!(
+                pull(&mut broker, version, 2, 0).0,
+                ResponseMessage::Error(_)
+            ));
+            assert!(matches!(
+                pull(&mut broker, version, 1, 1).0,
+                ResponseMessage::Error(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn reject_old_versions_by_default() {
+        let mut broker = KeyBroker::new(FixedSecret);
+        assert!(matches!(
+            call(&mut broker, 2, RequestMessage::PullChallenge),
+            ResponseMessage::Error(_)
+        ));
+        match call(&mut broker, 2, RequestMessage::Info) {
+            ResponseMessage::Info(info) => assert_eq!(info.supported_versions, [PROTOCOL_VERSION]),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+}
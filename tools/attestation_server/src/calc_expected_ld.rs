@@ -3,8 +3,8 @@ use sev::firmware::guest::{GuestPolicy, PlatformInfo};
 use sev::firmware::host::TcbVersion;
 use sev::measurement::{
     snp::{snp_calc_launch_digest, SnpMeasurementArgs},
+    vcpu_types::CpuType,
     vmsa::{GuestFeatures, VMMType},
-    vcpu_types::CpuType
 };
 use snafu::{whatever, ResultExt, Whatever};
 
@@ -12,7 +12,7 @@ use crate::snp_validate_report::ProductName;
 use hex_buffer_serde::{Hex as _, HexForm};
 
 ///Length fo the FamilyID and the ImageID data types in bytes
-pub const IDBLOCK_ID_BYTES :usize = 16;
+pub const IDBLOCK_ID_BYTES: usize = 16;
 
 #[derive(Serialize, Deserialize, Default)]
 ///User facing config struct to specify a VM.
@@ -27,13 +27,32 @@ pub struct VMDescription {
     pub kernel_file: String,
     pub initrd_file: String,
     pub kernel_cmdline: String,
+    ///Platform info of the host. Hosts with a platform info that is at least as strict are accepted
+    ///as well, e.g. hosts that additionally enable ECC or ciphertext hiding
     pub platform_info: PlatformInfo,
+    ///Names of platform info flags that must be set, see `PLATFORM_INFO_FLAGS`
+    #[serde(default)]
+    pub platform_info_required: Vec<String>,
+    ///Names of platform info flags that must not be set, see `PLATFORM_INFO_FLAGS`
+    #[serde(default)]
+    pub platform_info_forbidden: Vec<String>,
     ///Mininum required committed version numbers
     ///Committed means that the platform cannot be rolled back to a prior
     ///version
     pub min_commited_tcb: TcbVersion,
     /// Policy passed to QEMU and reflected in the attestation report
     pub guest_policy: GuestPolicy,
+    ///Minimum guest svn. Only used without an id block, which fixes the exact guest svn
+    #[serde(default)]
+    pub min_guest_svn: u32,
+    ///VMPL from which the guest requests its reports. Set it to 1 for guests whose initramfs
+    ///contains a server that predates requesting reports from VMPL 0
+    #[serde(default)]
+    pub vmpl: u32,
+    ///The VM takes the owner public key from HOST_DATA (`owner_public_key = "host_data"` in the
+    ///server config). The host chooses HOST_DATA, so verifiers then require it to be the owner key
+    #[serde(default)]
+    pub owner_key_in_host_data: bool,
     #[serde(with = "HexForm")]
     pub family_id: [u8; IDBLOCK_ID_BYTES],
     #[serde(with = "HexForm")]
@@ -49,7 +68,7 @@ impl VMDescription {
             guest_features: self.guest_features,
             kernel_file: Some(self.kernel_file.clone().into()),
             initrd_file: Some(self.initrd_file.clone().into()),
-            append: if self.kernel_cmdline != "" {
+            append: if !self.kernel_cmdline.is_empty() {
                 Some(&self.kernel_cmdline)
             } else {
                 None
@@ -61,8 +80,9 @@ impl VMDescription {
 
         let ld = snp_calc_launch_digest(snp_measure_args)
             .whatever_context("failed to compute launch digest")?;
-        let ld_vec = bincode::serialize(&ld).whatever_context("failed to bincode serialized SnpLaunchDigest to Vec<u8>")?;
-        let ld_arr : [u8; 384 / 8] = match ld_vec.try_into() {
+        let ld_vec = bincode::serialize(&ld)
+            .whatever_context("failed to bincode serialized SnpLaunchDigest to Vec<u8>")?;
+        let ld_arr: [u8; 384 / 8] = match ld_vec.try_into() {
             Ok(v) => v,
             Err(_) => whatever!("SnpLaunchDigest has unexpected length"),
         };
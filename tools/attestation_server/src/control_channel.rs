@@ -0,0 +1,217 @@
+//! Encrypted control channel between the owner and a running server.
+//!
+//! The owner attests the VM as usual and then sends a random channel key, wrapped for the
+//! attested DH key of the session, see `RequestMessage::OpenControlChannel`. Both sides derive one
+//! AES-256-GCM key per direction from the channel key and the report. Afterwards, each
+//! `ControlRequest` and `ControlResponse` is sent as `SealedControlMessage`. The sequence number
+//! serves as nonce and must increase by one with every message, so that messages cannot be
+//! replayed, reordered or dropped without the peer noticing
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hkdf::{Salt, HKDF_SHA256},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sev::firmware::guest::AttestationReport;
+use snafu::{whatever, FromString, ResultExt, Whatever};
+
+use crate::{
+    req_resp_ds::{report_digest, AttestationRequest, SealedControlMessage},
+    secret::SecretBytes,
+    secret_bundle::SecretBundle,
+};
+
+///Length of the channel key that the owner sends to the server
+pub const CHANNEL_KEY_BYTES: usize = 32;
+
+const CLIENT_TO_SERVER_LABEL: &[u8] = b"snpguard control channel client to server v1";
+const SERVER_TO_CLIENT_LABEL: &[u8] = b"snpguard control channel server to client v1";
+
+///Owner commands on the control channel
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", content = "body", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    ///Request a fresh attestation report bound to the nonce of the request
+    Report(AttestationRequest),
+    ///Deliver additional secrets to their destinations
+    InjectSecretBundle(SecretBundle),
+    ///Stop the server after answering
+    Shutdown,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", content = "body", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Status(ServerStatus),
+    ///Report bound to `ReportClaims` with only the nonce from the request
+    Report(Box<AttestationReport>),
+    SecretAccepted,
+    ShuttingDown,
+    ///The command failed. The channel stays open
+    Error(String),
+}
+
+///Answer to `ControlRequest::Status`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ServerStatus {
+    pub uptime_secs: u64,
+    ///Whether a secret was injected before the channel was opened
+    pub secret_injected: bool,
+    pub failed_attempts: u32,
+    ///Number of open control channels, including this one
+    pub open_channels: usize,
+}
+
+///Which end of the channel we are. Determines the key for sending and for receiving
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+fn derive_key(
+    channel_key: &[u8; CHANNEL_KEY_BYTES],
+    salt: &Salt,
+    label: &[u8],
+) -> Result<LessSafeKey, Whatever> {
+    let label = [label];
+    let prk = salt.extract(channel_key);
+    let okm = prk.expand(&label, &AES_256_GCM).map_err(|_| {
+        Whatever::without_source("failed to derive control channel key".to_string())
+    })?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+fn nonce(sequence: u64) -> Nonce {
+    let mut raw = [0u8; NONCE_LEN];
+    raw[NONCE_LEN - 8..].copy_from_slice(&sequence.to_be_bytes());
+    Nonce::assume_unique_for_key(raw)
+}
+
+fn aad(channel_id: &str, sequence: u64) -> Vec<u8> {
+    [channel_id.as_bytes(), &sequence.to_be_bytes()].concat()
+}
+
+///Keys and sequence numbers of one end of a control channel
+pub struct ControlChannel {
+    send_key: LessSafeKey,
+    receive_key: LessSafeKey,
+    send_sequence: u64,
+    receive_sequence: u64,
+}
+
+impl ControlChannel {
+    ///`report` is the report of the session in which the channel key was exchanged
+    pub fn new(
+        channel_key: &[u8; CHANNEL_KEY_BYTES],
+        report: &AttestationReport,
+        role: Role,
+    ) -> Result<Self, Whatever> {
+        let salt = Salt::new(HKDF_SHA256, &report_digest(report)?);
+        let client_to_server = derive_key(channel_key, &salt, CLIENT_TO_SERVER_LABEL)?;
+        let server_to_client = derive_key(channel_key, &salt, SERVER_TO_CLIENT_LABEL)?;
+        let (send_key, receive_key) = match role {
+            Role::Client => (client_to_server, server_to_client),
+            Role::Server => (server_to_client, client_to_server),
+        };
+        Ok(ControlChannel {
+            send_key,
+            receive_key,
+            send_sequence: 0,
+            receive_sequence: 0,
+        })
+    }
+
+    ///Encrypt the next message to the peer
+    pub fn seal<T: Serialize>(
+        &mut self,
+        channel_id: &str,
+        msg: &T,
+    ) -> Result<SealedControlMessage, Whatever> {
+        //messages may contain secrets, so only ever put the plaintext into a zeroizing buffer
+        let mut buffer =
+            SecretBytes::to_json(msg).whatever_context("failed to serialize control message")?;
+        let sequence = self.send_sequence;
+        let tag = self
+            .send_key
+            .seal_in_place_separate_tag(
+                nonce(sequence),
+                Aad::from(aad(channel_id, sequence)),
+                buffer.expose_mut(),
+            )
+            .map_err(|_| {
+                Whatever::without_source("failed to encrypt control message".to_string())
+            })?;
+        let ciphertext = [buffer.expose(), tag.as_ref()].concat();
+        self.send_sequence += 1;
+        Ok(SealedControlMessage {
+            channel_id: channel_id.to_string(),
+            sequence,
+            ciphertext,
+        })
+    }
+
+    ///Decrypt the next message from the peer. Fails for replayed or reordered messages
+    pub fn open<T: DeserializeOwned>(&mut self, msg: SealedControlMessage) -> Result<T, Whatever> {
+        if msg.sequence != self.receive_sequence {
+            whatever!(
+                "unexpected control message sequence number {}, expected {}",
+                msg.sequence,
+                self.receive_sequence
+            );
+        }
+        let mut buffer = SecretBytes::new(msg.ciphertext);
+        let plaintext_len = self
+            .receive_key
+            .open_in_place(
+                nonce(msg.sequence),
+                Aad::from(aad(&msg.channel_id, msg.sequence)),
+                buffer.expose_mut(),
+            )
+            .map_err(|_| Whatever::without_source("failed to decrypt control message".to_string()))?
+            .len();
+        buffer.truncate(plaintext_len);
+        let parsed = serde_json::from_slice(buffer.expose())
+            .whatever_context("failed to parse control message")?;
+        self.receive_sequence += 1;
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sev::firmware::guest::AttestationReport;
+
+    use super::{ControlChannel, ControlRequest, Role};
+
+    #[test]
+    fn channel_roundtrip() {
+        let report = AttestationReport::default();
+        let mut client = ControlChannel::new(&[7; 32], &report, Role::Client).unwrap();
+        let mut server = ControlChannel::new(&[7; 32], &report, Role::Server).unwrap();
+
+        let first = client.seal("a", &ControlRequest::Status).unwrap();
+        let replay = client.seal("a", &ControlRequest::Status).unwrap();
+        let replay = super::SealedControlMessage {
+            sequence: 0,
+            ..replay
+        };
+        assert!(matches!(
+            server.open(first).unwrap(),
+            ControlRequest::Status
+        ));
+        assert!(
+            server.open::<ControlRequest>(replay).is_err(),
+            "replayed message must be rejected"
+        );
+
+        let own = server.seal("a", &ControlRequest::Shutdown).unwrap();
+        let mut other_report = report;
+        other_report.report_data = [1; 64];
+        let mut other = ControlChannel::new(&[7; 32], &other_report, Role::Client).unwrap();
+        assert!(
+            other.open::<ControlRequest>(own).is_err(),
+            "keys must be bound to the report"
+        );
+    }
+}
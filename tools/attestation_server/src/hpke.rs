@@ -0,0 +1,326 @@
+//! Hybrid Public Key Encryption (RFC 9180) for the cipher suite
+//! DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-256-GCM.
+//!
+//! Only the single-shot API in base mode is implemented. Each sealed message uses a fresh
+//! encapsulation, thus the sequence number is always zero. The tests check the implementation
+//! against messages of `pyca/cryptography` and OpenSSL, so any RFC 9180 library can talk to it
+use openssl::{
+    derive::Deriver,
+    pkey::{Id, PKey, Private},
+};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM},
+    hmac,
+};
+use snafu::{whatever, FromString, ResultExt, Whatever};
+
+use crate::secret::SecretBytes;
+
+pub const KEM_DHKEM_X25519_HKDF_SHA256: u16 = 0x0020;
+pub const KDF_HKDF_SHA256: u16 = 0x0001;
+pub const AEAD_AES_256_GCM: u16 = 0x0002;
+
+///Length of public keys, encapsulated keys and DH outputs
+pub const N_PK: usize = 32;
+const N_H: usize = 32;
+const N_K: usize = 32;
+const N_N: usize = 12;
+
+const MODE_BASE: u8 = 0x00;
+
+fn kem_suite_id() -> Vec<u8> {
+    let mut id = b"KEM".to_vec();
+    id.extend_from_slice(&KEM_DHKEM_X25519_HKDF_SHA256.to_be_bytes());
+    id
+}
+
+fn hpke_suite_id() -> Vec<u8> {
+    let mut id = b"HPKE".to_vec();
+    for v in [
+        KEM_DHKEM_X25519_HKDF_SHA256,
+        KDF_HKDF_SHA256,
+        AEAD_AES_256_GCM,
+    ] {
+        id.extend_from_slice(&v.to_be_bytes());
+    }
+    id
+}
+
+fn labeled_extract(suite_id: &[u8], salt: &[u8], label: &[u8], ikm: &[u8]) -> hmac::Tag {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, salt);
+    let mut ctx = hmac::Context::with_key(&key);
+    for part in [b"HPKE-v1", suite_id, label, ikm] {
+        ctx.update(part);
+    }
+    ctx.sign()
+}
+
+fn labeled_expand(
+    suite_id: &[u8],
+    prk: &[u8],
+    label: &[u8],
+    info: &[u8],
+    len: usize,
+) -> SecretBytes {
+    assert!(len <= 255 * N_H, "HKDF-Expand output too long");
+    let key = hmac::Key::new(hmac::HMAC_SHA256, prk);
+    //room for the last, partially used block, so that the buffer never grows
+    let mut okm = SecretBytes::new(vec![0u8; len.div_ceil(N_H) * N_H]);
+    let mut previous: Option<hmac::Tag> = None;
+    for (idx, block) in okm.expose_mut().chunks_mut(N_H).enumerate() {
+        let mut ctx = hmac::Context::with_key(&key);
+        if let Some(previous) = &previous {
+            ctx.update(previous.as_ref());
+        }
+        for part in [
+            &(len as u16).to_be_bytes(),
+            b"HPKE-v1".as_slice(),
+            suite_id,
+            label,
+            info,
+        ] {
+            ctx.update(part);
+        }
+        ctx.update(&[idx as u8 + 1]);
+        let tag = ctx.sign();
+        block.copy_from_slice(tag.as_ref());
+        previous = Some(tag);
+    }
+    okm.truncate(len);
+    okm
+}
+
+///X25519 private key of an HPKE recipient or an ephemeral sender key
+pub struct HpkePrivateKey(PKey<Private>);
+
+impl HpkePrivateKey {
+    pub fn generate() -> Result<Self, Whatever> {
+        Ok(HpkePrivateKey(
+            PKey::generate_x25519().whatever_context("failed to generate X25519 key")?,
+        ))
+    }
+
+    pub fn from_raw(raw: &[u8; 32]) -> Result<Self, Whatever> {
+        Ok(HpkePrivateKey(
+            PKey::private_key_from_raw_bytes(raw, Id::X25519)
+                .whatever_context("failed to parse X25519 private key")?,
+        ))
+    }
+
+    pub fn public_key(&self) -> Result<[u8; N_PK], Whatever> {
+        let raw = self
+            .0
+            .raw_public_key()
+            .whatever_context("failed to get X25519 public key")?;
+        match raw.try_into() {
+            Ok(v) => Ok(v),
+            Err(_) => whatever!("X25519 public key has unexpected length"),
+        }
+    }
+
+    fn dh(&self, public_key: &[u8; N_PK]) -> Result<SecretBytes, Whatever> {
+        let peer = PKey::public_key_from_raw_bytes(public_key, Id::X25519)
+            .whatever_context("failed to parse X25519 public key")?;
+        let mut deriver =
+            Deriver::new(&self.0).whatever_context("failed to create X25519 deriver")?;
+        deriver
+            .set_peer(&peer)
+            .whatever_context("invalid X25519 peer key")?;
+        let mut shared = SecretBytes::new(vec![0u8; N_PK]);
+        let len = deriver
+            .derive(shared.expose_mut())
+            .whatever_context("failed to compute X25519 shared secret")?;
+        //RFC 9180 section 7.1.4: abort on the all-zero output of small order points
+        if len != N_PK || shared.expose() == [0u8; N_PK] {
+            whatever!("X25519 shared secret is invalid");
+        }
+        Ok(shared)
+    }
+}
+
+///`ExtractAndExpand` of DHKEM
+fn kem_shared_secret(dh: &[u8], kem_context: &[u8]) -> SecretBytes {
+    let suite_id = kem_suite_id();
+    let eae_prk = labeled_extract(&suite_id, b"", b"eae_prk", dh);
+    labeled_expand(
+        &suite_id,
+        eae_prk.as_ref(),
+        b"shared_secret",
+        kem_context,
+        N_H,
+    )
+}
+
+///`KeySchedule` of base mode. Returns the AEAD key and the base nonce
+fn key_schedule(shared_secret: &[u8], info: &[u8]) -> Result<(LessSafeKey, Nonce), Whatever> {
+    let suite_id = hpke_suite_id();
+    let psk_id_hash = labeled_extract(&suite_id, b"", b"psk_id_hash", b"");
+    let info_hash = labeled_extract(&suite_id, b"", b"info_hash", info);
+    let mut context = vec![MODE_BASE];
+    context.extend_from_slice(psk_id_hash.as_ref());
+    context.extend_from_slice(info_hash.as_ref());
+    let secret = labeled_extract(&suite_id, shared_secret, b"secret", b"");
+    let key = labeled_expand(&suite_id, secret.as_ref(), b"key", &context, N_K);
+    let base_nonce = labeled_expand(&suite_id, secret.as_ref(), b"base_nonce", &context, N_N);
+    let key = UnboundKey::new(&AES_256_GCM, key.expose())
+        .map_err(|_| Whatever::without_source("failed to create AES-256-GCM key".to_string()))?;
+    let nonce = Nonce::try_assume_unique_for_key(base_nonce.expose()).map_err(|_| {
+        Whatever::without_source("HPKE base nonce has unexpected length".to_string())
+    })?;
+    Ok((LessSafeKey::new(key), nonce))
+}
+
+///Encrypt `plaintext` with `key`. The plaintext is only copied into a `SecretBytes` buffer and
+///encrypted in place, so that no copy of it is left behind, also if encryption fails. Returns
+///the ciphertext including the tag
+pub(crate) fn seal_in_place(
+    key: &LessSafeKey,
+    nonce: Nonce,
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Whatever> {
+    let tag_len = key.algorithm().tag_len();
+    let mut buffer = SecretBytes::new(vec![0u8; plaintext.len() + tag_len]);
+    let (message, tag_out) = buffer.expose_mut().split_at_mut(plaintext.len());
+    message.copy_from_slice(plaintext);
+    let tag = key
+        .seal_in_place_separate_tag(nonce, Aad::from(aad), message)
+        .map_err(|_| Whatever::without_source("failed to encrypt message".to_string()))?;
+    tag_out.copy_from_slice(tag.as_ref());
+    Ok(buffer.expose().to_vec())
+}
+
+///Encrypt `plaintext` for `recipient_public_key`. Returns the encapsulated key and the
+///ciphertext including the tag
+pub fn seal(
+    recipient_public_key: &[u8; N_PK],
+    info: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<([u8; N_PK], Vec<u8>), Whatever> {
+    seal_with_ephemeral(
+        &HpkePrivateKey::generate()?,
+        recipient_public_key,
+        info,
+        aad,
+        plaintext,
+    )
+}
+
+///`seal` with a given ephemeral key, so that tests can reproduce known answers
+fn seal_with_ephemeral(
+    ephemeral: &HpkePrivateKey,
+    recipient_public_key: &[u8; N_PK],
+    info: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<([u8; N_PK], Vec<u8>), Whatever> {
+    let enc = ephemeral.public_key()?;
+    let dh = ephemeral.dh(recipient_public_key)?;
+    let kem_context = [enc, *recipient_public_key].concat();
+    let (key, nonce) = key_schedule(kem_shared_secret(dh.expose(), &kem_context).expose(), info)?;
+    Ok((enc, seal_in_place(&key, nonce, aad, plaintext)?))
+}
+
+///Counterpart of `seal`
+pub fn open(
+    recipient: &HpkePrivateKey,
+    enc: &[u8; N_PK],
+    info: &[u8],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> Result<SecretBytes, Whatever> {
+    let dh = recipient.dh(enc)?;
+    let kem_context = [*enc, recipient.public_key()?].concat();
+    let (key, nonce) = key_schedule(kem_shared_secret(dh.expose(), &kem_context).expose(), info)?;
+    let mut plaintext = SecretBytes::from_slice(ciphertext);
+    let len = key
+        .open_in_place(nonce, Aad::from(aad), plaintext.expose_mut())
+        .map_err(|_| Whatever::without_source("failed to decrypt message".to_string()))?
+        .len();
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open, seal, seal_with_ephemeral, HpkePrivateKey};
+
+    fn key(hex_key: &str) -> [u8; 32] {
+        hex::decode(hex_key).unwrap().try_into().unwrap()
+    }
+
+    ///Generated with the HPKE implementation of `pyca/cryptography`
+    #[test]
+    fn open_interop_vector() {
+        let mut raw_key = [0u8; 32];
+        raw_key
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, v)| *v = i as u8 + 1);
+        let recipient = HpkePrivateKey::from_raw(&raw_key).unwrap();
+        let sealed = hex::decode("8ec23d66281b507ab2fe90adf919b50a3d27191f74e9c30c05d1e3760fa1605f0096586b1f131abd362c65a7183194a29483188698188f7df9ad089312e2").unwrap();
+        let (enc, ciphertext) = sealed.split_at(32);
+        let plaintext = open(
+            &recipient,
+            enc.try_into().unwrap(),
+            b"snpguard test info",
+            b"",
+            ciphertext,
+        )
+        .unwrap();
+        assert_eq!(plaintext.expose(), b"interop secret");
+        assert!(open(
+            &recipient,
+            enc.try_into().unwrap(),
+            b"other info",
+            b"",
+            ciphertext
+        )
+        .is_err());
+    }
+
+    ///Known answer test in the format of the RFC 9180 test vectors, generated with the HPKE
+    ///implementation of OpenSSL 3.5 (`OSSL_HPKE_encap` with a fixed `ikmE`)
+    #[test]
+    fn seal_known_answer() {
+        let ephemeral = HpkePrivateKey::from_raw(&key(
+            "58661a2f70e4407ce264c6ec792b13faf122b0731108afe887d2a7994387d461",
+        ))
+        .unwrap();
+        let recipient = HpkePrivateKey::from_raw(&key(
+            "c8f6c83119182d8b0d21a1de07ad84a9e04908488f8f9592540eb6969ead1545",
+        ))
+        .unwrap();
+        let pk_rm = key("bad762e098446cbcc58b30c7e804f82847b8b911185996582af9f27bfd4e875f");
+        let enc = key("b259f6ee92dcba0111850b13b3f6dccc827726f9b08235ab62922b6b3f3f2a19");
+        let ct = hex::decode("a287fe6957be8aafd011343b4c19b695db08216a9c51b847e2a8e877f74ab738a658eb02af3de1bef821130d486ad9d9344f9e60edb2b055").unwrap();
+        let (info, aad, pt): (&[u8], &[u8], &[u8]) = (
+            b"snpguard hpke test info",
+            b"snpguard hpke test aad",
+            b"interop secret for the known answer test",
+        );
+        assert_eq!(recipient.public_key().unwrap(), pk_rm);
+        assert_eq!(
+            seal_with_ephemeral(&ephemeral, &pk_rm, info, aad, pt).unwrap(),
+            (enc, ct.clone())
+        );
+        assert_eq!(open(&recipient, &enc, info, aad, &ct).unwrap().expose(), pt);
+        assert!(open(&recipient, &enc, info, b"other aad", &ct).is_err());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let recipient = HpkePrivateKey::generate().unwrap();
+        let (enc, ciphertext) =
+            seal(&recipient.public_key().unwrap(), b"info", b"aad", b"secret").unwrap();
+        assert_eq!(
+            open(&recipient, &enc, b"info", b"aad", &ciphertext)
+                .unwrap()
+                .expose(),
+            b"secret"
+        );
+        let other = HpkePrivateKey::generate().unwrap();
+        assert!(open(&other, &enc, b"info", b"aad", &ciphertext).is_err());
+    }
+}
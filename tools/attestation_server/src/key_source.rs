@@ -0,0 +1,355 @@
+//! Sources for the disk key on the machine of the owner.
+//!
+//! Passing the key via the command line exposes it in `ps` and in the shell history. The sources
+//! here read it from a file, stdin, an environment variable, the passphrase protected `Keystore`
+//! or a PKCS#11 token. Passphrases and PINs that are required to unlock a source are read from an
+//! environment variable or, if that is not set, prompted for on the terminal
+use std::{
+    env,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    mem::MaybeUninit,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use snafu::{whatever, ResultExt, Whatever};
+
+use crate::{
+    keystore::Keystore,
+    req_resp_ds::{SecretEncoding, SecretPayload},
+    secret::SecretBytes,
+};
+
+const FILE_PREFIX: &str = "file:";
+const ENV_PREFIX: &str = "env:";
+const KEYSTORE_PREFIX: &str = "keystore:";
+const PKCS11_PREFIX: &str = "pkcs11:";
+const STDIN: &str = "stdin";
+
+///Passphrase of the keystore. Prompted for if not set
+pub const KEYSTORE_PASSPHRASE_ENV: &str = "SNPGUARD_KEYSTORE_PASSPHRASE";
+///User PIN of the PKCS#11 token. Prompted for if not set
+pub const PKCS11_PIN_ENV: &str = "SNPGUARD_PKCS11_PIN";
+///Upper bound on the length of a line that is read from the terminal
+const MAX_PROMPT_LEN: usize = 1024;
+
+///Where the disk key comes from. The string representation is
+/// - `stdin` or `-` : read stdin to the end. The content is used as is, including a trailing newline
+/// - `file:<path>` : the content of the file, used as is and may be binary
+/// - `env:<VAR>` : the value of the environment variable `VAR`, which is removed from the
+///   environment afterwards
+/// - `keystore:<path>#<name>` : the entry `name` of the `Keystore` at `path`. The passphrase is
+///   taken from `SNPGUARD_KEYSTORE_PASSPHRASE` or prompted for
+/// - `pkcs11:<module>#<label>` : the data object with `label` on the first token of the PKCS#11
+///   module, read via `pkcs11-tool` from OpenSC. The user PIN is taken from
+///   `SNPGUARD_PKCS11_PIN` or prompted for
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum KeySource {
+    Stdin,
+    File(PathBuf),
+    Env(String),
+    Keystore { path: PathBuf, name: String },
+    Pkcs11 { module: PathBuf, label: String },
+}
+
+impl KeySource {
+    ///Fetch the key from this source
+    pub fn load(&self) -> Result<SecretPayload, Whatever> {
+        let key = match self {
+            KeySource::Stdin => SecretPayload::binary(
+                SecretBytes::read_from(&mut io::stdin().lock())
+                    .whatever_context("failed to read key from stdin")?,
+            ),
+            KeySource::File(path) => {
+                let mut file = File::open(path)
+                    .whatever_context(format!("failed to open {}", path.display()))?;
+                SecretPayload::binary(
+                    SecretBytes::read_from(&mut file)
+                        .whatever_context(format!("failed to read key from {}", path.display()))?,
+                )
+            }
+            KeySource::Env(var) => {
+                let value = match env::var(var) {
+                    Ok(v) => v,
+                    Err(e) => whatever!(
+                        "failed to read key from environment variable {}: {}",
+                        var,
+                        e
+                    ),
+                };
+                //do not pass the key on to child processes
+                env::remove_var(var);
+                SecretPayload {
+                    data: SecretBytes::new(value.into_bytes()),
+                    encoding: SecretEncoding::Utf8,
+                }
+            }
+            KeySource::Keystore { path, name } => {
+                let keystore = Keystore::load(path)?;
+                let passphrase = read_secret(
+                    KEYSTORE_PASSPHRASE_ENV,
+                    &format!("Passphrase for keystore {}: ", path.display()),
+                )?;
+                keystore.get(name, passphrase.expose())?
+            }
+            KeySource::Pkcs11 { module, label } => read_pkcs11_object(module, label)?,
+        };
+        key.validate()
+            .whatever_context(format!("invalid key from {}", self))?;
+        Ok(key)
+    }
+}
+
+///Read the data object `label` via `pkcs11-tool`. The PIN is handed over via the environment of
+///the child, so that it does not show up in `ps`
+fn read_pkcs11_object(module: &Path, label: &str) -> Result<SecretPayload, Whatever> {
+    let pin = read_secret(
+        PKCS11_PIN_ENV,
+        &format!("User PIN for PKCS#11 module {}: ", module.display()),
+    )?;
+    let pin = match std::str::from_utf8(pin.expose()) {
+        Ok(v) => v,
+        Err(_) => whatever!("PKCS#11 PIN must be valid UTF-8"),
+    };
+    let mut child = Command::new("pkcs11-tool")
+        .arg("--module")
+        .arg(module)
+        .args(["--login", "--pin"])
+        .arg(format!("env:{}", PKCS11_PIN_ENV))
+        .args(["--read-object", "--type", "data", "--label", label])
+        .args(["--output-file", "/dev/stdout"])
+        .env(PKCS11_PIN_ENV, pin)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .whatever_context("failed to run pkcs11-tool")?;
+    let read_result = match child.stdout.take() {
+        Some(mut stdout) => SecretBytes::read_from(&mut stdout),
+        None => whatever!("failed to access stdout of pkcs11-tool"),
+    };
+    let status = child
+        .wait()
+        .whatever_context("failed to wait for pkcs11-tool")?;
+    let data = read_result.whatever_context("failed to read object from pkcs11-tool")?;
+    if !status.success() {
+        whatever!("pkcs11-tool failed to read object {} : {}", label, status);
+    }
+    Ok(SecretPayload::binary(data))
+}
+
+///Secret from the environment variable `var` or, if it is not set, from a prompt on the terminal
+pub fn read_secret(var: &str, prompt: &str) -> Result<SecretBytes, Whatever> {
+    if let Some(value) = env::var_os(var) {
+        return Ok(SecretBytes::new(value.into_encoded_bytes()));
+    }
+    prompt_secret(prompt)
+}
+
+///Ask for a secret on the controlling terminal, without echoing the input. The trailing newline
+///is not part of the secret
+pub fn prompt_secret(prompt: &str) -> Result<SecretBytes, Whatever> {
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .whatever_context("failed to open terminal, set the secret via the environment instead")?;
+    tty.write_all(prompt.as_bytes())
+        .and_then(|_| tty.flush())
+        .whatever_context("failed to write prompt")?;
+
+    let fd = tty.as_raw_fd();
+    let mut termios = MaybeUninit::<libc::termios>::uninit();
+    //SAFETY: fd is a valid terminal and termios is only used after it was initialized
+    if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error())
+            .whatever_context("failed to get terminal attributes");
+    }
+    let original = unsafe { termios.assume_init() };
+    let mut silent = original;
+    silent.c_lflag &= !libc::ECHO;
+    silent.c_lflag |= libc::ECHONL;
+    //SAFETY: see above
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) } != 0 {
+        return Err(io::Error::last_os_error()).whatever_context("failed to disable echo");
+    }
+    let result = read_line(&mut tty);
+    //SAFETY: see above
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+    result
+}
+
+///Read up to the next newline into a pre-allocated buffer, so that no partial copies are left
+fn read_line<R: Read>(reader: &mut R) -> Result<SecretBytes, Whatever> {
+    let mut line = SecretBytes::new(vec![0u8; MAX_PROMPT_LEN]);
+    let mut len = 0;
+    loop {
+        if len == MAX_PROMPT_LEN {
+            whatever!("input is longer than {} bytes", MAX_PROMPT_LEN);
+        }
+        let read = reader
+            .read(&mut line.expose_mut()[len..len + 1])
+            .whatever_context("failed to read from terminal")?;
+        if read == 0 || line.expose()[len] == b'\n' {
+            break;
+        }
+        len += 1;
+    }
+    if len > 0 && line.expose()[len - 1] == b'\r' {
+        len -= 1;
+    }
+    line.truncate(len);
+    Ok(line)
+}
+
+impl FromStr for KeySource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == STDIN || s == "-" {
+            return Ok(KeySource::Stdin);
+        }
+        if let Some(path) = s.strip_prefix(FILE_PREFIX) {
+            if path.is_empty() {
+                return Err("key file path must not be empty".to_string());
+            }
+            return Ok(KeySource::File(path.into()));
+        }
+        if let Some(var) = s.strip_prefix(ENV_PREFIX) {
+            if var.is_empty() || var.contains('=') {
+                return Err(format!("invalid environment variable name {}", var));
+            }
+            return Ok(KeySource::Env(var.to_string()));
+        }
+        if let Some(entry) = s.strip_prefix(KEYSTORE_PREFIX) {
+            return match entry.rsplit_once('#') {
+                Some((path, name)) if !path.is_empty() && !name.is_empty() => {
+                    Ok(KeySource::Keystore {
+                        path: path.into(),
+                        name: name.to_string(),
+                    })
+                }
+                _ => Err(format!(
+                    "expected {}<path>#<name>, got {}",
+                    KEYSTORE_PREFIX, s
+                )),
+            };
+        }
+        if let Some(object) = s.strip_prefix(PKCS11_PREFIX) {
+            return match object.rsplit_once('#') {
+                Some((module, label)) if !module.is_empty() && !label.is_empty() => {
+                    Ok(KeySource::Pkcs11 {
+                        module: module.into(),
+                        label: label.to_string(),
+                    })
+                }
+                _ => Err(format!(
+                    "expected {}<module>#<label>, got {}",
+                    PKCS11_PREFIX, s
+                )),
+            };
+        }
+        Err(format!(
+            "unknown key source {}, expected one of {}, {}<path>, {}<VAR>, {}<path>#<name> and {}<module>#<label>",
+            s, STDIN, FILE_PREFIX, ENV_PREFIX, KEYSTORE_PREFIX, PKCS11_PREFIX
+        ))
+    }
+}
+
+impl TryFrom<String> for KeySource {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<KeySource> for String {
+    fn from(value: KeySource) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeySource::Stdin => write!(f, "{}", STDIN),
+            KeySource::File(path) => write!(f, "{}{}", FILE_PREFIX, path.display()),
+            KeySource::Env(var) => write!(f, "{}{}", ENV_PREFIX, var),
+            KeySource::Keystore { path, name } => {
+                write!(f, "{}{}#{}", KEYSTORE_PREFIX, path.display(), name)
+            }
+            KeySource::Pkcs11 { module, label } => {
+                write!(f, "{}{}#{}", PKCS11_PREFIX, module.display(), label)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_line, KeySource};
+
+    #[test]
+    fn parse_sources() {
+        let cases = [
+            ("-", KeySource::Stdin),
+            (
+                "file:/root/disk.key",
+                KeySource::File("/root/disk.key".into()),
+            ),
+            ("env:DISK_KEY", KeySource::Env("DISK_KEY".to_string())),
+            (
+                "keystore:/home/owner/keys.json#vm1",
+                KeySource::Keystore {
+                    path: "/home/owner/keys.json".into(),
+                    name: "vm1".to_string(),
+                },
+            ),
+            (
+                "pkcs11:/usr/lib/softhsm/libsofthsm2.so#disk key",
+                KeySource::Pkcs11 {
+                    module: "/usr/lib/softhsm/libsofthsm2.so".into(),
+                    label: "disk key".to_string(),
+                },
+            ),
+        ];
+        for (input, want) in cases {
+            let got: KeySource = input.parse().unwrap();
+            assert_eq!(got, want, "input {}", input);
+            assert_eq!(got.to_string().parse::<KeySource>().unwrap(), want);
+        }
+        assert!(
+            "/root/disk.key".parse::<KeySource>().is_err(),
+            "plain paths are ambiguous"
+        );
+        assert!("keystore:/home/owner/keys.json"
+            .parse::<KeySource>()
+            .is_err());
+    }
+
+    #[test]
+    fn env_source() {
+        let var = format!("SNPGUARD_TEST_KEY_{}", std::process::id());
+        std::env::set_var(&var, "hunter2");
+        let key = KeySource::Env(var.clone()).load().unwrap();
+        assert_eq!(key.data.expose(), b"hunter2");
+        assert!(
+            std::env::var_os(&var).is_none(),
+            "key must be removed from the environment"
+        );
+        assert!(KeySource::Env(var).load().is_err());
+    }
+
+    #[test]
+    fn prompt_line() {
+        let line = read_line(&mut &b"secret\r\nrest"[..]).unwrap();
+        assert_eq!(line.expose(), b"secret");
+        assert!(read_line(&mut &[b'a'; 2048][..]).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+//! Passphrase protected file with disk keys on the machine of the owner.
+//!
+//! Each entry is encrypted with AES-256-GCM under a key that is derived from the passphrase via
+//! scrypt, using a fresh salt per entry. The name and encoding of the entry are authenticated as
+//! associated data, so entries cannot be renamed or swapped without noticing. The file itself is
+//! JSON and only readable by its owner
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use hex_buffer_serde::{Hex as _, HexForm};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use snafu::{whatever, FromString, ResultExt, Whatever};
+
+use crate::{
+    req_resp_ds::{SecretEncoding, SecretPayload},
+    secret::SecretBytes,
+};
+
+///Prefix of the associated data, separates it from other uses of the passphrase
+const KEYSTORE_LABEL: &[u8] = b"snpguard keystore v1";
+const SALT_BYTES: usize = 16;
+const KEY_BYTES: usize = 32;
+///Upper bound on the memory that scrypt may use. Rejects absurd parameters in a tampered file
+const SCRYPT_MAX_MEM: u64 = 1 << 30;
+///Permissions of the keystore file
+const KEYSTORE_FILE_MODE: u32 = 0o600;
+
+///Cost parameters of scrypt, see RFC 7914
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScryptParams {
+    ///Binary logarithm of the CPU/memory cost `N`
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for ScryptParams {
+    ///128 MiB of memory, about half a second on a current CPU
+    fn default() -> Self {
+        ScryptParams {
+            log_n: 17,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl ScryptParams {
+    fn derive_key(&self, passphrase: &[u8], salt: &[u8]) -> Result<SecretBytes, Whatever> {
+        if self.log_n >= 64 {
+            whatever!("invalid scrypt parameter log_n {}", self.log_n);
+        }
+        let mut key = SecretBytes::new(vec![0u8; KEY_BYTES]);
+        openssl::pkcs5::scrypt(
+            passphrase,
+            salt,
+            1 << self.log_n,
+            self.r.into(),
+            self.p.into(),
+            SCRYPT_MAX_MEM,
+            key.expose_mut(),
+        )
+        .whatever_context("failed to derive keystore key from passphrase")?;
+        Ok(key)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct KeystoreEntry {
+    name: String,
+    encoding: SecretEncoding,
+    scrypt: ScryptParams,
+    #[serde(with = "HexForm")]
+    salt: [u8; SALT_BYTES],
+    #[serde(with = "HexForm")]
+    nonce: [u8; NONCE_LEN],
+    ///Encrypted secret, followed by the tag
+    #[serde(with = "HexForm")]
+    ciphertext: Vec<u8>,
+}
+
+impl KeystoreEntry {
+    fn aad(name: &str, encoding: SecretEncoding) -> Vec<u8> {
+        let mut out = Vec::new();
+        for part in [
+            KEYSTORE_LABEL,
+            name.as_bytes(),
+            encoding.as_str().as_bytes(),
+        ] {
+            out.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            out.extend_from_slice(part);
+        }
+        out
+    }
+
+    fn cipher(&self, passphrase: &[u8]) -> Result<LessSafeKey, Whatever> {
+        let key = self.scrypt.derive_key(passphrase, &self.salt)?;
+        let key = UnboundKey::new(&AES_256_GCM, key.expose()).map_err(|_| {
+            Whatever::without_source("failed to create keystore cipher".to_string())
+        })?;
+        Ok(LessSafeKey::new(key))
+    }
+}
+
+///Content of a keystore file
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Keystore {
+    entries: Vec<KeystoreEntry>,
+}
+
+impl Keystore {
+    pub fn load(path: &Path) -> Result<Self, Whatever> {
+        let raw = fs::read_to_string(path)
+            .whatever_context(format!("failed to read keystore from {}", path.display()))?;
+        serde_json::from_str(&raw)
+            .whatever_context(format!("failed to parse keystore {}", path.display()))
+    }
+
+    ///Like `load`, but returns an empty keystore if `path` does not exist
+    pub fn load_or_default(path: &Path) -> Result<Self, Whatever> {
+        if !path.exists() {
+            return Ok(Keystore::default());
+        }
+        Keystore::load(path)
+    }
+
+    ///Write the keystore to `path`. Replaces the file atomically, so that a failed write does
+    ///not lose existing entries
+    pub fn save(&self, path: &Path) -> Result<(), Whatever> {
+        let raw =
+            serde_json::to_vec_pretty(self).whatever_context("failed to serialize keystore")?;
+        let tmp_path = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(KEYSTORE_FILE_MODE)
+            .open(&tmp_path)
+            .whatever_context(format!("failed to create {}", tmp_path.display()))?;
+        file.write_all(&raw)
+            .and_then(|_| file.sync_all())
+            .whatever_context(format!("failed to write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, path)
+            .whatever_context(format!("failed to replace keystore {}", path.display()))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|v| v.name.as_str())
+    }
+
+    ///Encrypt `secret` with `passphrase` and store it as `name`. Fails if the name is taken
+    pub fn add(
+        &mut self,
+        name: &str,
+        secret: &SecretPayload,
+        passphrase: &[u8],
+        scrypt: ScryptParams,
+    ) -> Result<(), Whatever> {
+        if self.names().any(|v| v == name) {
+            whatever!("keystore already contains a key named {}", name);
+        }
+        secret.validate()?;
+        let rng = SystemRandom::new();
+        let mut salt = [0u8; SALT_BYTES];
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill(&mut salt)
+            .and_then(|_| rng.fill(&mut nonce))
+            .map_err(|_| Whatever::without_source("failed to sample randomness".to_string()))?;
+        let mut entry = KeystoreEntry {
+            name: name.to_string(),
+            encoding: secret.encoding,
+            scrypt,
+            salt,
+            nonce,
+            ciphertext: Vec::new(),
+        };
+        let mut buffer = secret.data.clone();
+        let tag = entry
+            .cipher(passphrase)?
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(KeystoreEntry::aad(name, secret.encoding)),
+                buffer.expose_mut(),
+            )
+            .map_err(|_| {
+                Whatever::without_source("failed to encrypt keystore entry".to_string())
+            })?;
+        entry.ciphertext = [buffer.expose(), tag.as_ref()].concat();
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    ///Returns false if there is no entry `name`
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|v| v.name != name);
+        self.entries.len() != len
+    }
+
+    ///Decrypt the entry `name` with `passphrase`
+    pub fn get(&self, name: &str, passphrase: &[u8]) -> Result<SecretPayload, Whatever> {
+        let entry = match self.entries.iter().find(|v| v.name == name) {
+            Some(v) => v,
+            None => whatever!("keystore has no key named {}", name),
+        };
+        let mut buffer = SecretBytes::from_slice(&entry.ciphertext);
+        let plaintext_len = entry
+            .cipher(passphrase)?
+            .open_in_place(
+                Nonce::assume_unique_for_key(entry.nonce),
+                Aad::from(KeystoreEntry::aad(&entry.name, entry.encoding)),
+                buffer.expose_mut(),
+            )
+            .map_err(|_| {
+                Whatever::without_source(format!(
+                    "failed to decrypt key {}, wrong passphrase?",
+                    name
+                ))
+            })?
+            .len();
+        buffer.truncate(plaintext_len);
+        Ok(SecretPayload {
+            data: buffer,
+            encoding: entry.encoding,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Keystore, ScryptParams};
+    use crate::req_resp_ds::SecretPayload;
+
+    #[test]
+    fn keystore_roundtrip() {
+        let scrypt = ScryptParams {
+            log_n: 10,
+            r: 8,
+            p: 1,
+        };
+        let mut keystore = Keystore::default();
+        keystore
+            .add(
+                "root",
+                &SecretPayload::utf8("hunter2"),
+                b"passphrase",
+                scrypt,
+            )
+            .unwrap();
+        keystore
+            .add(
+                "data",
+                &SecretPayload::binary(vec![0, 1, 2]),
+                b"other",
+                scrypt,
+            )
+            .unwrap();
+        assert!(keystore
+            .add("root", &SecretPayload::utf8("x"), b"passphrase", scrypt)
+            .is_err());
+
+        let keystore: Keystore =
+            serde_json::from_str(&serde_json::to_string(&keystore).unwrap()).unwrap();
+        assert_eq!(
+            keystore.get("root", b"passphrase").unwrap(),
+            SecretPayload::utf8("hunter2")
+        );
+        assert_eq!(
+            keystore.get("data", b"other").unwrap(),
+            SecretPayload::binary(vec![0, 1, 2])
+        );
+        assert!(keystore.get("root", b"wrong").is_err());
+        assert!(keystore.get("missing", b"passphrase").is_err());
+
+        let mut swapped = keystore;
+        swapped.entries[0].name = "data".to_string();
+        swapped.entries[1].name = "root".to_string();
+        assert!(
+            swapped.get("data", b"passphrase").is_err(),
+            "entries must be bound to their name"
+        );
+    }
+}
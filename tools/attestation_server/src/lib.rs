@@ -1,4 +1,19 @@
+pub mod broker;
 pub mod calc_expected_ld;
+pub mod control_channel;
+pub mod hpke;
+pub mod key_source;
+pub mod keystore;
+pub mod luks;
+pub mod owner_auth;
+pub mod policy_db;
+pub mod ra_tls;
 pub mod req_resp_ds;
+pub mod secret;
+pub mod secret_bundle;
+pub mod secret_sink;
+pub mod session;
 pub mod snp_attestation;
 pub mod snp_validate_report;
+pub mod transport;
+pub mod verdict;
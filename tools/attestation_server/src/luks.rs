@@ -0,0 +1,236 @@
+//! Thin wrapper around `cryptsetup` for the LUKS operations of the server.
+//!
+//! Keys are never written to disk: the first key is passed via stdin, a second key (if required)
+//! via an anonymous in-memory file that only the `cryptsetup` child process inherits
+use std::{
+    ffi::CString,
+    fs::File,
+    io::Write,
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use snafu::{whatever, ResultExt, Whatever};
+
+///`program` that inherits `fd`, although it is close-on-exec in our process
+fn command_inheriting(program: &str, fd: Option<RawFd>) -> Command {
+    let mut command = Command::new(program);
+    if let Some(fd) = fd {
+        //SAFETY: the closure runs between fork and exec and only calls fcntl, which is
+        //async-signal-safe. It only changes the fd table of the child
+        unsafe {
+            command.pre_exec(move || {
+                if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    command
+}
+
+///`cryptsetup <args> <device> <extra_args>` that reads a key from stdin and inherits `fd`
+fn cryptsetup_command(
+    args: &[&str],
+    device: &Path,
+    extra_args: &[String],
+    fd: Option<RawFd>,
+) -> Command {
+    let mut command = command_inheriting("cryptsetup", fd);
+    command
+        .args(args)
+        .arg(device)
+        .args(extra_args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    command
+}
+
+///Run `cryptsetup` with `args`, passing `stdin_key` via stdin. Returns its output
+fn cryptsetup(
+    args: &[&str],
+    device: &Path,
+    extra_args: &[String],
+    stdin_key: &[u8],
+    fd: Option<RawFd>,
+) -> Result<String, Whatever> {
+    let mut child = cryptsetup_command(args, device, extra_args, fd)
+        .spawn()
+        .whatever_context("failed to run cryptsetup")?;
+    //take stdin, so that it is closed once we are done writing
+    let write_result = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(stdin_key),
+        None => whatever!("failed to access stdin of cryptsetup"),
+    };
+    let output = child
+        .wait_with_output()
+        .whatever_context("failed to wait for cryptsetup")?;
+    write_result.whatever_context("failed to pass key to cryptsetup")?;
+    if !output.status.success() {
+        whatever!(
+            "cryptsetup {} failed for {} : {}",
+            args[0],
+            device.display(),
+            output.status
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+///In-memory file with `content`. It is close-on-exec, pass it to a child process via
+///`command_inheriting`, which can then open it via `/proc/self/fd/<fd>`
+fn key_memfd(content: &[u8]) -> Result<File, Whatever> {
+    let name = CString::new("snpguard-key").whatever_context("invalid memfd name")?;
+    //SAFETY: name is a valid nul-terminated string that outlives the call
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).whatever_context("failed to create memfd");
+    }
+    //SAFETY: fd was just created and is not owned by anything else
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(content)
+        .whatever_context("failed to write key to memfd")?;
+    Ok(file)
+}
+
+///Keyslot from the verbose output of `cryptsetup luksAddKey`
+fn created_keyslot(output: &str) -> Option<u32> {
+    output.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("Key slot ")?
+            .strip_suffix(" created.")?
+            .parse()
+            .ok()
+    })
+}
+
+///LUKS volume identified by its block device
+#[derive(Debug, Clone)]
+pub struct LuksDevice(pub PathBuf);
+
+impl LuksDevice {
+    ///Unlock the volume with `key` and map it to `/dev/mapper/<name>`
+    pub fn open(&self, key: &[u8], name: &str) -> Result<(), Whatever> {
+        cryptsetup(
+            &["luksOpen", "--key-file=-"],
+            &self.0,
+            &[name.to_string()],
+            key,
+            None,
+        )?;
+        Ok(())
+    }
+
+    ///Add `new_key` to a free keyslot and return the keyslot. `current_key` must unlock an
+    ///existing keyslot
+    pub fn add_key(&self, current_key: &[u8], new_key: &[u8]) -> Result<u32, Whatever> {
+        let new_key_file = key_memfd(new_key)?;
+        let fd = new_key_file.as_raw_fd();
+        let output = cryptsetup(
+            &["luksAddKey", "--verbose", "--key-file=-"],
+            &self.0,
+            &[format!("/proc/self/fd/{}", fd)],
+            current_key,
+            Some(fd),
+        )?;
+        match created_keyslot(&output) {
+            Some(v) => Ok(v),
+            None => whatever!(
+                "failed to find the new keyslot in the output of cryptsetup: {}",
+                output
+            ),
+        }
+    }
+
+    ///Returns an error if `key` does not unlock any keyslot
+    pub fn test_key(&self, key: &[u8]) -> Result<(), Whatever> {
+        cryptsetup(
+            &["luksOpen", "--test-passphrase", "--key-file=-"],
+            &self.0,
+            &[],
+            key,
+            None,
+        )?;
+        Ok(())
+    }
+
+    ///Wipe the keyslot that is unlocked by `key`
+    pub fn remove_key(&self, key: &[u8]) -> Result<(), Whatever> {
+        cryptsetup(&["luksRemoveKey", "--key-file=-"], &self.0, &[], key, None)?;
+        Ok(())
+    }
+
+    ///Wipe `keyslot`. `key` must unlock another keyslot
+    pub fn kill_keyslot(&self, key: &[u8], keyslot: u32) -> Result<(), Whatever> {
+        cryptsetup(
+            &["luksKillSlot", "--key-file=-"],
+            &self.0,
+            &[keyslot.to_string()],
+            key,
+            None,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::fd::AsRawFd,
+        path::Path,
+        process::{Command, Stdio},
+    };
+
+    use super::{command_inheriting, created_keyslot, cryptsetup_command, key_memfd};
+
+    fn cat_fd(fd: i32, command: &mut Command) -> Option<Vec<u8>> {
+        let output = command
+            .arg(format!("/proc/self/fd/{}", fd))
+            .stderr(Stdio::null())
+            .output()
+            .unwrap();
+        output.status.success().then_some(output.stdout)
+    }
+
+    #[test]
+    fn memfd_handoff() {
+        let file = key_memfd(b"new key").unwrap();
+        let fd = file.as_raw_fd();
+        assert_eq!(
+            cat_fd(fd, &mut command_inheriting("cat", Some(fd))).unwrap(),
+            b"new key"
+        );
+        //other children do not get the key
+        assert_eq!(cat_fd(fd, &mut Command::new("cat")), None);
+    }
+
+    #[test]
+    fn cryptsetup_args() {
+        let command = cryptsetup_command(
+            &["luksAddKey", "--key-file=-"],
+            Path::new("/dev/vda"),
+            &["/proc/self/fd/5".to_string()],
+            Some(5),
+        );
+        assert_eq!(command.get_program(), "cryptsetup");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            ["luksAddKey", "--key-file=-", "/dev/vda", "/proc/self/fd/5"]
+        );
+    }
+
+    #[test]
+    fn parse_keyslot() {
+        assert_eq!(
+            created_keyslot("Key slot 0 unlocked.\nKey slot 3 created.\nCommand successful.\n"),
+            Some(3)
+        );
+        assert_eq!(created_keyslot("Command successful.\n"), None);
+    }
+}
@@ -0,0 +1,432 @@
+//! Authentication of the VM owner towards the guest.
+//!
+//! The attestation report only authenticates the VM to the owner. Without the other direction,
+//! anybody who can reach the server could inject their own disk key and make the VM boot an
+//! attacker controlled volume. Thus the guest can pin the Ed25519 public key of the owner, either
+//! via the measured kernel command line or via the `host_data` field of the attestation report,
+//! and then only accepts secrets that are signed with the corresponding private key.
+//! Only the first protects the guest against a malicious host, see `OwnerKeySource::HostData`.
+//!
+//! The signature covers the digest of the attestation report of the session. As the report binds
+//! the nonce and the DH key of the VM, a signed message cannot be replayed to another session
+use std::{fmt::Display, fs, path::Path, str::FromStr};
+
+use openssl::{
+    pkey::{Id, PKey, Private},
+    sign::{Signer, Verifier},
+};
+use serde::{Deserialize, Serialize};
+use sev::firmware::guest::AttestationReport;
+use snafu::{whatever, ResultExt, Whatever};
+
+use crate::req_resp_ds::{
+    report_digest, RaTlsDiskKey, RotationConfirmation, WrappedChannelKey, WrappedDiskKey,
+    WrappedKeyRotation, WrappedSecretBundle,
+};
+
+///Prefix of the signed transcript, separates it from other uses of the owner key
+const OWNER_SIGNATURE_LABEL: &[u8] = b"snpguard owner signature v1";
+///Value of `OwnerKeySource` that selects the `host_data` field of the report
+const HOST_DATA_SOURCE: &str = "host_data";
+
+///Message that the owner signs before sending it to the guest
+pub trait OwnerSigned {
+    ///Distinguishes the message types in the transcript
+    const MESSAGE_TYPE: &'static [u8];
+
+    ///Content that is covered by the signature, in a fixed order
+    fn signed_fields(&self) -> Vec<&[u8]>;
+
+    fn owner_signature(&self) -> Option<&[u8]>;
+
+    fn set_owner_signature(&mut self, signature: Vec<u8>);
+}
+
+impl OwnerSigned for WrappedDiskKey {
+    const MESSAGE_TYPE: &'static [u8] = b"wrapped_disk_key";
+
+    fn signed_fields(&self) -> Vec<&[u8]> {
+        vec![
+            &self.client_public_key,
+            &self.wrapped_disk_key,
+            self.encoding.as_str().as_bytes(),
+        ]
+    }
+
+    fn owner_signature(&self) -> Option<&[u8]> {
+        self.owner_signature.as_deref()
+    }
+
+    fn set_owner_signature(&mut self, signature: Vec<u8>) {
+        self.owner_signature = Some(signature);
+    }
+}
+
+impl OwnerSigned for WrappedSecretBundle {
+    const MESSAGE_TYPE: &'static [u8] = b"wrapped_secret_bundle";
+
+    fn signed_fields(&self) -> Vec<&[u8]> {
+        vec![&self.client_public_key, &self.wrapped_bundle]
+    }
+
+    fn owner_signature(&self) -> Option<&[u8]> {
+        self.owner_signature.as_deref()
+    }
+
+    fn set_owner_signature(&mut self, signature: Vec<u8>) {
+        self.owner_signature = Some(signature);
+    }
+}
+
+impl OwnerSigned for RaTlsDiskKey {
+    const MESSAGE_TYPE: &'static [u8] = b"ra_tls_disk_key";
+
+    fn signed_fields(&self) -> Vec<&[u8]> {
+        vec![
+            self.disk_key.data.expose(),
+            self.disk_key.encoding.as_str().as_bytes(),
+        ]
+    }
+
+    fn owner_signature(&self) -> Option<&[u8]> {
+        self.owner_signature.as_deref()
+    }
+
+    fn set_owner_signature(&mut self, signature: Vec<u8>) {
+        self.owner_signature = Some(signature);
+    }
+}
+
+impl OwnerSigned for WrappedKeyRotation {
+    const MESSAGE_TYPE: &'static [u8] = b"wrapped_key_rotation";
+
+    fn signed_fields(&self) -> Vec<&[u8]> {
+        vec![&self.client_public_key, &self.wrapped_rotation]
+    }
+
+    fn owner_signature(&self) -> Option<&[u8]> {
+        self.owner_signature.as_deref()
+    }
+
+    fn set_owner_signature(&mut self, signature: Vec<u8>) {
+        self.owner_signature = Some(signature);
+    }
+}
+
+impl OwnerSigned for RotationConfirmation {
+    const MESSAGE_TYPE: &'static [u8] = b"rotation_confirmation";
+
+    fn signed_fields(&self) -> Vec<&[u8]> {
+        vec![self.rotation_id.as_bytes()]
+    }
+
+    fn owner_signature(&self) -> Option<&[u8]> {
+        self.owner_signature.as_deref()
+    }
+
+    fn set_owner_signature(&mut self, signature: Vec<u8>) {
+        self.owner_signature = Some(signature);
+    }
+}
+
+impl OwnerSigned for WrappedChannelKey {
+    const MESSAGE_TYPE: &'static [u8] = b"wrapped_channel_key";
+
+    fn signed_fields(&self) -> Vec<&[u8]> {
+        vec![&self.client_public_key, &self.wrapped_channel_key]
+    }
+
+    fn owner_signature(&self) -> Option<&[u8]> {
+        self.owner_signature.as_deref()
+    }
+
+    fn set_owner_signature(&mut self, signature: Vec<u8>) {
+        self.owner_signature = Some(signature);
+    }
+}
+
+///Data covered by the owner signature: label, message type, report digest and the length
+///prefixed fields of the message
+fn transcript<M: OwnerSigned>(msg: &M, report: &AttestationReport) -> Result<Vec<u8>, Whatever> {
+    let mut out = Vec::new();
+    for part in [
+        OWNER_SIGNATURE_LABEL,
+        M::MESSAGE_TYPE,
+        &report_digest(report)?,
+    ] {
+        out.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        out.extend_from_slice(part);
+    }
+    for field in msg.signed_fields() {
+        out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        out.extend_from_slice(field);
+    }
+    Ok(out)
+}
+
+///Raw Ed25519 public key of the VM owner
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OwnerPublicKey(pub [u8; 32]);
+
+impl FromStr for OwnerPublicKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let raw = hex::decode(s.trim_start_matches("0x"))
+            .map_err(|e| format!("owner public key is not valid hex: {}", e))?;
+        let raw: [u8; 32] = raw.try_into().map_err(|v: Vec<u8>| {
+            format!("owner public key must have 32 bytes, got {}", v.len())
+        })?;
+        Ok(OwnerPublicKey(raw))
+    }
+}
+
+impl Display for OwnerPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl OwnerPublicKey {
+    ///Check that `msg` carries a valid owner signature for the session of `report`
+    pub fn verify<M: OwnerSigned>(
+        &self,
+        msg: &M,
+        report: &AttestationReport,
+    ) -> Result<(), Whatever> {
+        let signature = match msg.owner_signature() {
+            Some(v) => v,
+            None => whatever!("message is not signed by the owner"),
+        };
+        let key = PKey::public_key_from_raw_bytes(&self.0, Id::ED25519)
+            .whatever_context("failed to parse owner public key")?;
+        let mut verifier =
+            Verifier::new_without_digest(&key).whatever_context("failed to create verifier")?;
+        let valid = verifier
+            .verify_oneshot(signature, &transcript(msg, report)?)
+            .whatever_context("failed to verify owner signature")?;
+        if !valid {
+            whatever!("invalid owner signature, expected a signature by {}", self);
+        }
+        Ok(())
+    }
+}
+
+///Ed25519 private key of the VM owner
+pub struct OwnerSigningKey(PKey<Private>);
+
+impl OwnerSigningKey {
+    ///Load a PEM encoded key, as generated by `openssl genpkey -algorithm ed25519`
+    pub fn load(path: &Path) -> Result<Self, Whatever> {
+        let pem = fs::read(path)
+            .whatever_context(format!("failed to read owner key from {}", path.display()))?;
+        let key = PKey::private_key_from_pem(&pem)
+            .whatever_context(format!("failed to parse owner key from {}", path.display()))?;
+        if key.id() != Id::ED25519 {
+            whatever!("owner key in {} is not an Ed25519 key", path.display());
+        }
+        Ok(OwnerSigningKey(key))
+    }
+
+    pub fn generate() -> Result<Self, Whatever> {
+        Ok(OwnerSigningKey(
+            PKey::generate_ed25519().whatever_context("failed to generate Ed25519 key")?,
+        ))
+    }
+
+    pub fn public_key(&self) -> Result<OwnerPublicKey, Whatever> {
+        let raw = self
+            .0
+            .raw_public_key()
+            .whatever_context("failed to get raw public key")?;
+        match raw.try_into() {
+            Ok(v) => Ok(OwnerPublicKey(v)),
+            Err(_) => whatever!("Ed25519 public key has unexpected length"),
+        }
+    }
+
+    ///Sign `msg` for the session of `report`
+    pub fn sign<M: OwnerSigned>(
+        &self,
+        msg: &mut M,
+        report: &AttestationReport,
+    ) -> Result<(), Whatever> {
+        let mut signer =
+            Signer::new_without_digest(&self.0).whatever_context("failed to create signer")?;
+        let signature = signer
+            .sign_oneshot_to_vec(&transcript(msg, report)?)
+            .whatever_context("failed to sign message")?;
+        msg.set_owner_signature(signature);
+        Ok(())
+    }
+}
+
+///Where the guest learns the owner public key from. Parsed from either a hex encoded key,
+///e.g. from the kernel command line, or `host_data`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum OwnerKeySource {
+    Pinned(OwnerPublicKey),
+    ///Use the `host_data` field of the attestation report. The host chooses it at launch and
+    ///cannot change it later, but it is not part of the measurement. Thus the guest cannot tell
+    ///whether it contains the owner key, and a malicious host can launch the VM with its own key
+    ///and inject its own secrets. This only keeps the owner from sending secrets to such a VM if
+    ///the owner checks `host_data` in the report, see `VMDescription::owner_key_in_host_data`
+    HostData,
+}
+
+impl FromStr for OwnerKeySource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == HOST_DATA_SOURCE {
+            return Ok(OwnerKeySource::HostData);
+        }
+        Ok(OwnerKeySource::Pinned(s.parse()?))
+    }
+}
+
+impl TryFrom<String> for OwnerKeySource {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<OwnerKeySource> for String {
+    fn from(value: OwnerKeySource) -> Self {
+        match value {
+            OwnerKeySource::Pinned(key) => key.to_string(),
+            OwnerKeySource::HostData => HOST_DATA_SOURCE.to_string(),
+        }
+    }
+}
+
+impl OwnerKeySource {
+    ///Owner key for the session of `report`
+    pub fn resolve(&self, report: &AttestationReport) -> OwnerPublicKey {
+        match self {
+            OwnerKeySource::Pinned(key) => *key,
+            OwnerKeySource::HostData => OwnerPublicKey(report.host_data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sev::firmware::guest::AttestationReport;
+
+    use super::{OwnerKeySource, OwnerSigningKey};
+    use crate::{
+        req_resp_ds::{SecretEncoding, WrappedDiskKey},
+        snp_validate_report::{ProductName, VerificationPolicy},
+    };
+
+    fn message() -> WrappedDiskKey {
+        WrappedDiskKey {
+            session_id: "a".to_string(),
+            wrapped_disk_key: vec![1, 2, 3],
+            client_public_key: [4; 32],
+            encoding: SecretEncoding::Utf8,
+            owner_signature: None,
+        }
+    }
+
+    #[test]
+    fn owner_signature() {
+        let owner = OwnerSigningKey::generate().unwrap();
+        let mut report = AttestationReport::default();
+        report.host_data = owner.public_key().unwrap().0;
+        let mut msg = message();
+        let pinned = OwnerKeySource::HostData.resolve(&report);
+        assert!(
+            pinned.verify(&msg, &report).is_err(),
+            "unsigned message must be rejected"
+        );
+
+        owner.sign(&mut msg, &report).unwrap();
+        pinned.verify(&msg, &report).unwrap();
+
+        let other = OwnerSigningKey::generate().unwrap().public_key().unwrap();
+        assert!(other.verify(&msg, &report).is_err());
+
+        let mut other_session = report;
+        other_session.report_data = [1; 64];
+        assert!(
+            pinned.verify(&msg, &other_session).is_err(),
+            "signature must be bound to the session"
+        );
+
+        msg.encoding = SecretEncoding::Binary;
+        assert!(pinned.verify(&msg, &report).is_err());
+    }
+
+    #[test]
+    fn reject_forged_signatures() {
+        let owner = OwnerSigningKey::generate().unwrap();
+        let owner_public_key = owner.public_key().unwrap();
+        let report = AttestationReport::default();
+
+        //signed by a key other than the pinned one
+        let mut msg = message();
+        OwnerSigningKey::generate()
+            .unwrap()
+            .sign(&mut msg, &report)
+            .unwrap();
+        assert!(owner_public_key.verify(&msg, &report).is_err(), "wrong key");
+
+        //every signed field is covered
+        let mut signed = message();
+        owner.sign(&mut signed, &report).unwrap();
+        owner_public_key.verify(&signed, &report).unwrap();
+        let tampered: [fn(&mut WrappedDiskKey); 3] = [
+            |msg| msg.wrapped_disk_key[0] ^= 1,
+            |msg| msg.client_public_key[0] ^= 1,
+            |msg| msg.encoding = SecretEncoding::Binary,
+        ];
+        for tamper in tampered {
+            let mut msg = message();
+            msg.owner_signature = signed.owner_signature.clone();
+            tamper(&mut msg);
+            assert!(
+                owner_public_key.verify(&msg, &report).is_err(),
+                "tampered {:?}",
+                msg
+            );
+        }
+
+        //replayed against the report of another session or VM
+        let mut other_nonce = report;
+        other_nonce.report_data[0] ^= 1;
+        let mut other_vm = report;
+        other_vm.measurement[0] ^= 1;
+        for other in [other_nonce, other_vm] {
+            assert!(
+                owner_public_key.verify(&signed, &other).is_err(),
+                "replayed signature"
+            );
+        }
+    }
+
+    #[test]
+    fn host_data_source_needs_owner_check() {
+        //a malicious host launches the VM with its own key as host_data
+        let owner = OwnerSigningKey::generate().unwrap();
+        let host = OwnerSigningKey::generate().unwrap();
+        let mut report = AttestationReport::default();
+        report.host_data = host.public_key().unwrap().0;
+        let mut msg = message();
+        host.sign(&mut msg, &report).unwrap();
+        //the guest cannot tell, only the verifier of the owner can
+        OwnerKeySource::HostData
+            .resolve(&report)
+            .verify(&msg, &report)
+            .unwrap();
+        let policy = VerificationPolicy::default().with_host_data(owner.public_key().unwrap().0);
+        assert!(policy.check(&report, ProductName::Milan).is_err());
+
+        report.host_data = owner.public_key().unwrap().0;
+        policy.check(&report, ProductName::Milan).unwrap();
+    }
+}
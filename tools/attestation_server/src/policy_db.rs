@@ -0,0 +1,391 @@
+//! Secret database of the key broker service (`kbs` binary).
+//!
+//! Each secret is released to all VMs whose attestation report matches the identity of the
+//! entry (launch measurement, family/image id from the id block and/or chip id) and passes the
+//! release policy of the entry. See `examples/kbs-db.toml` for the file format
+use std::{fmt::Debug, fs, path::Path};
+
+use serde::Deserialize;
+use sev::firmware::{
+    guest::{AttestationReport, GuestPolicy, PlatformInfo},
+    host::TcbVersion,
+};
+use snafu::{whatever, ResultExt, Whatever};
+
+use crate::{
+    broker::ReleasePolicy,
+    calc_expected_ld::IDBLOCK_ID_BYTES,
+    req_resp_ds::SecretPayload,
+    secret::SecretBytes,
+    snp_validate_report::{
+        verify_and_check_report, BytesRule, CachingVCEKDownloader, FlagsRule, HexBytes, NumberRule,
+        ProductName, TcbRule, VerificationPolicy,
+    },
+    transport::ListenAddr,
+};
+
+///Selects the VMs that an entry applies to. All specified fields must match. Fields with
+///several values match if the report contains any of them. Only the launch digest and the key
+///digests of the id block pin the VM image, the other fields merely narrow the match
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Identity {
+    ///Accepted launch digests
+    #[serde(default)]
+    pub measurement: Vec<HexBytes<48>>,
+    ///Digests of the keys that may sign the id block
+    #[serde(default)]
+    pub id_key_digest: Vec<HexBytes<48>>,
+    ///Digests of the author keys that may sign the id key
+    #[serde(default)]
+    pub author_key_digest: Vec<HexBytes<48>>,
+    ///Family id from the id block
+    pub family_id: Option<HexBytes<IDBLOCK_ID_BYTES>>,
+    ///Image id from the id block
+    pub image_id: Option<HexBytes<IDBLOCK_ID_BYTES>>,
+    ///Ids of the CPUs that may run the VM
+    #[serde(default)]
+    pub chip_id: Vec<HexBytes<64>>,
+}
+
+///True if `values` is empty or contains `got`
+fn any_or_empty<const N: usize>(values: &[HexBytes<N>], got: &[u8; N]) -> bool {
+    values.is_empty() || values.iter().any(|v| &v.0 == got)
+}
+
+impl Identity {
+    ///Whether the identity determines the VM image. The family and image id are chosen by
+    ///whoever signs the id block, and a chip runs arbitrary images, so they do not count
+    fn pins_image(&self) -> bool {
+        !self.measurement.is_empty()
+            || !self.id_key_digest.is_empty()
+            || !self.author_key_digest.is_empty()
+    }
+
+    ///Check the claims of the report. Does not verify the report signature
+    pub fn matches(&self, report: &AttestationReport) -> bool {
+        any_or_empty(&self.measurement, &report.measurement)
+            && any_or_empty(&self.id_key_digest, &report.id_key_digest)
+            && any_or_empty(&self.author_key_digest, &report.author_key_digest)
+            && self.family_id.is_none_or(|v| v.0 == report.family_id)
+            && self.image_id.is_none_or(|v| v.0 == report.image_id)
+            && any_or_empty(&self.chip_id, &report.chip_id)
+    }
+}
+
+///Requirements that a matching VM has to fulfill to get the secret. Unset fields are not checked,
+///apart from `guest_policy`, which is required
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SecretPolicy {
+    ///Guest policy, see `guest_policy` in the vm config. Stricter policies are accepted as well.
+    ///Required, as it is not part of the launch digest and e.g. decides whether the VM can be
+    ///debugged. Unless it allows a migration agent, the report must not name one
+    pub guest_policy: Option<GuestPolicy>,
+    ///Minimum committed TCB, see `min_commited_tcb` in the vm config
+    pub min_committed_tcb: Option<TcbVersion>,
+    ///Platform info, see `platform_info` in the vm config. Stricter platforms are accepted as well
+    pub platform_info: Option<PlatformInfo>,
+    ///Data that was passed as HOST_DATA to QEMU during VM launch
+    pub host_data: Option<HexBytes<32>>,
+    ///VMPL from which the VM requests its reports, defaults to 0. See `vmpl` in the vm config
+    pub vmpl: Option<u32>,
+}
+
+impl SecretPolicy {
+    ///Rules for `verify_and_check_report`, including `VerificationPolicy::report_defaults`
+    pub fn verification_policy(&self) -> VerificationPolicy {
+        let guest_policy = match self.guest_policy {
+            Some(v) => VerificationPolicy::for_guest_policy(v.0),
+            None => VerificationPolicy::default(),
+        };
+        VerificationPolicy {
+            committed_tcb: self.min_committed_tcb.map(TcbRule::min),
+            platform_info: self
+                .platform_info
+                .map(|v| FlagsRule::at_least_as_strict_as(v.0)),
+            host_data: self.host_data.map(|v| BytesRule::exact(v.0)),
+            vmpl: self.vmpl.map(|v| NumberRule::exact(v.into())),
+            ..Default::default()
+        }
+        .or(guest_policy)
+        .or(VerificationPolicy::report_defaults())
+    }
+}
+
+///A secret together with the VMs that may receive it
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SecretEntry {
+    ///Unique name, used for logging
+    pub name: String,
+    ///Secret value
+    pub value: Option<String>,
+    ///Read the secret value from this file. Relative paths are resolved against the
+    ///directory of the database file. The content may be binary
+    pub value_file: Option<String>,
+    ///Content of `value_file`, filled in by `PolicyDb::load`
+    #[serde(skip)]
+    pub file_content: Option<SecretBytes>,
+    ///CPU generation of the hosts running the VMs. Used to fetch the VCEK
+    pub host_cpu_family: ProductName,
+    pub identity: Identity,
+    #[serde(default)]
+    pub policy: SecretPolicy,
+}
+
+impl SecretEntry {
+    ///The secret value. None if `value_file` was not loaded yet
+    pub fn secret(&self) -> Option<SecretPayload> {
+        match (&self.value, &self.file_content) {
+            (Some(v), _) => Some(SecretPayload::utf8(v)),
+            (None, Some(v)) => Some(SecretPayload::binary(v.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+///Database of the key broker service
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyDb {
+    ///Address to listen on. Same syntax as `listen` in the server config
+    pub listen: Option<ListenAddr>,
+    ///PEM file with the TLS certificate chain of the broker. Enables https
+    pub tls_certificate: Option<String>,
+    ///PEM file with the TLS private key of the broker
+    pub tls_private_key: Option<String>,
+    ///PEM file with the Ed25519 owner key. Released secrets are signed with it, for VMs that
+    ///pin the owner public key
+    pub owner_signing_key: Option<String>,
+    #[serde(rename = "secret", default)]
+    pub secrets: Vec<SecretEntry>,
+}
+
+impl PolicyDb {
+    ///Parse the database at `path`, read all referenced secrets and validate the entries
+    pub fn load(path: &Path) -> Result<Self, Whatever> {
+        let mut db: PolicyDb = toml::from_str(
+            &fs::read_to_string(path)
+                .whatever_context(format!("failed to read database from {}", path.display()))?,
+        )
+        .whatever_context("failed to parse database as toml")?;
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        for entry in db.secrets.iter_mut() {
+            if let Some(value_file) = &entry.value_file {
+                let value_path = base_dir.join(value_file);
+                entry.file_content = Some(SecretBytes::new(
+                    fs::read(&value_path).whatever_context(format!(
+                        "failed to read secret {} from {}",
+                        entry.name,
+                        value_path.display()
+                    ))?,
+                ));
+            }
+        }
+        db.validate()?;
+        Ok(db)
+    }
+
+    ///Check that names are unique and that every entry has a value, an identity and a guest policy
+    pub fn validate(&self) -> Result<(), Whatever> {
+        for (idx, entry) in self.secrets.iter().enumerate() {
+            if self.secrets[..idx].iter().any(|v| v.name == entry.name) {
+                whatever!("duplicate secret name {}", entry.name);
+            }
+            if entry.value.is_some() == entry.value_file.is_some() {
+                whatever!(
+                    "secret {} must specify exactly one of value and value_file",
+                    entry.name
+                );
+            }
+            if let Some(secret) = entry.secret() {
+                secret
+                    .validate()
+                    .whatever_context(format!("invalid secret {}", entry.name))?;
+            }
+            if !entry.identity.pins_image() {
+                whatever!(
+                    "secret {} must restrict at least one of measurement, id_key_digest and author_key_digest",
+                    entry.name
+                );
+            }
+            if entry.policy.guest_policy.is_none() {
+                whatever!("secret {} must specify policy.guest_policy", entry.name);
+            }
+        }
+        Ok(())
+    }
+
+    ///Entry whose identity matches the claims of `report`. It is an error if several entries match,
+    ///as the VM can only receive one secret
+    pub fn lookup(&self, report: &AttestationReport) -> Result<&SecretEntry, Whatever> {
+        let mut candidates = self.secrets.iter().filter(|v| v.identity.matches(report));
+        let entry = match candidates.next() {
+            Some(v) => v,
+            None => whatever!(
+                "no secret for measurement 0x{} on chip 0x{}",
+                hex::encode(report.measurement),
+                hex::encode(report.chip_id)
+            ),
+        };
+        if let Some(other) = candidates.next() {
+            whatever!(
+                "report matches several secrets ({}, {}), refine their identities",
+                entry.name,
+                other.name
+            );
+        }
+        Ok(entry)
+    }
+}
+
+impl ReleasePolicy for PolicyDb {
+    fn release(&self, report: &AttestationReport) -> Result<SecretPayload, Whatever> {
+        let entry = self.lookup(report)?;
+        let vcek_resolver = CachingVCEKDownloader::new()
+            .whatever_context("failed to instantiate vcek downloader")?;
+        let vcek_cert = vcek_resolver
+            .get_vceck_cert(report.chip_id, entry.host_cpu_family, &report.committed_tcb)
+            .whatever_context(format!(
+                "failed to download vcek cert for cpu family {}, chip_id 0x{}",
+                entry.host_cpu_family,
+                hex::encode(report.chip_id)
+            ))?;
+        //the broker already checked the report data, the measurement was checked by `lookup`
+        verify_and_check_report(
+            report,
+            entry.host_cpu_family,
+            vcek_cert,
+            &entry.policy.verification_policy(),
+            None::<fn([u8; 64]) -> _>,
+        )
+        .whatever_context(format!(
+            "report does not satisfy the policy of secret {}",
+            entry.name
+        ))?;
+        println!(
+            "Releasing secret {} to VM on chip 0x{}",
+            entry.name,
+            hex::encode(report.chip_id)
+        );
+        match entry.secret() {
+            Some(v) => Ok(v),
+            None => whatever!("value of secret {} was not loaded", entry.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use sev::firmware::guest::{AttestationReport, GuestPolicy};
+
+    use super::PolicyDb;
+    use crate::snp_validate_report::ProductName;
+
+    const DB: &str = r#"
+        [[secret]]
+        name = "web"
+        value = "a"
+        host_cpu_family = "Milan"
+        identity = { measurement = ["010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101"] }
+        policy = { guest_policy = 0x30000 }
+
+        [[secret]]
+        name = "db"
+        value = "b"
+        host_cpu_family = "Genoa"
+        identity = { id_key_digest = ["050505050505050505050505050505050505050505050505050505050505050505050505050505050505050505050505"], family_id = "02020202020202020202020202020202", image_id = "03030303030303030303030303030303" }
+        policy = { guest_policy = 0x30000 }
+    "#;
+
+    #[test]
+    fn lookup_by_identity() {
+        let db: PolicyDb = toml::from_str(DB).unwrap();
+        db.validate().unwrap();
+        let mut report = AttestationReport::default();
+        assert!(db.lookup(&report).is_err());
+
+        report.measurement = [1; 48];
+        assert_eq!(db.lookup(&report).unwrap().name, "web");
+
+        report.family_id = [2; 16];
+        report.image_id = [3; 16];
+        assert!(
+            db.lookup(&report).is_ok(),
+            "id block signed by another key must not match"
+        );
+        report.id_key_digest = [5; 48];
+        assert!(
+            db.lookup(&report).is_err(),
+            "ambiguous match must be rejected"
+        );
+
+        report.measurement = [0; 48];
+        assert_eq!(db.lookup(&report).unwrap().name, "db");
+        report.image_id = [4; 16];
+        assert!(db.lookup(&report).is_err());
+    }
+
+    #[test]
+    fn reject_unrestricted_secret() {
+        for identity in [
+            "{}",
+            "{ chip_id = [\"06060606060606060606060606060606060606060606060606060606060606060606060606060606060606060606060606060606060606060606060606060606\"] }",
+            "{ image_id = \"03030303030303030303030303030303\" }",
+            "{ family_id = \"02020202020202020202020202020202\", image_id = \"03030303030303030303030303030303\" }",
+        ] {
+            let path = std::env::temp_dir().join(format!("snpguard-kbs-db-{}.toml", std::process::id()));
+            let db = format!(
+                r#"
+                [[secret]]
+                name = "any"
+                value = "a"
+                host_cpu_family = "Milan"
+                identity = {}
+                policy = {{ guest_policy = 0x30000 }}
+                "#,
+                identity
+            );
+            fs::write(&path, db).unwrap();
+            let result = PolicyDb::load(&path);
+            fs::remove_file(&path).unwrap();
+            assert!(result.is_err(), "identity {} must be rejected at load time", identity);
+        }
+    }
+
+    #[test]
+    fn require_guest_policy() {
+        let mut db: PolicyDb = toml::from_str(DB).unwrap();
+        db.secrets[0].policy.guest_policy = None;
+        assert!(db.validate().is_err());
+
+        //debugging and a migration agent are not part of the launch digest
+        let policy = toml::from_str::<PolicyDb>(DB).unwrap().secrets[0]
+            .policy
+            .verification_policy();
+        let mut report = AttestationReport::default();
+        report.version = 2;
+        report.policy = GuestPolicy(0x30000);
+        report.report_id_ma = [0xff; 32];
+        let failures = |report: &AttestationReport| -> Vec<&str> {
+            policy
+                .evaluate(report, ProductName::Milan)
+                .into_iter()
+                .filter(|v| v.failure.is_some())
+                .map(|v| v.check)
+                .collect()
+        };
+        assert!(!failures(&report).contains(&"guest policy"));
+        report.policy = GuestPolicy(0x30000 | 1 << 19);
+        assert!(
+            failures(&report).contains(&"guest policy"),
+            "debug_allowed must be rejected"
+        );
+        report.policy = GuestPolicy(0x30000);
+        report.report_id_ma = [0; 32];
+        assert!(failures(&report).contains(&"migration agent report id"));
+    }
+}
@@ -0,0 +1,341 @@
+//! RA-TLS support: binds a self-signed TLS certificate to an SEV-SNP attestation report.
+//!
+//! The VM generates a fresh key pair at startup and requests an attestation report whose
+//! `report_data` binds the DER encoded public key (SubjectPublicKeyInfo) as `ReportClaims`.
+//! The report is embedded as a non-critical extension into a self-signed certificate for that key.
+//! Clients verify the report during the handshake, see `RaTlsVerifier`, and can thus trust the
+//! TLS connection.
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+    thread,
+    time::SystemTime,
+};
+
+use openssl::{
+    asn1::{Asn1Integer, Asn1Object, Asn1OctetString, Asn1Time},
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::PKey,
+    x509::{X509Extension, X509NameBuilder, X509},
+};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, CertificateError, ClientConfig, RootCertStore, ServerName,
+};
+use sev::firmware::guest::AttestationReport;
+use snafu::{whatever, ResultExt, Whatever};
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use crate::snp_attestation::{QuerySNPAttestation, ReportClaims};
+
+///Object identifier of the certificate extension that carries the bincode serialized attestation report.
+///Uses the UUID based arc from ITU-T X.667, so no registration is required
+pub const SNP_REPORT_EXTENSION_OID: &str = "2.25.28751073187998234314329237039658780837";
+
+///Validity period of the generated certificate. The certificate is only valid as long as the
+///VM is running, the date range is merely required by the X.509 format
+const CERT_VALIDITY_DAYS: u32 = 365;
+
+///Claims that bind the public key of the certificate to the attestation report
+pub fn claims_for_public_key(public_key_der: &[u8]) -> ReportClaims {
+    ReportClaims::new().with_tls_public_key(public_key_der)
+}
+
+///Key material and certificate used by the server in RA-TLS mode
+pub struct RaTlsIdentity {
+    ///PEM encoded, self-signed certificate that contains the attestation report
+    pub certificate_pem: Vec<u8>,
+    ///PEM encoded private key of the certificate
+    pub private_key_pem: Vec<u8>,
+    ///Attestation report that was embedded into the certificate
+    pub report: AttestationReport,
+}
+
+impl RaTlsIdentity {
+    ///Generate a fresh P-384 key pair, fetch an attestation report bound to its public key
+    ///and wrap everything into a self-signed certificate
+    pub fn generate<Q: QuerySNPAttestation>() -> Result<Self, Whatever> {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1)
+            .whatever_context("failed to load P-384 curve")?;
+        let ec_key = EcKey::generate(&group).whatever_context("failed to generate P-384 key")?;
+        let key = PKey::from_ec_key(ec_key).whatever_context("failed to wrap P-384 key")?;
+        let public_key_der = key
+            .public_key_to_der()
+            .whatever_context("failed to encode public key as DER")?;
+
+        let report = Q::get_report(&claims_for_public_key(&public_key_der))
+            .whatever_context("failed to get attestation report for the TLS key")?;
+        let raw_report =
+            bincode::serialize(&report).whatever_context("failed to bincode serialize report")?;
+
+        let mut name = X509NameBuilder::new().whatever_context("failed to create x509 name")?;
+        name.append_entry_by_nid(Nid::COMMONNAME, "snpguard-ra-tls")
+            .whatever_context("failed to set common name")?;
+        let name = name.build();
+
+        let mut builder = X509::builder().whatever_context("failed to create x509 builder")?;
+        builder
+            .set_version(2)
+            .whatever_context("failed to set certificate version")?;
+        let serial = BigNum::from_u32(1)
+            .and_then(|v| Asn1Integer::from_bn(&v))
+            .whatever_context("failed to create serial number")?;
+        builder
+            .set_serial_number(&serial)
+            .whatever_context("failed to set serial number")?;
+        builder
+            .set_subject_name(&name)
+            .whatever_context("failed to set subject name")?;
+        builder
+            .set_issuer_name(&name)
+            .whatever_context("failed to set issuer name")?;
+        builder
+            .set_pubkey(&key)
+            .whatever_context("failed to set public key")?;
+        let not_before = Asn1Time::days_from_now(0).whatever_context("failed to get time")?;
+        let not_after =
+            Asn1Time::days_from_now(CERT_VALIDITY_DAYS).whatever_context("failed to get time")?;
+        builder
+            .set_not_before(&not_before)
+            .whatever_context("failed to set not before")?;
+        builder
+            .set_not_after(&not_after)
+            .whatever_context("failed to set not after")?;
+
+        let oid = Asn1Object::from_str(SNP_REPORT_EXTENSION_OID)
+            .whatever_context("failed to parse report extension oid")?;
+        let content = Asn1OctetString::new_from_bytes(&raw_report)
+            .whatever_context("failed to wrap report as octet string")?;
+        let extension = X509Extension::new_from_der(&oid, false, &content)
+            .whatever_context("failed to create report extension")?;
+        builder
+            .append_extension(extension)
+            .whatever_context("failed to add report extension")?;
+
+        builder
+            .sign(&key, MessageDigest::sha384())
+            .whatever_context("failed to sign certificate")?;
+        let cert = builder.build();
+
+        Ok(RaTlsIdentity {
+            certificate_pem: cert
+                .to_pem()
+                .whatever_context("failed to encode certificate as PEM")?,
+            private_key_pem: key
+                .private_key_to_pem_pkcs8()
+                .whatever_context("failed to encode private key as PEM")?,
+            report,
+        })
+    }
+}
+
+///Content of an RA-TLS certificate that is relevant for verification
+pub struct RaTlsCertificate {
+    ///Attestation report embedded in the certificate
+    pub report: AttestationReport,
+    ///Claims that the report data must bind, computed from the public key of the certificate
+    pub claims: ReportClaims,
+}
+
+impl RaTlsCertificate {
+    ///Parse a DER encoded RA-TLS certificate.
+    /// *DOES NOT* verify the report. Use e.g. `verify_and_check_report` and
+    /// check the report data with `claims`
+    pub fn from_der(cert_der: &[u8]) -> Result<Self, Whatever> {
+        let (_, cert) =
+            X509Certificate::from_der(cert_der).whatever_context("failed to parse certificate")?;
+        //x509-parser cannot display arcs larger than u64, thus we compare the encoded oids
+        let oid = Asn1Object::from_str(SNP_REPORT_EXTENSION_OID)
+            .whatever_context("failed to parse report extension oid")?;
+        let extension = match cert
+            .extensions()
+            .iter()
+            .find(|v| v.oid.as_bytes() == oid.as_slice())
+        {
+            Some(v) => v,
+            None => whatever!("certificate does not contain an attestation report extension"),
+        };
+        let report: AttestationReport = bincode::deserialize(extension.value)
+            .whatever_context("failed to deserialize attestation report from certificate")?;
+        Ok(RaTlsCertificate {
+            report,
+            claims: claims_for_public_key(cert.public_key().raw),
+        })
+    }
+}
+
+///Decides whether the RA-TLS certificate of the server is acceptable, usually by verifying the
+///embedded report and checking its report data with `RaTlsCertificate::claims`
+pub type RaTlsCheck = dyn Fn(&RaTlsCertificate) -> Result<(), String> + Send + Sync;
+
+///Verifies the RA-TLS certificate of the server during the TLS handshake, so that no data is
+///exchanged with a server whose report was not accepted.
+///
+///The report is created once when the server starts and thus cannot contain a nonce of the
+///client. It proves that a VM matching the report generated the TLS key, and as the private key
+///never leaves the VM, a replayed certificate cannot complete a handshake. However, the report
+///is as old as the server process, e.g. it may predate a TCB update of the host. Use the default
+///mode if the report has to be fresh
+pub struct RaTlsVerifier {
+    check: Box<RaTlsCheck>,
+    ///Report of the last certificate that the server presented, accepted or not
+    last_report: Mutex<Option<AttestationReport>>,
+    ///Last accepted certificate. The client may open several connections, which only need to be
+    ///checked once
+    accepted: Mutex<Option<Certificate>>,
+    ///Reason why the last certificate was rejected
+    failure: Mutex<Option<String>>,
+}
+
+impl RaTlsVerifier {
+    pub fn new(
+        check: impl Fn(&RaTlsCertificate) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        Arc::new(RaTlsVerifier {
+            check: Box::new(check),
+            last_report: Mutex::new(None),
+            accepted: Mutex::new(None),
+            failure: Mutex::new(None),
+        })
+    }
+
+    ///Report of the last certificate that the server presented, accepted or not
+    pub fn last_report(&self) -> Option<AttestationReport> {
+        *self.last_report.lock().expect("poisoned lock")
+    }
+
+    ///Reason why the last certificate was rejected
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().expect("poisoned lock").clone()
+    }
+
+    fn verify(&self, end_entity: &Certificate) -> Result<(), String> {
+        if self.accepted.lock().expect("poisoned lock").as_ref() == Some(end_entity) {
+            return Ok(());
+        }
+        let cert = RaTlsCertificate::from_der(&end_entity.0).map_err(|e| format!("{:#}", e))?;
+        *self.last_report.lock().expect("poisoned lock") = Some(cert.report);
+        //The handshake runs on the async runtime of the http client, which does not allow
+        //blocking calls like the VCEK download. Thus run the check on a thread of its own
+        thread::scope(|scope| scope.spawn(|| (self.check)(&cert)).join())
+            .unwrap_or_else(|_| Err("report check panicked".to_string()))?;
+        *self.accepted.lock().expect("poisoned lock") = Some(end_entity.clone());
+        Ok(())
+    }
+}
+
+impl ServerCertVerifier for RaTlsVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.verify(end_entity) {
+            Ok(()) => Ok(ServerCertVerified::assertion()),
+            Err(e) => {
+                *self.failure.lock().expect("poisoned lock") = Some(e.clone());
+                Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                    Arc::new(RaTlsRejected(e)),
+                )))
+            }
+        }
+    }
+}
+
+///Error for rustls when the check of an RA-TLS certificate failed
+#[derive(Debug)]
+struct RaTlsRejected(String);
+
+impl Display for RaTlsRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RA-TLS certificate rejected: {}", self.0)
+    }
+}
+
+impl std::error::Error for RaTlsRejected {}
+
+///TLS config that only accepts servers whose RA-TLS certificate passes `verifier`.
+///Use with `reqwest::ClientBuilder::use_preconfigured_tls`
+pub fn ra_tls_client_config(verifier: Arc<RaTlsVerifier>) -> ClientConfig {
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+    config.dangerous().set_certificate_verifier(verifier);
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use openssl::x509::X509;
+    use rustls::{client::ServerCertVerifier, Certificate, ServerName};
+
+    use super::{RaTlsCertificate, RaTlsIdentity, RaTlsVerifier};
+    use crate::snp_attestation::MockSNPAttestation;
+
+    fn mock_certificate() -> Certificate {
+        let identity = RaTlsIdentity::generate::<MockSNPAttestation>().unwrap();
+        Certificate(
+            X509::from_pem(&identity.certificate_pem)
+                .unwrap()
+                .to_der()
+                .unwrap(),
+        )
+    }
+
+    fn verify(verifier: &RaTlsVerifier, cert: &Certificate) -> Result<(), rustls::Error> {
+        verifier
+            .verify_server_cert(
+                cert,
+                &[],
+                &ServerName::try_from("localhost").unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .map(|_| ())
+    }
+
+    #[test]
+    fn certificate_roundtrip() {
+        let identity = RaTlsIdentity::generate::<MockSNPAttestation>().unwrap();
+        let cert_der = X509::from_pem(&identity.certificate_pem)
+            .unwrap()
+            .to_der()
+            .unwrap();
+        let parsed = RaTlsCertificate::from_der(&cert_der).unwrap();
+        assert_eq!(parsed.report.report_data, identity.report.report_data);
+        parsed.claims.check(&parsed.report.report_data).unwrap();
+    }
+
+    #[test]
+    fn verifier_checks_report_in_handshake() {
+        let cert = mock_certificate();
+        let accepting = RaTlsVerifier::new(|cert| {
+            cert.claims
+                .check(&cert.report.report_data)
+                .map_err(|e| e.to_string())
+        });
+        verify(&accepting, &cert).unwrap();
+        assert!(accepting.last_report().is_some());
+        assert!(accepting.failure().is_none());
+
+        let rejecting = RaTlsVerifier::new(|_| Err("measurement mismatch".to_string()));
+        assert!(verify(&rejecting, &cert).is_err());
+        assert!(rejecting.last_report().is_some());
+        assert_eq!(rejecting.failure().unwrap(), "measurement mismatch");
+
+        //anything that is not an RA-TLS certificate is rejected before the check runs
+        let plain = Certificate(vec![0x30, 0x00]);
+        assert!(verify(&accepting, &plain).is_err());
+    }
+}
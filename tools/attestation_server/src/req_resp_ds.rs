@@ -1,103 +1,1025 @@
-use ring::aead::Aad;
-use ring::aead::BoundKey;
-use ring::aead::Nonce;
-use ring::aead::NonceSequence;
-use ring::aead::OpeningKey;
-use ring::aead::SealingKey;
-use ring::aead::UnboundKey;
-use ring::aead::AES_256_GCM;
-use ring::aead::NONCE_LEN;
-use ring::error::Unspecified;
-use ring::hkdf::{Prk, Salt, HKDF_SHA512};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::agreement::{self, EphemeralPrivateKey};
+use ring::digest;
+use ring::hkdf::{self, Salt, HKDF_SHA512};
+use ring::rand::SystemRandom;
 use serde::{Deserialize, Serialize};
-use snafu::FromString;
+use sev::firmware::guest::AttestationReport;
 use snafu::ResultExt;
 use snafu::Whatever;
+use snafu::{whatever, FromString};
+use std::{fmt::Display, str};
+
+use crate::hpke::{self, HpkePrivateKey};
+use crate::secret::SecretBytes;
+use crate::secret_bundle::SecretBundle;
+use crate::snp_attestation::{LegacyReportData, ReportClaims};
+use crate::snp_validate_report::ReportVerificationError;
+
+///Protocol version spoken by this build
+pub const PROTOCOL_VERSION: u32 = 4;
+///All protocol versions that this build understands, ordered ascending.
+///Version 1 puts nonce and public key in plain into the report data, see `ReportBinding`.
+///Version 2 introduced the transcript-bound key derivation, see `wrap_secret`.
+///Version 3 binds hashed `ReportClaims` to the report and sends the public key next to it.
+///Version 4 wraps secrets with HPKE, see `WrappingScheme`
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u32] = &[1, 2, 3, 4];
+///Lowest protocol version that peers negotiate unless older versions are explicitly allowed.
+///Nothing authenticates the info message, so a man in the middle could otherwise strip the newer
+///versions from it and force a version without transcript binding or hashed report claims
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+///HTTP path under which the server accepts protocol messages
+pub const PROTOCOL_PATH: &str = "/api";
+
+///Versioned wrapper around every protocol message. The `Info` request and all error responses
+///are understood independent of the version, so that peers can negotiate a common version
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Envelope<T> {
+    pub version: u32,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(version: u32, message: T) -> Self {
+        Envelope { version, message }
+    }
+}
+
+///Messages sent from the client to the server
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", content = "body", rename_all = "snake_case")]
+pub enum RequestMessage {
+    ///Query supported protocol versions and capabilities of the server
+    Info,
+    ///Request an attestation report. Opens a new session on the server
+    Report(AttestationRequest),
+    ///Inject a secret that is wrapped with the key material of the session
+    InjectSecret(WrappedDiskKey),
+    ///Inject a secret without additional wrapping. Only accepted through an RA-TLS channel
+    InjectSecretRaTls(RaTlsDiskKey),
+    ///Inject several named secrets that are wrapped with the key material of the session
+    InjectSecretBundle(WrappedSecretBundle),
+    ///Inject several named secrets without additional wrapping. Only accepted through an
+    ///RA-TLS channel
+    InjectSecretBundleRaTls(SecretBundle),
+    ///Pull mode, sent by the VM to a key broker: request a fresh nonce. Opens a new session on the broker
+    PullChallenge,
+    ///Pull mode, sent by the VM to a key broker: request the secret for the attested VM
+    PullSecret(Box<PullRequest>),
+    ///Add a new LUKS key to the running VM, wrapped with the key material of the session
+    RotateKey(WrappedKeyRotation),
+    ///Remove the old LUKS key after `RotateKey` succeeded
+    ConfirmRotation(RotationConfirmation),
+    ///Open a control channel, keyed with a channel key that is wrapped with the key material
+    ///of the session
+    OpenControlChannel(WrappedChannelKey),
+    ///Encrypted `ControlRequest` on an open control channel, see `control_channel`
+    Control(SealedControlMessage),
+}
+
+///Messages sent from the server to the client
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", content = "body", rename_all = "snake_case")]
+pub enum ResponseMessage {
+    Info(ServerInfo),
+    Report(Box<AttestationResponse>),
+    SecretAccepted,
+    Challenge(PullChallenge),
+    ///Secret for the VM, wrapped for the public key from the report data of the `PullRequest`.
+    ///`client_public_key` contains the public DH key of the broker
+    WrappedSecret(WrappedDiskKey),
+    ///The new key was added and unlocks the volume. The old key is still valid until the
+    ///rotation is confirmed
+    RotationPending(RotationPending),
+    ///The old key was removed
+    RotationComplete,
+    ControlChannelOpened(ControlChannelOpened),
+    ///Encrypted `ControlResponse`
+    Control(SealedControlMessage),
+    Error(ProtocolError),
+}
+
+///Optional features of the server
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    ///Server accepts secrets via `RequestMessage::InjectSecret`
+    SecretInjection,
+    ///Server is reachable via RA-TLS and accepts `RequestMessage::InjectSecretRaTls`
+    RaTls,
+    ///Server accepts `RequestMessage::InjectSecretBundle` and, if `RaTls` is offered as well,
+    ///`RequestMessage::InjectSecretBundleRaTls`
+    SecretBundle,
+    ///Key broker that serves `RequestMessage::PullChallenge` and `RequestMessage::PullSecret`
+    KeyBroker,
+    ///Server rotates the LUKS key of the running VM via `RequestMessage::RotateKey`
+    KeyRotation,
+    ///Server keeps running after secret injection and accepts owner commands via
+    ///`RequestMessage::OpenControlChannel`
+    ControlChannel,
+}
+
+///Answer to `RequestMessage::Info`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ServerInfo {
+    pub supported_versions: Vec<u32>,
+    pub capabilities: Vec<Capability>,
+}
+
+impl ServerInfo {
+    ///Highest protocol version supported by both this build and the server that is at least `min_version`
+    pub fn negotiate_version(&self, min_version: u32) -> Option<u32> {
+        enabled_versions(min_version)
+            .into_iter()
+            .rev()
+            .find(|v| self.supported_versions.contains(v))
+    }
+}
+
+///Supported protocol versions that are at least `min_version`, see `MIN_PROTOCOL_VERSION`
+pub fn enabled_versions(min_version: u32) -> Vec<u32> {
+    SUPPORTED_PROTOCOL_VERSIONS
+        .iter()
+        .copied()
+        .filter(|v| *v >= min_version)
+        .collect()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtocolErrorKind {
+    ///The message uses a protocol version that the server does not support
+    UnsupportedVersion,
+    ///The message was sent to an unknown endpoint
+    UnknownEndpoint,
+    ///The message could not be parsed
+    MalformedRequest,
+    ///The message is not valid in the server's current configuration
+    UnexpectedMessage,
+    ///The session id is unknown or the session expired. Request a new report
+    UnknownSession,
+    ///The server cannot open more sessions right now
+    TooManySessions,
+    ///The server failed to get an attestation report from the secure processor
+    ReportUnavailable,
+    ///The injected secret could not be unwrapped or stored
+    InvalidSecret,
+    ///The key broker did not accept the attestation report
+    ReportRejected,
+    Internal,
+}
+
+impl ProtocolErrorKind {
+    ///HTTP status code used when sending the error over HTTP
+    pub fn http_status(&self) -> u16 {
+        match self {
+            ProtocolErrorKind::UnsupportedVersion
+            | ProtocolErrorKind::MalformedRequest
+            | ProtocolErrorKind::UnexpectedMessage
+            | ProtocolErrorKind::InvalidSecret => 400,
+            ProtocolErrorKind::ReportRejected => 403,
+            ProtocolErrorKind::UnknownEndpoint | ProtocolErrorKind::UnknownSession => 404,
+            ProtocolErrorKind::TooManySessions => 503,
+            ProtocolErrorKind::ReportUnavailable | ProtocolErrorKind::Internal => 500,
+        }
+    }
+}
+
+///Error response with a machine readable kind and a human readable message
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ProtocolError {
+    pub kind: ProtocolErrorKind,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(kind: ProtocolErrorKind, message: impl Into<String>) -> Self {
+        ProtocolError {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct AttestationRequest {
     pub nonce: u64,
 }
 
+///Answer to an `AttestationRequest`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct AttestationResponse {
+    ///Identifies the key material on the server side. Needs to be passed along with the `WrappedDiskKey`
+    pub session_id: String,
+    ///Report that binds the nonce from the request and the public DH key of the VM, see `ReportBinding`
+    pub report: AttestationReport,
+    ///Public DH key of the VM. Only trust it after checking it against the report data.
+    ///Not sent before version 3, where the key is part of the report data
+    #[serde(default)]
+    pub public_key: [u8; 32],
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct WrappedDiskKey {
-    //was encrypted with aead_enc, need to decrypt with aead_dec
+    ///Session id from the `AttestationResponse`
+    pub session_id: String,
+    ///Encrypted with `wrap_secret`, decrypt with `unwrap_secret`
     pub wrapped_disk_key: Vec<u8>,
     pub client_public_key: [u8; 32],
+    ///Encoding of the unwrapped disk key
+    #[serde(default)]
+    pub encoding: SecretEncoding,
+    ///Signature of the VM owner, see `owner_auth`. Required if the VM pins an owner key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner_signature: Option<Vec<u8>>,
+}
+
+///Answer to `RequestMessage::PullChallenge`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PullChallenge {
+    ///Needs to be passed along with the `PullRequest`
+    pub session_id: String,
+    ///Must be included in the report data of the `PullRequest`
+    pub nonce: u64,
+}
+
+///Pull mode request for the secret of the VM
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PullRequest {
+    ///Session id from the `PullChallenge`
+    pub session_id: String,
+    ///Report that binds the nonce from the challenge and the public DH key of the VM, see `ReportBinding`
+    pub report: AttestationReport,
+    ///Public DH key of the VM, the secret is wrapped for it. Not sent before version 3, where the
+    ///key is part of the report data
+    #[serde(default)]
+    pub public_key: [u8; 32],
+}
+
+///Bundle of secrets, wrapped like `WrappedDiskKey`. The plaintext is the JSON serialized
+///`SecretBundle`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct WrappedSecretBundle {
+    ///Session id from the `AttestationResponse`
+    pub session_id: String,
+    ///Encrypted with `wrap_secret`, decrypt with `unwrap_secret`
+    pub wrapped_bundle: Vec<u8>,
+    pub client_public_key: [u8; 32],
+    ///Signature of the VM owner, see `owner_auth`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner_signature: Option<Vec<u8>>,
+}
+
+///Old and new LUKS key for a key rotation. Sent JSON serialized in a `WrappedKeyRotation`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct KeyRotation {
+    ///Key that currently unlocks the volume. Its keyslot is removed once the rotation is confirmed
+    pub current_key: SecretPayload,
+    pub new_key: SecretPayload,
+}
+
+impl KeyRotation {
+    pub fn validate(&self) -> Result<(), Whatever> {
+        self.current_key
+            .validate()
+            .whatever_context("invalid current key")?;
+        self.new_key
+            .validate()
+            .whatever_context("invalid new key")?;
+        if self.current_key.data == self.new_key.data {
+            whatever!("the new key must differ from the current key");
+        }
+        Ok(())
+    }
+}
+
+///`KeyRotation`, wrapped like `WrappedDiskKey`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct WrappedKeyRotation {
+    ///Session id from the `AttestationResponse`
+    pub session_id: String,
+    ///Encrypted with `WrappingScheme::wrap`
+    pub wrapped_rotation: Vec<u8>,
+    pub client_public_key: [u8; 32],
+    ///Signature of the VM owner, see `owner_auth`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner_signature: Option<Vec<u8>>,
+}
+
+///Answer to `RequestMessage::RotateKey`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RotationPending {
+    ///Needs to be passed along with the `RotationConfirmation`
+    pub rotation_id: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RotationConfirmation {
+    ///Id from `RotationPending`
+    pub rotation_id: String,
+    ///Signature of the VM owner over the report of the rotation session, see `owner_auth`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner_signature: Option<Vec<u8>>,
+}
+
+///Channel key for a control channel, wrapped like `WrappedDiskKey`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct WrappedChannelKey {
+    ///Session id from the `AttestationResponse`
+    pub session_id: String,
+    ///Encrypted with `WrappingScheme::wrap`
+    pub wrapped_channel_key: Vec<u8>,
+    pub client_public_key: [u8; 32],
+    ///Signature of the VM owner, see `owner_auth`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner_signature: Option<Vec<u8>>,
+}
+
+///Answer to `RequestMessage::OpenControlChannel`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ControlChannelOpened {
+    ///Needs to be passed along with every `SealedControlMessage`
+    pub channel_id: String,
+}
+
+///Message on a control channel, encrypted with the key of the sending direction
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SealedControlMessage {
+    pub channel_id: String,
+    ///Number of messages that were sent before in the same direction. Used as nonce
+    pub sequence: u64,
+    pub ciphertext: Vec<u8>,
+}
+
+///Disk key sent in RA-TLS mode. Confidentiality and integrity are provided
+///by the attested TLS channel
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RaTlsDiskKey {
+    pub disk_key: SecretPayload,
+    ///Signature of the VM owner over the RA-TLS report, see `owner_auth`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner_signature: Option<Vec<u8>>,
 }
 
-struct CounterNonceSequence(u64);
+///Upper bound on the length of a single secret in bytes. Large enough for any LUKS key file
+///that is used in practice
+pub const MAX_SECRET_LEN: usize = 8192;
 
-impl NonceSequence for CounterNonceSequence {
-    // called once for each seal operation
-    fn advance(&mut self) -> Result<Nonce, Unspecified> {
-        let mut nonce_bytes = vec![0; NONCE_LEN];
+///Encoding of a secret
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretEncoding {
+    ///Passphrase or other text. Must be valid UTF-8
+    #[default]
+    Utf8,
+    ///Arbitrary bytes, e.g. a random LUKS key file
+    Binary,
+}
 
-        let bytes = self.0.to_be_bytes();
-        //TODO:this seems whack. investigate
-        nonce_bytes[..8].copy_from_slice(&bytes);
-        println!("nonce_bytes = {}", hex::encode(&nonce_bytes));
+impl SecretEncoding {
+    ///Name as used in the serialized form
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecretEncoding::Utf8 => "utf8",
+            SecretEncoding::Binary => "binary",
+        }
+    }
 
-        self.0 += 1; // advance the counter
-        Nonce::try_assume_unique_for_key(&nonce_bytes)
+    ///Check that `secret` is not empty, not longer than `MAX_SECRET_LEN` and valid for this encoding
+    pub fn validate(&self, secret: &[u8]) -> Result<(), Whatever> {
+        if secret.is_empty() {
+            whatever!("secret is empty");
+        }
+        if secret.len() > MAX_SECRET_LEN {
+            whatever!(
+                "secret has {} bytes, the limit is {}",
+                secret.len(),
+                MAX_SECRET_LEN
+            );
+        }
+        if *self == SecretEncoding::Utf8 && str::from_utf8(secret).is_err() {
+            whatever!("secret is declared as utf8 but is not valid utf8");
+        }
+        Ok(())
     }
 }
 
-fn derive_keys(shared_secret: &[u8], nonce_from_report: u64) -> Result<Vec<u8>, Whatever> {
-    let salt = Salt::new(HKDF_SHA512, &nonce_from_report.to_le_bytes());
-    let pseudo_rand_key: Prk = salt.extract(shared_secret);
-    let context_data = &["aes_key".as_bytes()];
-    let mut aes_key = vec![0u8; AES_256_GCM.key_len()];
-    pseudo_rand_key
-        .expand(context_data, &AES_256_GCM).map_err(|_| Whatever::without_source("failed to expand key material for AES_256_GCM using HKDF".to_string()))
-        ?
-        .fill(&mut aes_key).map_err(|_| Whatever::without_source("failed to store expanded AES key".to_string()))
-        ?;
+///Secret together with its encoding
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SecretPayload {
+    pub data: SecretBytes,
+    #[serde(default)]
+    pub encoding: SecretEncoding,
+}
 
-    Ok(aes_key)
+impl SecretPayload {
+    pub fn utf8(value: &str) -> Self {
+        SecretPayload {
+            data: SecretBytes::from_slice(value.as_bytes()),
+            encoding: SecretEncoding::Utf8,
+        }
+    }
+
+    pub fn binary(data: impl Into<SecretBytes>) -> Self {
+        SecretPayload {
+            data: data.into(),
+            encoding: SecretEncoding::Binary,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Whatever> {
+        self.encoding.validate(self.data.expose())
+    }
 }
 
-///outputs ciphertext + tag
-pub fn aead_enc(shared_secret: &[u8], nonce_from_report: u64, plaintext: &[u8]) -> Result<Vec<u8>,Whatever> {
-    let aes_key = derive_keys(shared_secret, nonce_from_report).whatever_context("failed to derive AES key from shared secret")?;
+///Label that binds the derived wrapping keys to this protocol and KDF version. Needs to change
+///whenever the key derivation in `derive_wrapping_key` changes
+const WRAP_KDF_LABEL: &[u8] = b"snpguard secret wrapping v2";
 
-    let unbound_key = UnboundKey::new(&AES_256_GCM, &aes_key).map_err(|_| Whatever::without_source("failed to parse AES key into internal data structure".to_string()))?;
+///Output length for HKDF-Expand
+struct OkmLen(usize);
 
-    let nonce_sequence = CounterNonceSequence(nonce_from_report);
+impl hkdf::KeyType for OkmLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
 
-    let mut sealing_key = SealingKey::new(unbound_key, nonce_sequence);
-    let associated_data = Aad::empty();
+///SHA-512 digest of the canonical (bincode) encoding of `report`
+pub fn report_digest(report: &AttestationReport) -> Result<[u8; 64], Whatever> {
+    let raw =
+        bincode::serialize(report).whatever_context("failed to serialize attestation report")?;
+    digest::digest(&digest::SHA512, &raw)
+        .as_ref()
+        .try_into()
+        .whatever_context("sha512 digest has unexpected length")
+}
 
-    let mut ciphertext = Vec::from(plaintext);
-    // Encrypt the data with AEAD using the AES_256_GCM algorithm
-    let tag = sealing_key
-        .seal_in_place_separate_tag(associated_data, &mut ciphertext)
-        .map_err(|_| Whatever::without_source("failed to encrpyt data".to_string()))?;
-    let cypher_text_with_tag = [&ciphertext, tag.as_ref()].concat();
-    Ok(cypher_text_with_tag)
+///Derive the AES-256-GCM key and nonce for wrapping a secret for the VM that produced `report`.
+///Besides the DH shared secret, the derivation binds the protocol label, the public DH key of the
+///VM, the public DH key of the wrapping peer and the digest of the whole report. Thus a wrapped
+///secret can only be unwrapped in the attested session that it was created for.
+///Every key pair is only used for a single secret, so deriving the nonce alongside the key is safe
+fn derive_wrapping_key(
+    shared_secret: &[u8],
+    vm_public_key: &[u8; 32],
+    wrapper_public_key: &[u8; 32],
+    report: &AttestationReport,
+) -> Result<(LessSafeKey, Nonce), Whatever> {
+    let report_digest = report_digest(report)?;
+    let prk = Salt::new(HKDF_SHA512, WRAP_KDF_LABEL).extract(shared_secret);
+    let info: [&[u8]; 4] = [
+        WRAP_KDF_LABEL,
+        vm_public_key,
+        wrapper_public_key,
+        &report_digest,
+    ];
+    let key_len = AES_256_GCM.key_len();
+    let mut okm = SecretBytes::new(vec![0u8; key_len + NONCE_LEN]);
+    prk.expand(&info, OkmLen(okm.len()))
+        .map_err(|_| {
+            Whatever::without_source("failed to expand key material using HKDF".to_string())
+        })?
+        .fill(okm.expose_mut())
+        .map_err(|_| {
+            Whatever::without_source("failed to store expanded key material".to_string())
+        })?;
+    let key = UnboundKey::new(&AES_256_GCM, &okm.expose()[..key_len]).map_err(|_| {
+        Whatever::without_source("failed to parse AES key into internal data structure".to_string())
+    })?;
+    let nonce = Nonce::try_assume_unique_for_key(&okm.expose()[key_len..])
+        .map_err(|_| Whatever::without_source("derived nonce has unexpected length".to_string()))?;
+    Ok((LessSafeKey::new(key), nonce))
 }
 
-///outputs plaintext
-pub fn aead_dec(
+///Generate an ephemeral DH key pair, derive a wrapping key with `vm_public_key` and encrypt
+///`plaintext` with it. `kdf` derives the key of the scheme from the DH shared secret, the public
+///key of the VM and our public key. The caller must have checked that the report data binds
+///`vm_public_key`, see `ReportClaims::check`. Returns our public key, which the peer needs
+///for unwrapping, and the ciphertext including the tag
+fn wrap_secret(
+    vm_public_key: &[u8; 32],
+    plaintext: &[u8],
+    kdf: impl FnOnce(&[u8], &[u8; 32], &[u8; 32]) -> Result<(LessSafeKey, Nonce), Whatever>,
+) -> Result<([u8; 32], Vec<u8>), Whatever> {
+    let unparsed_vm_key = agreement::UnparsedPublicKey::new(&agreement::X25519, vm_public_key);
+    let private_key =
+        agreement::EphemeralPrivateKey::generate(&agreement::X25519, &SystemRandom::new())
+            .map_err(|_| {
+                Whatever::without_source("failed to generate private DH key".to_string())
+            })?;
+    let public_key: [u8; 32] = private_key
+        .compute_public_key()
+        .map_err(|_| {
+            Whatever::without_source("failed to derive public dh key from private key".to_string())
+        })?
+        .as_ref()
+        .try_into()
+        .whatever_context("generated public dh key has unexpected length, expected 32 bytes")?;
+    let shared_secret =
+        agreement::agree_ephemeral(private_key, &unparsed_vm_key, SecretBytes::from_slice)
+            .map_err(|_| {
+                Whatever::without_source("failed to compute shared secret from DH keys".to_string())
+            })
+            .whatever_context("failed to derive shared secret")?;
+    let (key, nonce) = kdf(shared_secret.expose(), vm_public_key, &public_key)?;
+    let wrapped = hpke::seal_in_place(&key, nonce, b"", plaintext)
+        .whatever_context("failed to encrypt secret")?;
+    Ok((public_key, wrapped))
+}
+
+///Counterpart of `wrap_secret`. `private_key` is the key whose public part was included in the
+///report data, `peer_public_key` the public key returned by `wrap_secret`. `kdf` is called like
+///in `wrap_secret`, with our public key as the one of the VM
+fn unwrap_secret(
+    private_key: EphemeralPrivateKey,
+    peer_public_key: [u8; 32],
+    wrapped: Vec<u8>,
+    kdf: impl FnOnce(&[u8], &[u8; 32], &[u8; 32]) -> Result<(LessSafeKey, Nonce), Whatever>,
+) -> Result<SecretBytes, Whatever> {
+    let own_public_key: [u8; 32] = private_key
+        .compute_public_key()
+        .map_err(|_| {
+            Whatever::without_source("failed to derive public dh key from private key".to_string())
+        })?
+        .as_ref()
+        .try_into()
+        .whatever_context("public dh key has unexpected length, expected 32 bytes")?;
+    let unparsed_peer_key = agreement::UnparsedPublicKey::new(&agreement::X25519, peer_public_key);
+    let shared_secret =
+        agreement::agree_ephemeral(private_key, &unparsed_peer_key, SecretBytes::from_slice)
+            .map_err(|_| {
+                Whatever::without_source("failed to compute shared secret from DH keys".to_string())
+            })
+            .whatever_context("failed to derive shared secret")?;
+    let (key, nonce) = kdf(shared_secret.expose(), &own_public_key, &peer_public_key)?;
+    //decrypt in place, so that the plaintext only ever exists inside the secret buffer
+    let mut plaintext = SecretBytes::new(wrapped);
+    let len = key
+        .open_in_place(nonce, Aad::empty(), plaintext.expose_mut())
+        .map_err(|_| Whatever::without_source("failed to decrypt secret".to_string()))?
+        .len();
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+///Derive the AES-256-GCM key and nonce of protocol version 1 from the DH shared secret and the
+///nonce from the report data. Unlike `derive_wrapping_key`, nothing else of the session is bound
+fn derive_legacy_wrapping_key(
     shared_secret: &[u8],
-    nonce_from_report: u64,
-    cipher_text_with_tag: Vec<u8>,
-) -> Result<Vec<u8>,Whatever> {
-    let aes_key = derive_keys(shared_secret, nonce_from_report).whatever_context("failed to derive AES key from shared secret")?;
+    report_nonce: u64,
+) -> Result<(LessSafeKey, Nonce), Whatever> {
+    let prk = Salt::new(HKDF_SHA512, &report_nonce.to_le_bytes()).extract(shared_secret);
+    let mut aes_key = SecretBytes::new(vec![0u8; AES_256_GCM.key_len()]);
+    prk.expand(&[b"aes_key"], &AES_256_GCM)
+        .map_err(|_| {
+            Whatever::without_source(
+                "failed to expand key material for AES_256_GCM using HKDF".to_string(),
+            )
+        })?
+        .fill(aes_key.expose_mut())
+        .map_err(|_| Whatever::without_source("failed to store expanded AES key".to_string()))?;
+    let key = UnboundKey::new(&AES_256_GCM, aes_key.expose()).map_err(|_| {
+        Whatever::without_source("failed to parse AES key into internal data structure".to_string())
+    })?;
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..8].copy_from_slice(&report_nonce.to_be_bytes());
+    Ok((LessSafeKey::new(key), Nonce::assume_unique_for_key(nonce)))
+}
+
+///How the report data binds the nonce and the public key of the VM. Determined by the negotiated
+///protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportBinding {
+    ///Nonce and public key in plain, see `LegacyReportData`. Versions 1 and 2
+    Legacy,
+    ///Digest of `ReportClaims`, the public key is sent next to the report. Since version 3
+    Claims,
+}
+
+impl ReportBinding {
+    ///Binding of the protocol `version`. Fails for versions below `min_version`, so that a peer
+    ///cannot silently fall back to the legacy layout, see `MIN_PROTOCOL_VERSION`
+    pub fn for_version(version: u32, min_version: u32) -> Result<Self, Whatever> {
+        if version < min_version {
+            whatever!(
+                "protocol version {} is below the minimum version {}",
+                version,
+                min_version
+            );
+        }
+        Ok(if version >= 3 {
+            ReportBinding::Claims
+        } else {
+            ReportBinding::Legacy
+        })
+    }
 
-    let unbound_key = UnboundKey::new(&AES_256_GCM, &aes_key).map_err(|_| Whatever::without_source("failed to parse AES key into internal data structure".to_string()))?;
+    ///Value for the `report_data` field of a report that binds `nonce` and `public_key`
+    pub fn report_data(&self, nonce: u64, public_key: [u8; 32]) -> [u8; 64] {
+        match self {
+            ReportBinding::Legacy => LegacyReportData::new(nonce, public_key).into(),
+            ReportBinding::Claims => ReportClaims::new()
+                .with_nonce(nonce)
+                .with_dh_public_key(public_key)
+                .to_report_data(),
+        }
+    }
 
-    let nonce_sequence = CounterNonceSequence(nonce_from_report);
+    ///Public key of the VM. `sent_public_key` is the key that the peer sent next to the report,
+    ///which peers before version 3 do not send. Only trust the key after `check` succeeded
+    pub fn public_key(&self, report_data: &[u8; 64], sent_public_key: [u8; 32]) -> [u8; 32] {
+        match self {
+            ReportBinding::Legacy => LegacyReportData::from(*report_data).public_key,
+            ReportBinding::Claims => sent_public_key,
+        }
+    }
 
-    // Create a new AEAD key for decrypting and verifying the authentication tag
-    let mut opening_key = OpeningKey::new(unbound_key, nonce_sequence);
+    ///Check that `report_data` binds exactly `nonce` and `public_key`
+    pub fn check(
+        &self,
+        report_data: &[u8; 64],
+        nonce: u64,
+        public_key: [u8; 32],
+    ) -> Result<(), ReportVerificationError> {
+        match self {
+            ReportBinding::Legacy => LegacyReportData::new(nonce, public_key).check(report_data),
+            ReportBinding::Claims => ReportClaims::new()
+                .with_nonce(nonce)
+                .with_dh_public_key(public_key)
+                .check(report_data),
+        }
+    }
+}
 
-    // Decrypt the data by passing in the associated data and the cypher text with the authentication tag appended
-    let mut cypher_text_with_tag = cipher_text_with_tag.clone();
-    let associated_data = Aad::empty();
-    let decrypted_data = opening_key
-        .open_in_place(associated_data, &mut cypher_text_with_tag)
-        .map_err(|_| Whatever::without_source("failed to derypt data".to_string()))?;
+///Info parameter of the HPKE key schedule. Binds the wrapped secret to the attested session
+const HPKE_INFO_LABEL: &[u8] = b"snpguard hpke secret wrapping v1";
 
-    Ok(Vec::from(decrypted_data))
+fn hpke_info(report: &AttestationReport) -> Result<Vec<u8>, Whatever> {
+    Ok([HPKE_INFO_LABEL, &report_digest(report)?].concat())
+}
+
+///How secrets are wrapped for the VM. Determined by the negotiated protocol version, so that
+///peers that only speak an older version keep working
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrappingScheme {
+    ///X25519 DH with a key derived from the nonce in the report data only, see
+    ///`derive_legacy_wrapping_key`. Version 1
+    NonceKdf,
+    ///X25519 DH with our own, transcript-bound key derivation, see `wrap_secret`
+    TranscriptKdf,
+    ///RFC 9180 HPKE in base mode with DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-256-GCM.
+    ///The report data commits to the recipient key, the HPKE info to the whole report
+    Hpke,
+}
+
+impl WrappingScheme {
+    pub fn for_version(version: u32) -> Self {
+        match version {
+            ..=1 => WrappingScheme::NonceKdf,
+            2 | 3 => WrappingScheme::TranscriptKdf,
+            _ => WrappingScheme::Hpke,
+        }
+    }
+
+    ///Wrap `plaintext` for `vm_public_key`. The caller must have checked that the report data of
+    ///`report` binds `vm_public_key`. Returns the public key (HPKE: encapsulated key) that the VM
+    ///needs for unwrapping and the ciphertext
+    pub fn wrap(
+        &self,
+        report: &AttestationReport,
+        vm_public_key: &[u8; 32],
+        plaintext: &[u8],
+    ) -> Result<([u8; 32], Vec<u8>), Whatever> {
+        match self {
+            WrappingScheme::NonceKdf => {
+                wrap_secret(vm_public_key, plaintext, |shared_secret, _, _| {
+                    derive_legacy_wrapping_key(
+                        shared_secret,
+                        LegacyReportData::from(report.report_data).nonce,
+                    )
+                })
+            }
+            WrappingScheme::TranscriptKdf => wrap_secret(
+                vm_public_key,
+                plaintext,
+                |shared_secret, vm_key, own_key| {
+                    derive_wrapping_key(shared_secret, vm_key, own_key, report)
+                },
+            ),
+            WrappingScheme::Hpke => hpke::seal(vm_public_key, &hpke_info(report)?, b"", plaintext),
+        }
+    }
+}
+
+///Key pair of the VM for receiving a single wrapped secret
+pub enum UnwrappingKey {
+    NonceKdf(EphemeralPrivateKey),
+    TranscriptKdf(EphemeralPrivateKey),
+    Hpke(HpkePrivateKey),
+}
+
+impl UnwrappingKey {
+    pub fn generate(scheme: WrappingScheme) -> Result<Self, Whatever> {
+        let generate_dh = || {
+            EphemeralPrivateKey::generate(&agreement::X25519, &SystemRandom::new()).map_err(|_| {
+                Whatever::without_source("failed to generate private DH key".to_string())
+            })
+        };
+        match scheme {
+            WrappingScheme::NonceKdf => Ok(UnwrappingKey::NonceKdf(generate_dh()?)),
+            WrappingScheme::TranscriptKdf => Ok(UnwrappingKey::TranscriptKdf(generate_dh()?)),
+            WrappingScheme::Hpke => Ok(UnwrappingKey::Hpke(HpkePrivateKey::generate()?)),
+        }
+    }
+
+    ///Public key that has to be bound to the report
+    pub fn public_key(&self) -> Result<[u8; 32], Whatever> {
+        match self {
+            UnwrappingKey::NonceKdf(private_key) | UnwrappingKey::TranscriptKdf(private_key) => {
+                private_key
+                    .compute_public_key()
+                    .map_err(|_| {
+                        Whatever::without_source(
+                            "failed to derive public dh key from private key".to_string(),
+                        )
+                    })?
+                    .as_ref()
+                    .try_into()
+                    .whatever_context("public dh key has unexpected length, expected 32 bytes")
+            }
+            UnwrappingKey::Hpke(private_key) => private_key.public_key(),
+        }
+    }
+
+    ///Counterpart of `WrappingScheme::wrap`
+    pub fn unwrap(
+        self,
+        report: &AttestationReport,
+        peer_public_key: [u8; 32],
+        wrapped: Vec<u8>,
+    ) -> Result<SecretBytes, Whatever> {
+        match self {
+            UnwrappingKey::NonceKdf(private_key) => unwrap_secret(
+                private_key,
+                peer_public_key,
+                wrapped,
+                |shared_secret, _, _| {
+                    derive_legacy_wrapping_key(
+                        shared_secret,
+                        LegacyReportData::from(report.report_data).nonce,
+                    )
+                },
+            ),
+            UnwrappingKey::TranscriptKdf(private_key) => unwrap_secret(
+                private_key,
+                peer_public_key,
+                wrapped,
+                |shared_secret, own_key, peer_key| {
+                    derive_wrapping_key(shared_secret, own_key, peer_key, report)
+                },
+            ),
+            UnwrappingKey::Hpke(private_key) => hpke::open(
+                &private_key,
+                &peer_public_key,
+                &hpke_info(report)?,
+                b"",
+                &wrapped,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::{
+        agreement::{self, EphemeralPrivateKey},
+        rand::SystemRandom,
+    };
+
+    use ring::aead::Aad;
+
+    use super::{
+        derive_legacy_wrapping_key, derive_wrapping_key, unwrap_secret, wrap_secret,
+        AttestationRequest, Envelope, ReportBinding, RequestMessage, SecretEncoding, ServerInfo,
+        UnwrappingKey, WrappedDiskKey, WrappingScheme, MAX_SECRET_LEN, MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION,
+    };
+    use sev::firmware::guest::AttestationReport;
+
+    use crate::snp_attestation::ReportClaims;
+
+    #[test]
+    fn envelope_roundtrip() {
+        let raw = serde_json::to_string(&Envelope::new(
+            PROTOCOL_VERSION,
+            RequestMessage::Report(AttestationRequest { nonce: 42 }),
+        ))
+        .unwrap();
+        let parsed: Envelope<RequestMessage> = serde_json::from_str(&raw).unwrap();
+        assert_eq!(parsed.version, PROTOCOL_VERSION);
+        assert!(matches!(
+            parsed.message,
+            RequestMessage::Report(AttestationRequest { nonce: 42 })
+        ));
+
+        let info: Envelope<RequestMessage> =
+            serde_json::from_str(r#"{"version":7,"type":"info"}"#).unwrap();
+        assert!(matches!(info.message, RequestMessage::Info));
+    }
+
+    #[test]
+    fn wrap_unwrap_roundtrip() {
+        let rng = SystemRandom::new();
+        let private_key = EphemeralPrivateKey::generate(&agreement::X25519, &rng).unwrap();
+        let public_key: [u8; 32] = private_key
+            .compute_public_key()
+            .unwrap()
+            .as_ref()
+            .try_into()
+            .unwrap();
+        let mut report = AttestationReport::default();
+        report.report_data = ReportClaims::new()
+            .with_nonce(7)
+            .with_dh_public_key(public_key)
+            .to_report_data();
+        let kdf = |shared_secret: &[u8], vm_key: &[u8; 32], peer_key: &[u8; 32]| {
+            derive_wrapping_key(shared_secret, vm_key, peer_key, &report)
+        };
+        let (peer_public_key, wrapped) = wrap_secret(&public_key, b"secret", kdf).unwrap();
+        let unwrapped = unwrap_secret(private_key, peer_public_key, wrapped, kdf).unwrap();
+        assert_eq!(unwrapped.expose(), b"secret");
+    }
+
+    #[test]
+    fn negotiated_wrapping_schemes() {
+        assert_eq!(WrappingScheme::for_version(1), WrappingScheme::NonceKdf);
+        assert_eq!(
+            WrappingScheme::for_version(2),
+            WrappingScheme::TranscriptKdf
+        );
+        assert_eq!(
+            ReportBinding::for_version(2, 1).unwrap(),
+            ReportBinding::Legacy
+        );
+        assert_eq!(
+            ReportBinding::for_version(3, 1).unwrap(),
+            ReportBinding::Claims
+        );
+        assert!(ReportBinding::for_version(2, MIN_PROTOCOL_VERSION).is_err());
+        assert!(ReportBinding::for_version(3, MIN_PROTOCOL_VERSION).is_err());
+        assert_eq!(
+            WrappingScheme::for_version(3),
+            WrappingScheme::TranscriptKdf
+        );
+        assert_eq!(
+            WrappingScheme::for_version(PROTOCOL_VERSION),
+            WrappingScheme::Hpke
+        );
+        for scheme in [
+            WrappingScheme::NonceKdf,
+            WrappingScheme::TranscriptKdf,
+            WrappingScheme::Hpke,
+        ] {
+            let public_key = UnwrappingKey::generate(scheme)
+                .unwrap()
+                .public_key()
+                .unwrap();
+            let mut report = AttestationReport::default();
+            report.report_data = ReportClaims::new()
+                .with_dh_public_key(public_key)
+                .to_report_data();
+            let (peer_public_key, wrapped) = scheme.wrap(&report, &public_key, b"secret").unwrap();
+            //a fresh key of the same scheme must not be able to unwrap
+            let other_key = UnwrappingKey::generate(scheme).unwrap();
+            assert!(
+                other_key.unwrap(&report, peer_public_key, wrapped).is_err(),
+                "{:?}",
+                scheme
+            );
+        }
+
+        let key = UnwrappingKey::generate(WrappingScheme::Hpke).unwrap();
+        let public_key = key.public_key().unwrap();
+        let report = AttestationReport::default();
+        let (enc, wrapped) = WrappingScheme::Hpke
+            .wrap(&report, &public_key, b"secret")
+            .unwrap();
+        let mut other_report = report;
+        other_report.guest_svn = 1;
+        assert!(UnwrappingKey::generate(WrappingScheme::Hpke)
+            .unwrap()
+            .unwrap(&other_report, enc, wrapped.clone())
+            .is_err());
+        assert_eq!(
+            key.unwrap(&report, enc, wrapped).unwrap().expose(),
+            b"secret"
+        );
+    }
+
+    #[test]
+    fn version_1_binding() {
+        let binding = ReportBinding::for_version(1, 1).unwrap();
+        assert_eq!(binding, ReportBinding::Legacy);
+        let key = UnwrappingKey::generate(WrappingScheme::for_version(1)).unwrap();
+        let public_key = key.public_key().unwrap();
+        let mut report = AttestationReport::default();
+        report.report_data = binding.report_data(7, public_key);
+        //version 1 peers do not send the public key next to the report
+        assert_eq!(binding.public_key(&report.report_data, [0; 32]), public_key);
+        binding.check(&report.report_data, 7, public_key).unwrap();
+        assert!(binding.check(&report.report_data, 8, public_key).is_err());
+        assert!(
+            ReportBinding::for_version(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION)
+                .unwrap()
+                .check(&report.report_data, 7, public_key)
+                .is_err()
+        );
+
+        let (peer_public_key, wrapped) = WrappingScheme::NonceKdf
+            .wrap(&report, &public_key, b"secret")
+            .unwrap();
+        assert_eq!(
+            key.unwrap(&report, peer_public_key, wrapped)
+                .unwrap()
+                .expose(),
+            b"secret"
+        );
+
+        let seal = |nonce: u64| {
+            let (key, aead_nonce) = derive_legacy_wrapping_key(&[1; 32], nonce).unwrap();
+            let mut data = b"secret".to_vec();
+            key.seal_in_place_append_tag(aead_nonce, Aad::empty(), &mut data)
+                .unwrap();
+            data
+        };
+        assert_ne!(seal(7), seal(8), "the key must depend on the nonce");
+    }
+
+    #[test]
+    fn wrapping_key_is_bound_to_transcript() {
+        let seal = |vm_key: [u8; 32], report: &AttestationReport| {
+            let (key, nonce) = derive_wrapping_key(&[1; 32], &vm_key, &[3; 32], report).unwrap();
+            let mut data = b"secret".to_vec();
+            key.seal_in_place_append_tag(nonce, Aad::empty(), &mut data)
+                .unwrap();
+            data
+        };
+        let report = AttestationReport::default();
+        let mut other_report = AttestationReport::default();
+        other_report.guest_svn = 1;
+        assert_eq!(seal([2; 32], &report), seal([2; 32], &report));
+        assert_ne!(seal([2; 32], &report), seal([2; 32], &other_report));
+        assert_ne!(seal([2; 32], &report), seal([4; 32], &report));
+    }
+
+    #[test]
+    fn secret_encoding() {
+        assert!(SecretEncoding::Utf8.validate(b"hunter2").is_ok());
+        assert!(SecretEncoding::Utf8.validate(&[0xff, 0x00]).is_err());
+        assert!(SecretEncoding::Binary.validate(&[0xff, 0x00]).is_ok());
+        assert!(SecretEncoding::Binary.validate(&[]).is_err());
+        assert!(SecretEncoding::Binary
+            .validate(&[0; MAX_SECRET_LEN + 1])
+            .is_err());
+
+        //messages from clients that predate the encoding field are treated as utf8
+        let wrapped: WrappedDiskKey = serde_json::from_str(
+            r#"{"session_id":"a","wrapped_disk_key":[1],"client_public_key":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]}"#,
+        )
+        .unwrap();
+        assert_eq!(wrapped.encoding, SecretEncoding::Utf8);
+    }
+
+    #[test]
+    fn version_negotiation() {
+        let info = ServerInfo {
+            supported_versions: vec![PROTOCOL_VERSION, PROTOCOL_VERSION + 1],
+            capabilities: vec![],
+        };
+        assert_eq!(
+            info.negotiate_version(MIN_PROTOCOL_VERSION),
+            Some(PROTOCOL_VERSION)
+        );
+        let info = ServerInfo {
+            supported_versions: vec![PROTOCOL_VERSION + 1],
+            capabilities: vec![],
+        };
+        assert_eq!(info.negotiate_version(MIN_PROTOCOL_VERSION), None);
+
+        //a man in the middle that only offers old versions must not get them by default
+        let info = ServerInfo {
+            supported_versions: vec![1, 2],
+            capabilities: vec![],
+        };
+        assert_eq!(info.negotiate_version(MIN_PROTOCOL_VERSION), None);
+        assert_eq!(info.negotiate_version(1), Some(2));
+    }
 }
@@ -0,0 +1,235 @@
+//! Container for plaintext secrets and key material.
+//!
+//! `SecretBytes` zeroizes its buffer when dropped and never prints its content. If enabled via
+//! `set_mlock`, the buffer is additionally locked into memory, so that it is not written to swap.
+//! The buffer is never resized after creation, thus no stale copies are left behind by
+//! reallocations
+use std::{
+    fmt::Debug,
+    io::{self, Read, Write},
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use serde::{
+    de::{SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use zeroize::Zeroize;
+
+///Whether new `SecretBytes` lock their memory
+static MLOCK: AtomicBool = AtomicBool::new(false);
+
+///Lock the memory of all `SecretBytes` that are created afterwards. Locking is best effort: if it
+///fails, e.g. due to `RLIMIT_MEMLOCK`, a warning is printed and the secret is used anyway
+pub fn set_mlock(enabled: bool) {
+    MLOCK.store(enabled, Ordering::Relaxed);
+}
+
+///Growable buffer that zeroizes the old allocation whenever it grows, so that growing does not
+///leave partial copies of the content behind
+#[derive(Default)]
+struct GrowBuffer(Vec<u8>);
+
+impl GrowBuffer {
+    fn extend(&mut self, data: &[u8]) {
+        let required = self.0.len() + data.len();
+        if required > self.0.capacity() {
+            let mut grown = Vec::with_capacity(required.max(2 * self.0.capacity()).max(64));
+            grown.extend_from_slice(&self.0);
+            self.0.zeroize();
+            self.0 = grown;
+        }
+        self.0.extend_from_slice(data);
+    }
+
+    fn into_secret(mut self) -> SecretBytes {
+        SecretBytes::new(mem::take(&mut self.0))
+    }
+}
+
+impl Write for GrowBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for GrowBuffer {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+///Secret byte string, zeroized on drop
+pub struct SecretBytes {
+    data: Vec<u8>,
+    ///Whether `data` is locked and needs to be unlocked on drop
+    locked: bool,
+}
+
+impl SecretBytes {
+    ///Take ownership of `data`. Copies that `data` may have left behind are not affected
+    pub fn new(data: Vec<u8>) -> Self {
+        let mut secret = SecretBytes {
+            data,
+            locked: false,
+        };
+        if MLOCK.load(Ordering::Relaxed) && secret.data.capacity() > 0 {
+            //SAFETY: the range is the allocation of `data`, which is owned by the secret and never
+            //resized, so it stays valid until it is unlocked in drop
+            let ret = unsafe { libc::mlock(secret.data.as_ptr().cast(), secret.data.capacity()) };
+            if ret == 0 {
+                secret.locked = true;
+            } else {
+                eprintln!(
+                    "Warning: failed to lock secret in memory: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        secret
+    }
+
+    pub fn from_slice(data: &[u8]) -> Self {
+        SecretBytes::new(data.to_vec())
+    }
+
+    ///JSON serialization of a value that contains secrets. Unlike `serde_json::to_vec`, no
+    ///partial copies are left behind
+    pub fn to_json<T: Serialize>(value: &T) -> Result<Self, serde_json::Error> {
+        let mut buffer = GrowBuffer::default();
+        serde_json::to_writer(&mut buffer, value)?;
+        Ok(buffer.into_secret())
+    }
+
+    ///Read `reader` to the end, e.g. a secret piped via stdin
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut buffer = GrowBuffer::default();
+        io::copy(reader, &mut buffer)?;
+        Ok(buffer.into_secret())
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.data
+    }
+
+    ///Mutable access, e.g. for in-place decryption
+    pub fn expose_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    ///Shorten the secret to `len` bytes. The cut off part is zeroized
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.data.len() {
+            self.data[len..].zeroize();
+            self.data.truncate(len);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        //zeroizes the whole capacity
+        self.data.zeroize();
+        if self.locked {
+            //SAFETY: the same range that was locked in `new`, `data` is still allocated
+            unsafe { libc::munlock(self.data.as_ptr().cast(), self.data.capacity()) };
+        }
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> Self {
+        SecretBytes::from_slice(&self.data)
+    }
+}
+
+impl Debug for SecretBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretBytes(<{} bytes redacted>)", self.data.len())
+    }
+}
+
+///Constant time comparison
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.data.len() == other.data.len() && openssl::memcmp::eq(&self.data, &other.data)
+    }
+}
+
+impl Eq for SecretBytes {}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(value: Vec<u8>) -> Self {
+        SecretBytes::new(value)
+    }
+}
+
+///Serialized like `Vec<u8>`
+impl Serialize for SecretBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.data.serialize(serializer)
+    }
+}
+
+struct SecretBytesVisitor;
+
+impl<'de> Visitor<'de> for SecretBytesVisitor {
+    type Value = SecretBytes;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a byte array")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(SecretBytes::from_slice(v))
+    }
+
+    fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(SecretBytes::new(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut buffer = GrowBuffer::default();
+        while let Some(byte) = seq.next_element::<u8>()? {
+            buffer.extend(&[byte]);
+        }
+        Ok(buffer.into_secret())
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(SecretBytesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SecretBytes;
+
+    #[test]
+    fn secret_bytes() {
+        let mut secret = SecretBytes::from_slice(b"hunter2");
+        assert_eq!(format!("{:?}", secret), "SecretBytes(<7 bytes redacted>)");
+        assert_eq!(secret, SecretBytes::new(b"hunter2".to_vec()));
+        assert_ne!(secret, SecretBytes::from_slice(b"hunter"));
+        secret.truncate(6);
+        assert_eq!(secret.expose(), b"hunter");
+        let json = serde_json::to_string(&secret).unwrap();
+        assert_eq!(serde_json::from_str::<SecretBytes>(&json).unwrap(), secret);
+    }
+}
@@ -0,0 +1,353 @@
+//! Destinations for secrets that were injected into the VM.
+//!
+//! Apart from plain files, all sinks hand over the secret without touching a filesystem:
+//! the Linux kernel keyring, an inherited file descriptor or FIFO, and stdout, e.g. for piping
+//! straight into `cryptsetup --key-file=-`
+use std::{
+    ffi::CString,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    mem::ManuallyDrop,
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::fs::{FileTypeExt, OpenOptionsExt},
+    },
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use snafu::{whatever, ResultExt, Whatever};
+
+const FILE_PREFIX: &str = "file:";
+const FD_PREFIX: &str = "fd:";
+const FIFO_PREFIX: &str = "fifo:";
+const KEYRING_PREFIX: &str = "keyring:";
+const STDOUT: &str = "stdout";
+
+///Permissions for secrets written to regular files
+const SECRET_FILE_MODE: u32 = 0o600;
+
+///Fds of `SecretSink::Fd` that received their secret and were closed. Sinks are cloned, so this is
+///tracked per process: afterwards, the fd number may refer to an unrelated socket or file
+static CLOSED_FDS: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+///Kernel keyrings that can be addressed by a special id, see `man 7 keyrings`.
+///The string representation matches the one used by `keyctl`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keyring {
+    ///`@s`, the session keyring of the server process
+    #[serde(rename = "@s")]
+    Session,
+    ///`@u`, shared by all processes of the user. Outlives the server process
+    #[serde(rename = "@u")]
+    User,
+    ///`@us`, the default session keyring of the user
+    #[serde(rename = "@us")]
+    UserSession,
+}
+
+impl Keyring {
+    fn special_id(&self) -> i32 {
+        match self {
+            Keyring::Session => -3,
+            Keyring::User => -4,
+            Keyring::UserSession => -5,
+        }
+    }
+
+    fn from_spec(spec: &str) -> Option<Self> {
+        match spec {
+            "@s" => Some(Keyring::Session),
+            "@u" => Some(Keyring::User),
+            "@us" => Some(Keyring::UserSession),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let spec = match self {
+            Keyring::Session => "@s",
+            Keyring::User => "@u",
+            Keyring::UserSession => "@us",
+        };
+        write!(f, "{}", spec)
+    }
+}
+
+///Destination for an injected secret. The string representation is
+/// - `stdout` or `-` : write to stdout
+/// - `fd:<N>` : write to the already open file descriptor `N` and close it afterwards. Only a
+///   single secret can be delivered this way
+/// - `fifo:<path>` : write to an existing named pipe. Blocks until there is a reader
+/// - `keyring:[<keyring>:]<description>` : add a `user` key to the kernel keyring. `<keyring>`
+///   is one of `@u` (default), `@s` or `@us`
+/// - `file:<path>` or just `<path>` : write to a regular file, only readable by the owner
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum SecretSink {
+    File(PathBuf),
+    Fifo(PathBuf),
+    Fd(RawFd),
+    Keyring {
+        keyring: Keyring,
+        description: String,
+    },
+    Stdout,
+}
+
+impl SecretSink {
+    ///Must be called before anything is printed, if the secret is to be delivered via stdout.
+    ///Redirects our own stdout to stderr, so that log messages do not end up in the secret, and
+    ///returns a sink for a duplicate of the original stdout. All other sinks are returned unchanged
+    pub fn detach_stdout(self) -> Result<Self, Whatever> {
+        if self != SecretSink::Stdout {
+            return Ok(self);
+        }
+        io::stdout()
+            .flush()
+            .whatever_context("failed to flush stdout")?;
+        //SAFETY: only operates on the standard file descriptors, which are always valid
+        let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).whatever_context("failed to duplicate stdout");
+        }
+        //SAFETY: see above
+        if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+            return Err(io::Error::last_os_error())
+                .whatever_context("failed to redirect stdout to stderr");
+        }
+        Ok(SecretSink::Fd(fd))
+    }
+
+    ///Hand over `secret` to this sink
+    pub fn deliver(&self, secret: &[u8]) -> Result<(), Whatever> {
+        match self {
+            SecretSink::File(path) => {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(SECRET_FILE_MODE)
+                    .open(path)
+                    .whatever_context(format!("failed to create secret file {}", path.display()))?;
+                file.write_all(secret)
+                    .whatever_context(format!("failed to write secret to {}", path.display()))
+            }
+            SecretSink::Fifo(path) => {
+                let mut fifo = OpenOptions::new()
+                    .write(true)
+                    .open(path)
+                    .whatever_context(format!("failed to open fifo {}", path.display()))?;
+                let is_fifo = fifo
+                    .metadata()
+                    .whatever_context(format!("failed to stat {}", path.display()))?
+                    .file_type()
+                    .is_fifo();
+                if !is_fifo {
+                    whatever!("{} is not a fifo", path.display());
+                }
+                fifo.write_all(secret)
+                    .whatever_context(format!("failed to write secret to fifo {}", path.display()))
+            }
+            SecretSink::Fd(fd) => {
+                let mut closed = CLOSED_FDS.lock().expect("poisoned lock");
+                if closed.contains(fd) {
+                    whatever!("fd {} already received a secret and was closed", fd);
+                }
+                //SAFETY: the fd was handed to us for exclusive use and is not closed yet, see
+                //`CLOSED_FDS`. `ManuallyDrop` keeps it open if writing fails, so that a retry
+                //still writes to the same file
+                let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(*fd) });
+                file.write_all(secret)
+                    .whatever_context(format!("failed to write secret to fd {}", fd))?;
+                //close exactly once, so that the reader sees EOF
+                drop(ManuallyDrop::into_inner(file));
+                closed.push(*fd);
+                Ok(())
+            }
+            SecretSink::Keyring {
+                keyring,
+                description,
+            } => add_user_key(*keyring, description, secret),
+            SecretSink::Stdout => {
+                whatever!("stdout sink must be detached via `detach_stdout` before use")
+            }
+        }
+    }
+}
+
+///Add a key of type `user` to the given kernel keyring via the `add_key` syscall.
+///Replaces the payload if a key with the same description already exists
+fn add_user_key(keyring: Keyring, description: &str, payload: &[u8]) -> Result<(), Whatever> {
+    let key_type = CString::new("user").expect("static string without nul byte");
+    let c_description = CString::new(description)
+        .whatever_context("key description must not contain a nul byte")?;
+    //SAFETY: all pointers are valid for the duration of the call and the lengths match
+    let serial = unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            key_type.as_ptr(),
+            c_description.as_ptr(),
+            payload.as_ptr(),
+            payload.len(),
+            keyring.special_id(),
+        )
+    };
+    if serial < 0 {
+        return Err(io::Error::last_os_error()).whatever_context(format!(
+            "failed to add key {} to keyring {}",
+            description, keyring
+        ));
+    }
+    Ok(())
+}
+
+impl FromStr for SecretSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == STDOUT || s == "-" {
+            return Ok(SecretSink::Stdout);
+        }
+        if let Some(fd) = s.strip_prefix(FD_PREFIX) {
+            return match fd.parse::<RawFd>() {
+                Ok(v) if v >= 0 => Ok(SecretSink::Fd(v)),
+                _ => Err(format!("invalid file descriptor {}", fd)),
+            };
+        }
+        if let Some(path) = s.strip_prefix(FIFO_PREFIX) {
+            return Ok(SecretSink::Fifo(path.into()));
+        }
+        if let Some(key) = s.strip_prefix(KEYRING_PREFIX) {
+            let (keyring, description) = match key.split_once(':') {
+                Some((spec, description)) if spec.starts_with('@') => {
+                    match Keyring::from_spec(spec) {
+                        Some(v) => (v, description),
+                        None => return Err(format!("unknown keyring {}", spec)),
+                    }
+                }
+                _ => (Keyring::User, key),
+            };
+            if description.is_empty() {
+                return Err("key description must not be empty".to_string());
+            }
+            return Ok(SecretSink::Keyring {
+                keyring,
+                description: description.to_string(),
+            });
+        }
+        let path = s.strip_prefix(FILE_PREFIX).unwrap_or(s);
+        if path.is_empty() {
+            return Err("secret output path must not be empty".to_string());
+        }
+        Ok(SecretSink::File(path.into()))
+    }
+}
+
+impl TryFrom<String> for SecretSink {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<SecretSink> for String {
+    fn from(value: SecretSink) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for SecretSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretSink::File(path) => write!(f, "{}{}", FILE_PREFIX, path.display()),
+            SecretSink::Fifo(path) => write!(f, "{}{}", FIFO_PREFIX, path.display()),
+            SecretSink::Fd(fd) => write!(f, "{}{}", FD_PREFIX, fd),
+            SecretSink::Keyring {
+                keyring,
+                description,
+            } => write!(f, "{}{}:{}", KEYRING_PREFIX, keyring, description),
+            SecretSink::Stdout => write!(f, "{}", STDOUT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        io::Read,
+        os::{fd::FromRawFd, unix::fs::PermissionsExt},
+    };
+
+    use super::{Keyring, SecretSink};
+
+    #[test]
+    fn parse_sinks() {
+        let cases = [
+            ("-", SecretSink::Stdout),
+            ("fd:3", SecretSink::Fd(3)),
+            ("fifo:/run/key", SecretSink::Fifo("/run/key".into())),
+            ("./disk_key.txt", SecretSink::File("./disk_key.txt".into())),
+            (
+                "keyring:cryptsetup",
+                SecretSink::Keyring {
+                    keyring: Keyring::User,
+                    description: "cryptsetup".to_string(),
+                },
+            ),
+            (
+                "keyring:@s:luks:root",
+                SecretSink::Keyring {
+                    keyring: Keyring::Session,
+                    description: "luks:root".to_string(),
+                },
+            ),
+        ];
+        for (input, want) in cases {
+            let got: SecretSink = input.parse().unwrap();
+            assert_eq!(got, want, "input {}", input);
+            assert_eq!(got.to_string().parse::<SecretSink>().unwrap(), want);
+        }
+        assert!("fd:-1".parse::<SecretSink>().is_err());
+        assert!("keyring:@x:foo".parse::<SecretSink>().is_err());
+    }
+
+    #[test]
+    fn file_sink_is_private() {
+        let path = std::env::temp_dir().join(format!("snpguard-sink-{}", std::process::id()));
+        SecretSink::File(path.clone()).deliver(b"secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let content = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(content, b"secret");
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn fd_sink_closes_once() {
+        let mut fds = [0; 2];
+        //SAFETY: `fds` has room for the two fds
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        //SAFETY: the read end was just created and is only owned here
+        let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+        let sink = SecretSink::Fd(fds[1]);
+        sink.clone().deliver(b"secret").unwrap();
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(
+            content, b"secret",
+            "the write end must be closed after delivery"
+        );
+        assert!(
+            sink.deliver(b"again").is_err(),
+            "the fd number may have been reused"
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Bookkeeping for concurrent attestation sessions on the server side
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use snafu::{whatever, FromString, ResultExt, Whatever};
+
+///Number of random bytes in a session id. Session ids must not be guessable, as they are
+///the only thing that links the secret injection to the corresponding attestation report
+const SESSION_ID_BYTES: usize = 16;
+
+struct Session<T> {
+    value: T,
+    expires_at: Instant,
+}
+
+///Stores per-session state, e.g. the ephemeral key material of an attestation report that
+///was sent to a client. Sessions are identified by random ids and expire after a fixed timeout
+pub struct SessionStore<T> {
+    sessions: HashMap<String, Session<T>>,
+    rng: SystemRandom,
+    ///Time after which a session is discarded
+    timeout: Duration,
+    ///Upper bound on the number of concurrently open sessions
+    max_sessions: usize,
+}
+
+impl<T> SessionStore<T> {
+    pub fn new(timeout: Duration, max_sessions: usize) -> Self {
+        SessionStore {
+            sessions: HashMap::new(),
+            rng: SystemRandom::new(),
+            timeout,
+            max_sessions,
+        }
+    }
+
+    ///Store `value` under a fresh session id and return the id
+    pub fn insert(&mut self, value: T) -> Result<String, Whatever> {
+        self.prune();
+        if self.sessions.len() >= self.max_sessions {
+            whatever!(
+                "too many open sessions, limit is {}. Try again later",
+                self.max_sessions
+            );
+        }
+        let mut raw_id = [0u8; SESSION_ID_BYTES];
+        self.rng
+            .fill(&mut raw_id)
+            .map_err(|_| Whatever::without_source("failed to sample randomness".to_string()))
+            .whatever_context("failed to sample session id")?;
+        let id = hex::encode(raw_id);
+        self.sessions.insert(
+            id.clone(),
+            Session {
+                value,
+                expires_at: Instant::now() + self.timeout,
+            },
+        );
+        Ok(id)
+    }
+
+    ///Remove the session with the given id and return its value.
+    ///Returns None if there is no such session or if it expired
+    pub fn take(&mut self, id: &str) -> Option<T> {
+        self.prune();
+        self.sessions.remove(id).map(|v| v.value)
+    }
+
+    ///Access the session with the given id and extend its lifetime by the timeout. For long
+    ///lived sessions that expire when idle. Returns None if there is no such session or if it expired
+    pub fn touch(&mut self, id: &str) -> Option<&mut T> {
+        self.prune();
+        let expires_at = Instant::now() + self.timeout;
+        self.sessions.get_mut(id).map(|v| {
+            v.expires_at = expires_at;
+            &mut v.value
+        })
+    }
+
+    ///Number of open, non-expired sessions
+    pub fn len(&mut self) -> usize {
+        self.prune();
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&mut self) -> bool {
+        self.len() == 0
+    }
+
+    ///Drop all expired sessions
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.sessions.retain(|_, v| v.expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::SessionStore;
+
+    #[test]
+    fn sessions_are_independent() {
+        let mut store = SessionStore::new(Duration::from_secs(60), 8);
+        let a = store.insert(1).unwrap();
+        let b = store.insert(2).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(store.take(&b), Some(2));
+        assert_eq!(store.take(&b), None);
+        assert_eq!(store.touch(&a), Some(&mut 1));
+        assert_eq!(store.take(&a), Some(1));
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let mut store = SessionStore::new(Duration::ZERO, 8);
+        let a = store.insert(1).unwrap();
+        assert_eq!(store.take(&a), None);
+    }
+
+    #[test]
+    fn session_limit() {
+        let mut store = SessionStore::new(Duration::from_secs(60), 1);
+        store.insert(1).unwrap();
+        assert!(store.insert(2).is_err());
+    }
+}
@@ -1,88 +1,291 @@
-use std::io::Write;
-
-use ring::agreement::{self, UnparsedPublicKey};
+//! Access to the SEV-SNP attestation report and the layout of its `report_data` field.
+//!
+//! `report_data` is 64 bytes of caller chosen data. We never store values in it directly but
+//! bind a set of typed `ReportClaims` to the report:
+//!
+//! | bytes    | content                                                                 |
+//! |----------|-------------------------------------------------------------------------|
+//! | `0..6`   | magic `SNPGRD`                                                          |
+//! | `6..8`   | layout version, big endian. Currently `REPORT_DATA_VERSION`             |
+//! | `8..64`  | first 56 bytes of SHA-512 over the canonical encoding of the claims     |
+//!
+//! The verifier cannot recover the claims from the digest, it has to learn them out of band
+//! (e.g. the nonce it chose itself and the public key sent along with the report) and check them
+//! with `ReportClaims::check`.
+//!
+//! Protocol versions 1 and 2 store the nonce and the public DH key in plain instead, see
+//! `LegacyReportData`
+use ring::digest::{self, SHA512};
 use sev::{
     error::UserApiError,
     firmware::guest::{AttestationReport, Firmware},
 };
 
-pub struct ReportData {
+use crate::snp_validate_report::ReportVerificationError;
+
+///Marks report data that uses the layout described in the module docs
+pub const REPORT_DATA_MAGIC: [u8; 6] = *b"SNPGRD";
+///Version of the report data layout and of the claim encoding
+pub const REPORT_DATA_VERSION: u16 = 1;
+///Domain separation label for the claims digest
+const CLAIMS_DIGEST_LABEL: &[u8] = b"snpguard report data claims";
+const HEADER_LEN: usize = REPORT_DATA_MAGIC.len() + 2;
+
+///Claims bound to an attestation report via its `report_data`. Build them with the `with_*`
+///methods. Absent claims are not encoded, so a verifier must expect exactly the claims that
+///were used to request the report
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportClaims {
+    ///Freshness nonce chosen by the verifier
+    pub nonce: Option<u64>,
+    ///Public X25519 key of the VM, used to wrap secrets for it
+    pub dh_public_key: Option<[u8; 32]>,
+    ///DER encoded SubjectPublicKeyInfo of the RA-TLS certificate key
+    pub tls_public_key: Option<Vec<u8>>,
+    ///Fingerprint of the SSH host key, as printed by `ssh-keygen -l`
+    pub ssh_fingerprint: Option<String>,
+    ///Arbitrary, application specific data
+    pub app_data: Option<Vec<u8>>,
+}
+
+///Tags of the claims in the canonical encoding. Never reuse a value
+#[repr(u8)]
+enum ClaimTag {
+    Nonce = 1,
+    DhPublicKey = 2,
+    TlsPublicKey = 3,
+    SshFingerprint = 4,
+    AppData = 5,
+}
+
+impl ReportClaims {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = Some(nonce);
+        self
+    }
+
+    pub fn with_dh_public_key(mut self, public_key: [u8; 32]) -> Self {
+        self.dh_public_key = Some(public_key);
+        self
+    }
+
+    pub fn with_tls_public_key(mut self, public_key_der: &[u8]) -> Self {
+        self.tls_public_key = Some(public_key_der.to_vec());
+        self
+    }
+
+    pub fn with_ssh_fingerprint(mut self, fingerprint: &str) -> Self {
+        self.ssh_fingerprint = Some(fingerprint.to_string());
+        self
+    }
+
+    pub fn with_app_data(mut self, app_data: &[u8]) -> Self {
+        self.app_data = Some(app_data.to_vec());
+        self
+    }
+
+    ///Canonical encoding: the present claims in ascending tag order, each as
+    ///`tag (1 byte) || length (4 bytes, big endian) || value`
+    fn encode(&self) -> Vec<u8> {
+        fn push(out: &mut Vec<u8>, tag: ClaimTag, value: &[u8]) {
+            out.push(tag as u8);
+            out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            out.extend_from_slice(value);
+        }
+        let mut out = Vec::new();
+        if let Some(v) = self.nonce {
+            push(&mut out, ClaimTag::Nonce, &v.to_be_bytes());
+        }
+        if let Some(v) = &self.dh_public_key {
+            push(&mut out, ClaimTag::DhPublicKey, v);
+        }
+        if let Some(v) = &self.tls_public_key {
+            push(&mut out, ClaimTag::TlsPublicKey, v);
+        }
+        if let Some(v) = &self.ssh_fingerprint {
+            push(&mut out, ClaimTag::SshFingerprint, v.as_bytes());
+        }
+        if let Some(v) = &self.app_data {
+            push(&mut out, ClaimTag::AppData, v);
+        }
+        out
+    }
+
+    ///Value for the `report_data` field of the attestation report
+    pub fn to_report_data(&self) -> [u8; 64] {
+        let mut report_data = [0u8; 64];
+        report_data[..REPORT_DATA_MAGIC.len()].copy_from_slice(&REPORT_DATA_MAGIC);
+        report_data[REPORT_DATA_MAGIC.len()..HEADER_LEN]
+            .copy_from_slice(&REPORT_DATA_VERSION.to_be_bytes());
+
+        let mut ctx = digest::Context::new(&SHA512);
+        ctx.update(CLAIMS_DIGEST_LABEL);
+        ctx.update(&report_data[..HEADER_LEN]);
+        ctx.update(&self.encode());
+        let claims_digest = ctx.finish();
+        report_data[HEADER_LEN..].copy_from_slice(&claims_digest.as_ref()[..64 - HEADER_LEN]);
+        report_data
+    }
+
+    ///Check that `report_data` was built from exactly these claims
+    pub fn check(&self, report_data: &[u8; 64]) -> Result<(), ReportVerificationError> {
+        if report_data[..REPORT_DATA_MAGIC.len()] != REPORT_DATA_MAGIC {
+            return Err(ReportVerificationError::ReportDataMismatch {
+                expected: format!(
+                    "report data starting with {:?}",
+                    String::from_utf8_lossy(&REPORT_DATA_MAGIC)
+                ),
+                got: format!("0x{}", hex::encode(report_data)),
+            });
+        }
+        let expected = self.to_report_data();
+        if report_data[..HEADER_LEN] != expected[..HEADER_LEN] {
+            return Err(ReportVerificationError::ReportDataMismatch {
+                expected: format!("report data version {}", REPORT_DATA_VERSION),
+                got: format!(
+                    "version {}",
+                    u16::from_be_bytes([report_data[HEADER_LEN - 2], report_data[HEADER_LEN - 1]])
+                ),
+            });
+        }
+        if report_data != &expected {
+            return Err(ReportVerificationError::ReportDataMismatch {
+                expected: format!("0x{} for {:?}", hex::encode(expected), self),
+                got: format!("0x{}", hex::encode(report_data)),
+            });
+        }
+        Ok(())
+    }
+}
+
+///Report data layout of protocol versions 1 and 2: the nonce (little endian) followed by the
+///public X25519 key of the VM, padded with zeros
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LegacyReportData {
     pub nonce: u64,
-    pub server_public_key: UnparsedPublicKey<[u8; 32]>,
+    pub public_key: [u8; 32],
 }
 
-impl ReportData {
+impl LegacyReportData {
     pub fn new(nonce: u64, public_key: [u8; 32]) -> Self {
-        let pk = agreement::UnparsedPublicKey::new(&agreement::X25519, public_key);
-        ReportData {
-            nonce,
-            server_public_key: pk,
+        LegacyReportData { nonce, public_key }
+    }
+
+    ///Check that `report_data` contains exactly this nonce and public key
+    pub fn check(&self, report_data: &[u8; 64]) -> Result<(), ReportVerificationError> {
+        let expected: [u8; 64] = (*self).into();
+        if report_data != &expected {
+            return Err(ReportVerificationError::ReportDataMismatch {
+                expected: format!("0x{} for {:?}", hex::encode(expected), self),
+                got: format!("0x{}", hex::encode(report_data)),
+            });
         }
+        Ok(())
     }
 }
 
-impl Into<[u8; 64]> for ReportData {
-    fn into(self) -> [u8; 64] {
-        let mut in_data = Vec::new();
-        in_data
-            .write_all(&self.nonce.to_le_bytes())
-            .expect("failed to write nonce");
-        in_data
-            .write_all(self.server_public_key.as_ref())
-            .expect("failed to write pubkey");
-        const OUT_LEN: usize = 64;
-        assert!(in_data.len() < OUT_LEN);
-        in_data.resize(OUT_LEN, 0);
-        in_data
-            .try_into()
-            .expect("failed to convert user data to fixed size slice")
+impl From<LegacyReportData> for [u8; 64] {
+    fn from(value: LegacyReportData) -> Self {
+        let mut report_data = [0u8; 64];
+        report_data[..8].copy_from_slice(&value.nonce.to_le_bytes());
+        report_data[8..40].copy_from_slice(&value.public_key);
+        report_data
     }
 }
 
-impl From<[u8; 64]> for ReportData {
+impl From<[u8; 64]> for LegacyReportData {
     fn from(value: [u8; 64]) -> Self {
-        let mut raw_le_nonce = [0u8; 8];
-        let mut raw_pubkey = [0u8; 32];
-        raw_le_nonce.copy_from_slice(&value[..8]);
-        raw_pubkey.copy_from_slice(&value[8..(8 + 32)]);
-        let nonce = u64::from_le_bytes(raw_le_nonce);
-        let server_public_key = agreement::UnparsedPublicKey::new(&agreement::X25519, raw_pubkey);
-        ReportData {
-            nonce,
-            server_public_key,
+        LegacyReportData {
+            nonce: u64::from_le_bytes(value[..8].try_into().expect("slice has eight bytes")),
+            public_key: value[8..40].try_into().expect("slice has 32 bytes"),
         }
     }
 }
 
 pub trait QuerySNPAttestation {
-    fn get_report(
-        nonce: u64,
-        server_public_key: [u8; 32],
-    ) -> Result<AttestationReport, UserApiError>;
+    ///Request a report that contains the given, raw `report_data`
+    fn get_report_raw(report_data: [u8; 64]) -> Result<AttestationReport, UserApiError>;
+
+    ///Request a report that is bound to `claims`
+    fn get_report(claims: &ReportClaims) -> Result<AttestationReport, UserApiError> {
+        Self::get_report_raw(claims.to_report_data())
+    }
 }
 
 pub struct MockSNPAttestation {}
 
 impl QuerySNPAttestation for MockSNPAttestation {
-    fn get_report(
-        nonce: u64,
-        server_public_key: [u8; 32],
-    ) -> Result<AttestationReport, UserApiError> {
+    fn get_report_raw(report_data: [u8; 64]) -> Result<AttestationReport, UserApiError> {
         let mut report = AttestationReport::default();
-        report.report_data = ReportData::new(nonce, server_public_key).into();
+        report.report_data = report_data;
         Ok(report)
     }
 }
 
+///VMPL from which the guest requests its reports. The kernel of our guests runs at VMPL 0, while
+///the sev crate defaults to VMPL 1
+pub const REPORT_VMPL: u32 = 0;
+
 pub struct SNPAttestation {}
 
 impl QuerySNPAttestation for SNPAttestation {
-    fn get_report(
-        nonce: u64,
-        server_public_key: [u8; 32],
-    ) -> Result<AttestationReport, UserApiError> {
+    fn get_report_raw(report_data: [u8; 64]) -> Result<AttestationReport, UserApiError> {
         let mut fw = Firmware::open()?;
-        let report_data = ReportData::new(nonce, server_public_key);
-        fw.get_report(None, Some(report_data.into()), None)
+        fw.get_report(None, Some(report_data), Some(REPORT_VMPL))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LegacyReportData, ReportClaims, REPORT_DATA_MAGIC};
+
+    #[test]
+    fn claims_roundtrip() {
+        let claims = ReportClaims::new()
+            .with_nonce(7)
+            .with_dh_public_key([1; 32]);
+        let report_data = claims.to_report_data();
+        assert_eq!(report_data[..6], REPORT_DATA_MAGIC);
+        claims.check(&report_data).unwrap();
+
+        assert!(claims.clone().with_nonce(8).check(&report_data).is_err());
+        assert!(claims
+            .clone()
+            .with_app_data(b"")
+            .check(&report_data)
+            .is_err());
+        assert!(ReportClaims::new().check(&report_data).is_err());
+
+        let mut tampered = report_data;
+        tampered[7] ^= 1;
+        assert!(claims.check(&tampered).is_err());
+    }
+
+    #[test]
+    fn legacy_roundtrip() {
+        let legacy = LegacyReportData::new(7, [1; 32]);
+        let report_data: [u8; 64] = legacy.into();
+        assert_eq!(report_data[..8], 7u64.to_le_bytes());
+        assert_eq!(LegacyReportData::from(report_data), legacy);
+        legacy.check(&report_data).unwrap();
+        assert!(LegacyReportData::new(8, [1; 32])
+            .check(&report_data)
+            .is_err());
+
+        let mut padded = report_data;
+        padded[63] = 1;
+        assert!(legacy.check(&padded).is_err());
+    }
+
+    #[test]
+    fn encoding_is_unambiguous() {
+        //same bytes in different claims must not collide
+        let a = ReportClaims::new().with_ssh_fingerprint("abc");
+        let b = ReportClaims::new().with_app_data(b"abc");
+        assert_ne!(a.to_report_data(), b.to_report_data());
     }
 }
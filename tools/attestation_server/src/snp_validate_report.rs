@@ -1,31 +1,27 @@
 use std::{
-    fmt::Display,
+    collections::BTreeMap,
+    fmt::{Debug, Display},
     fs::{self, File},
     io::{self, Read, Write},
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use base64::{engine::general_purpose, Engine};
 use openssl::sha::sha384;
 use reqwest::{blocking, Url};
-use serde::{Deserialize, Serialize};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 use sev::{
     certs::snp::{
         builtin::{genoa, milan},
         ca, Certificate, Chain, Verifiable,
     },
-    firmware::{
-        guest::{AttestationReport, GuestPolicy, PlatformInfo},
-        host::TcbVersion,
-    },
+    firmware::{guest::AttestationReport, host::TcbVersion},
     measurement::idblock_types::{IdAuth, IdBlock, SevEcdsaPubKey},
 };
-use snafu::{whatever, ResultExt, Whatever,prelude::*,FromString};
-
-use crate::calc_expected_ld::IDBLOCK_ID_BYTES;
-
-
+use snafu::{prelude::*, whatever, FromString, ResultExt, Whatever};
 
+use crate::calc_expected_ld::{VMDescription, IDBLOCK_ID_BYTES};
 
 ///Parse the supplied data and also return a special representation
 ///that is usefull for checking the attestation report
@@ -35,33 +31,41 @@ pub fn parse_id_block_data(
 ) -> Result<(IdBlock, IdAuth, IDBLockReportData), Whatever> {
     //decode id_block
     let id_block_raw = general_purpose::STANDARD
-        .decode(&id_block_raw)
+        .decode(id_block_raw)
         .whatever_context("failed to decode id block as base64")?;
     let id_block: IdBlock =
         bincode::deserialize(&id_block_raw).whatever_context("failed to bindecode id block")?;
 
     //decode id_auth block
     let id_auth_block_raw = general_purpose::STANDARD
-        .decode(&id_auth_block_raw)
+        .decode(id_auth_block_raw)
         .whatever_context("failed to decode id auth block as base64")?;
     let id_auth_block: IdAuth = bincode::deserialize(&id_auth_block_raw)
         .whatever_context("failed to bindecode id auth block")?;
 
-    let id_block_report_data: IDBLockReportData =
-        (id_block.clone(), id_auth_block.clone()).try_into()?;
+    let id_block_report_data: IDBLockReportData = (id_block, id_auth_block).try_into()?;
 
     Ok((id_block, id_auth_block, id_block_report_data))
 }
 
-
-#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
-///Describes the CPU generation
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default)]
+///Describes the CPU generation. Turin is not supported yet, as the sev crate ships no Turin ARK
+///and ASK to verify its VCEK with
 pub enum ProductName {
     #[default]
     Milan,
     Genoa,
 }
 
+impl ProductName {
+    ///CPUID family of this generation, reported by the host since report version 3
+    pub fn cpuid_family(&self) -> u8 {
+        match self {
+            ProductName::Milan | ProductName::Genoa => 0x19,
+        }
+    }
+}
+
 impl Display for ProductName {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
@@ -128,7 +132,7 @@ impl CachingVCEKDownloader {
                 if e.kind() != io::ErrorKind::NotFound {
                     Err(e).whatever_context(format!("file path {:?}", cert_cache_path))?;
                 }
-                cert_bytes = download_vceck_cert(chip_id, product_name, &tcb)
+                cert_bytes = download_vceck_cert(chip_id, product_name, tcb)
                     .whatever_context("failed to download certificate")?;
                 let mut out_file = File::create(&cert_cache_path)
                     .whatever_context(format!("file path {:?}", cert_cache_path))?;
@@ -183,6 +187,7 @@ pub fn download_vceck_cert(
 
 /// Data from the ID Block and ID Authentication Information Structure (shorthand ID Auth Block)
 /// that is relevant for veryfing the attestation report
+#[derive(Clone, Copy)]
 pub struct IDBLockReportData {
     ///Vm owner defined data to identify the VM
     guest_svn: u32,
@@ -198,24 +203,34 @@ pub struct IDBLockReportData {
 
 ///Convert a public key a sha384 digest
 fn pubkey_to_id_block_digest(p: &SevEcdsaPubKey) -> Result<[u8; 48], Whatever> {
-    sha384(
+    Ok(sha384(
         bincode::serialize(p)
             .whatever_context("faild to serialize pubkey with bincode")?
             .as_slice(),
-    )
-    .try_into()
-    .whatever_context("public key hash has incorrect size")
+    ))
 }
 
 impl TryFrom<(IdBlock, IdAuth)> for IDBLockReportData {
     type Error = Whatever;
 
     fn try_from((id, auth): (IdBlock, IdAuth)) -> Result<Self, Self::Error> {
-        let family_id_bytes : Vec<u8> = bincode::serialize(&id.family_id).whatever_context("failed to serialize family id to bytes")?;
-        let family_id_bytes : [u8;IDBLOCK_ID_BYTES] = family_id_bytes.try_into().map_err(|v| Whatever::without_source(format!("family id serialized to {:x?} but expected {} bytes", &v, IDBLOCK_ID_BYTES )))?;
+        let family_id_bytes: Vec<u8> = bincode::serialize(&id.family_id)
+            .whatever_context("failed to serialize family id to bytes")?;
+        let family_id_bytes: [u8; IDBLOCK_ID_BYTES] = family_id_bytes.try_into().map_err(|v| {
+            Whatever::without_source(format!(
+                "family id serialized to {:x?} but expected {} bytes",
+                &v, IDBLOCK_ID_BYTES
+            ))
+        })?;
 
-        let image_id_bytes : Vec<u8> = bincode::serialize(&id.image_id).whatever_context("failed to serialize image id to bytes")?;
-        let image_id_bytes : [u8;IDBLOCK_ID_BYTES] = image_id_bytes.try_into().map_err(|v| Whatever::without_source(format!("image id serialized to {:x?} but expected {} bytes", &v, IDBLOCK_ID_BYTES )))?;
+        let image_id_bytes: Vec<u8> = bincode::serialize(&id.image_id)
+            .whatever_context("failed to serialize image id to bytes")?;
+        let image_id_bytes: [u8; IDBLOCK_ID_BYTES] = image_id_bytes.try_into().map_err(|v| {
+            Whatever::without_source(format!(
+                "image id serialized to {:x?} but expected {} bytes",
+                &v, IDBLOCK_ID_BYTES
+            ))
+        })?;
         Ok(Self {
             guest_svn: id.guest_svn,
             f_id: family_id_bytes,
@@ -228,145 +243,1530 @@ impl TryFrom<(IdBlock, IdAuth)> for IDBLockReportData {
     }
 }
 
-impl IDBLockReportData {
-    ///Check that the data in the report matches the data specified in self
-    pub fn check(&self, report: &AttestationReport) -> Result<(), Whatever> {
-        if report.guest_svn != self.guest_svn {
-            whatever!(
-                "guest svn does not match, expected {:x?} got {:x?}",
-                self.guest_svn,
-                report.guest_svn
-            );
+///Byte array that is represented as a hex string
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct HexBytes<const N: usize>(pub [u8; N]);
+
+impl<'de, const N: usize> Deserialize<'de> for HexBytes<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let raw = hex::decode(s.trim_start_matches("0x")).map_err(D::Error::custom)?;
+        let raw: [u8; N] = raw.try_into().map_err(|v: Vec<u8>| {
+            D::Error::custom(format!("expected {} bytes, got {}", N, v.len()))
+        })?;
+        Ok(HexBytes(raw))
+    }
+}
+
+impl<const N: usize> Serialize for HexBytes<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.0))
+    }
+}
+
+impl<const N: usize> Debug for HexBytes<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+///Effect of setting a flag on the isolation of the guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagKind {
+    ///Setting the flag relaxes the isolation, e.g. it allows debugging
+    Relaxes,
+    ///Setting the flag restricts the platform, e.g. to a single socket
+    Restricts,
+}
+
+///Named bit of a bit field in the report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flag {
+    pub name: &'static str,
+    pub bit: u32,
+    pub kind: FlagKind,
+}
+
+impl Flag {
+    const fn new(name: &'static str, bit: u32, kind: FlagKind) -> Self {
+        Flag { name, bit, kind }
+    }
+
+    pub fn mask(&self) -> u64 {
+        1 << self.bit
+    }
+}
+
+///Named bits of the guest policy, see Table 9 of the SEV-SNP firmware ABI specification (AMD 56860)
+pub const GUEST_POLICY_FLAGS: &[Flag] = &[
+    Flag::new("smt_allowed", 16, FlagKind::Relaxes),
+    Flag::new("migrate_ma_allowed", 18, FlagKind::Relaxes),
+    Flag::new("debug_allowed", 19, FlagKind::Relaxes),
+    Flag::new("single_socket_required", 20, FlagKind::Restricts),
+    Flag::new("cxl_allowed", 21, FlagKind::Relaxes),
+    Flag::new("mem_aes_256_xts", 22, FlagKind::Restricts),
+    Flag::new("rapl_dis", 23, FlagKind::Restricts),
+    Flag::new("ciphertext_hiding", 24, FlagKind::Restricts),
+];
+
+///Bits of the guest policy that hold the minimum ABI version, checked by the `guest_abi` rule
+pub const GUEST_POLICY_ABI_MASK: u64 = 0xffff;
+
+///Named bits of the platform info, see Table 23 of the SEV-SNP firmware ABI specification (AMD 56860)
+pub const PLATFORM_INFO_FLAGS: &[Flag] = &[
+    Flag::new("smt_enabled", 0, FlagKind::Relaxes),
+    Flag::new("tsme_enabled", 1, FlagKind::Restricts),
+    Flag::new("ecc_enabled", 2, FlagKind::Restricts),
+    Flag::new("rapl_disabled", 3, FlagKind::Restricts),
+    Flag::new("ciphertext_hiding_enabled", 4, FlagKind::Restricts),
+    Flag::new("alias_check_complete", 5, FlagKind::Restricts),
+];
+
+///Minimum firmware ABI version that a guest policy demands, stored in its lowest 16 bits.
+///Written as "major.minor"
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(try_from = "String", into = "String")]
+pub struct AbiVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl AbiVersion {
+    pub fn of_policy(policy: u64) -> Self {
+        AbiVersion {
+            major: (policy >> 8) as u8,
+            minor: policy as u8,
+        }
+    }
+}
+
+impl Display for AbiVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for AbiVersion {
+    type Err = String;
+
+    ///Parse "major.minor", e.g. "1.51"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, minor) = s
+            .split_once('.')
+            .ok_or_else(|| format!("invalid ABI version {}, expected major.minor", s))?;
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<u8>()
+                .map_err(|e| format!("invalid ABI version {}: {}", s, e))
+        };
+        Ok(AbiVersion {
+            major: parse(major)?,
+            minor: parse(minor)?,
+        })
+    }
+}
+
+impl TryFrom<String> for AbiVersion {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<AbiVersion> for String {
+    fn from(value: AbiVersion) -> Self {
+        value.to_string()
+    }
+}
+
+///Value of `sig_algo` for ECDSA P-384 with SHA-384, the only algorithm that AMD defines
+pub const SIG_ALGO_ECDSA_P384_SHA384: u32 = 1;
+///Oldest report version whose layout `AttestationReport` matches
+pub const MIN_REPORT_VERSION: u32 = 2;
+///First report version with the CPUID fields
+const CPUID_REPORT_VERSION: u32 = 3;
+///Offset of the word with AUTHOR_KEY_EN, MASK_CHIP_KEY and SIGNING_KEY
+const KEY_INFO_OFFSET: usize = 0x48;
+///Offset of CPUID_FAM_ID, CPUID_MOD_ID and CPUID_STEP
+const CPUID_OFFSET: usize = 0x188;
+
+///Key that signed the report, see SIGNING_KEY in Table 22 of the SEV-SNP firmware ABI
+///specification (AMD 56860)
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SigningKey {
+    ///Versioned chip endorsement key
+    Vcek,
+    ///Versioned loaded endorsement key, provisioned by the cloud provider
+    Vlek,
+    ///The report is not signed
+    None,
+    ///Value that the specification reserves
+    Reserved,
+}
+
+impl Display for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SigningKey::Vcek => write!(f, "vcek"),
+            SigningKey::Vlek => write!(f, "vlek"),
+            SigningKey::None => write!(f, "none"),
+            SigningKey::Reserved => write!(f, "reserved"),
         }
+    }
+}
+
+///Fields of the report that `AttestationReport` keeps private or treats as reserved
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportDetails {
+    ///The id block was signed with an author key, see `author_key_digest`
+    pub author_key_en: bool,
+    ///The platform masks the chip key, so the report is not signed
+    pub mask_chip_key: bool,
+    pub signing_key: SigningKey,
+    ///CPUID family, model and stepping of the host. `None` before report version 3
+    pub cpuid_family: Option<u8>,
+    pub cpuid_model: Option<u8>,
+    pub cpuid_stepping: Option<u8>,
+}
 
-        if report.family_id != self.f_id {
-            whatever!(
-                "family id does not match, expected {:x?} got {:?}",
-                self.f_id,
-                report.family_id
-            );
+impl ReportDetails {
+    ///Decode the fields from the raw report
+    pub fn new(report: &AttestationReport) -> Self {
+        let raw = bincode::serialize(report).expect("AttestationReport is plain bytes");
+        let key_info = u32::from_le_bytes(
+            raw[KEY_INFO_OFFSET..KEY_INFO_OFFSET + 4]
+                .try_into()
+                .expect("slice has four bytes"),
+        );
+        let cpuid =
+            |i: usize| (report.version >= CPUID_REPORT_VERSION).then_some(raw[CPUID_OFFSET + i]);
+        ReportDetails {
+            author_key_en: key_info & 1 != 0,
+            mask_chip_key: key_info & 1 << 1 != 0,
+            signing_key: match (key_info >> 2) & 0x7 {
+                0 => SigningKey::Vcek,
+                1 => SigningKey::Vlek,
+                7 => SigningKey::None,
+                _ => SigningKey::Reserved,
+            },
+            cpuid_family: cpuid(0),
+            cpuid_model: cpuid(1),
+            cpuid_stepping: cpuid(2),
         }
+    }
+}
+
+///Common interface of the rules
+trait Rule {
+    ///Human readable description of the accepted values
+    fn describe(&self) -> String;
+}
+
+///Rule for a byte string field like the measurement. All set constraints must hold
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BytesRule<T> {
+    pub exact: Option<T>,
+    ///The field must have one of these values
+    pub one_of: Option<Vec<T>>,
+}
 
-        if report.image_id != self.i_id {
-            whatever!(
-                "image id does not match, expected {:x?} got {:x?}",
-                self.i_id,
-                report.image_id
-            );
+impl<T> Default for BytesRule<T> {
+    fn default() -> Self {
+        BytesRule {
+            exact: None,
+            one_of: None,
         }
+    }
+}
 
-        if report.id_key_digest != self.id_key_digest {
-            whatever!(
-                "id key digest does not match, expected {:x?} got {:x?}",
-                self.id_key_digest,
-                report.id_key_digest
-            );
+impl<const N: usize> BytesRule<HexBytes<N>> {
+    pub fn exact(value: [u8; N]) -> Self {
+        BytesRule {
+            exact: Some(HexBytes(value)),
+            one_of: None,
         }
+    }
 
-        if report.author_key_digest != self.author_key_digest {
-            whatever!(
-                "author key digest does not match, expected {:x?} got {:x?}",
-                self.author_key_digest,
-                report.author_key_digest
-            );
+    fn validate(&self) -> Result<(), String> {
+        if self.one_of.as_ref().is_some_and(|v| v.is_empty()) {
+            return Err("one_of must not be empty".to_string());
         }
+        Ok(())
+    }
 
+    fn check(&self, got: &[u8; N]) -> Result<(), String> {
+        if let Some(want) = &self.exact {
+            if want.0 != *got {
+                return Err(format!(
+                    "expected 0x{} got 0x{}",
+                    hex::encode(want.0),
+                    hex::encode(got)
+                ));
+            }
+        }
+        if let Some(allowed) = &self.one_of {
+            if !allowed.iter().any(|v| v.0 == *got) {
+                return Err(format!(
+                    "0x{} is not in the list of allowed values",
+                    hex::encode(got)
+                ));
+            }
+        }
         Ok(())
     }
 }
 
-/// Ensures that the given information matches the information specified in the report.
-/// *DOES NOT* check the report signature
-/// # Arguments
-/// - `report` : The report that we want to check
-/// - `idblock_data` : Information from the optional id block and id auth block that is relevant for the report verification. Both are optional data structures passed to QEMU. They are checked before the VM is launched
-/// - `policy` : The Guest policy from Table 9 in [1]. We specify this in VM description and pass it to QEMU at start
-/// - `tcb`    : Minimal required software versions for TCB components. The report defines three variants: comitted, launch and current. Commited is the minimum, rollback protected version. We check against this version. Launch is the tcb version at VM launch and current is the tcb version at time of report.
-/// - `plat_info` : Selected information about the status of security relevant hardware features. In constract to `policy` these features affect the platform as a whole and cannot be toggled per VM. Most features are not configured through SEV APIs but through regular CPU config options like BIOS settings. Specified in Table 23 of [1]
-/// - `report_data_validator` : Function that checks if the report data is valid. The report data is guest defined data provided when requesting the attestation report. We currently use it to return a nonce send by the guest owner as well as the public DH key generated by the VM at runtime
-/// - `host_data` : Optional VM owner defined data that was passed as HOST_DATA to QEMU during VM launch
-/// - `ld` : The expected launch digest of the guest. Use e.g. `compute_expected_hash` to compute this
-/// [1] https://www.amd.com/content/dam/amd/en/documents/epyc-technical-docs/specifications/56860.pdf
-pub fn check_report_data<F>(
-    report: &AttestationReport,
-    idblock_data: Option<IDBLockReportData>,
-    policy: Option<GuestPolicy>,
-    tcb: Option<TcbVersion>,
-    plat_info: Option<PlatformInfo>,
-    report_data_validator: Option<F>,
-    host_data: Option<[u8; 32]>,
-    ld: Option<[u8; 48]>,
-) -> Result<(), ReportVerificationError>
-where
-    F: Fn([u8; 64]) -> Result<(), ReportVerificationError>,
-{
-    if let Some(p) = policy {
-        ensure!(report.policy.0 == p.0, PolicyMismatchSnafu{
-            expected: p,
-            got: report.policy,
-        });
+impl<const N: usize> Rule for BytesRule<HexBytes<N>> {
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(want) = &self.exact {
+            parts.push(format!("0x{}", hex::encode(want.0)));
+        }
+        if let Some(allowed) = &self.one_of {
+            let allowed: Vec<String> = allowed
+                .iter()
+                .map(|v| format!("0x{}", hex::encode(v.0)))
+                .collect();
+            parts.push(format!("one of {}", allowed.join(", ")));
+        }
+        describe_parts(parts)
     }
+}
 
-    if let Some(idblock_data) = idblock_data {
-        idblock_data
-            .check(&report).context(InvalidIdBlockSnafu{})?
+///Joins the constraints of a rule
+fn describe_parts(parts: Vec<String>) -> String {
+    if parts.is_empty() {
+        return "any value".to_string();
     }
+    parts.join(" and ")
+}
 
-    if let Some(tcb) = tcb {
-        let got = &report.committed_tcb;
-        if got.bootloader < tcb.bootloader
-            || got.tee < tcb.tee
-            || got.snp < tcb.snp
-            || got.microcode < tcb.microcode
-        {
-            return TcbVersionMismatchSnafu{required_minimum:tcb, got:report.committed_tcb}.fail();
+///Rule for a numeric field like the guest SVN. All set constraints must hold
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct NumberRule {
+    pub exact: Option<u64>,
+    ///The field must have one of these values
+    pub one_of: Option<Vec<u64>>,
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+}
+
+impl NumberRule {
+    pub fn exact(value: u64) -> Self {
+        NumberRule {
+            exact: Some(value),
+            ..Default::default()
         }
     }
 
-    if let Some(pinfo) = plat_info {
-        if report.plat_info.0 != pinfo.0 {
-            return PlatformInfoMismatchSnafu{
-                expected: pinfo,
-                got: report.plat_info,
-            }.fail();
+    pub fn min(value: u64) -> Self {
+        NumberRule {
+            min: Some(value),
+            ..Default::default()
         }
     }
 
-    if let Some(report_data_validator) = report_data_validator {
-        report_data_validator(report.report_data)?;
+    fn validate(&self) -> Result<(), String> {
+        if self.one_of.as_ref().is_some_and(|v| v.is_empty()) {
+            return Err("one_of must not be empty".to_string());
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                return Err(format!("min {} is larger than max {}", min, max));
+            }
+        }
+        Ok(())
     }
 
-    if let Some(host_data) = host_data {
-        if report.host_data != host_data {
-            return HostDataMismatchSnafu{
-                expected: host_data,
-                got: report.host_data,
-            }.fail();
+    fn check(&self, got: u64) -> Result<(), String> {
+        if let Some(want) = self.exact {
+            if want != got {
+                return Err(format!("expected {} got {}", want, got));
+            }
+        }
+        if let Some(allowed) = &self.one_of {
+            if !allowed.contains(&got) {
+                return Err(format!("{} is not one of {:?}", got, allowed));
+            }
         }
+        if let Some(min) = self.min {
+            if got < min {
+                return Err(format!("want at least {} got {}", min, got));
+            }
+        }
+        if let Some(max) = self.max {
+            if got > max {
+                return Err(format!("want at most {} got {}", max, got));
+            }
+        }
+        Ok(())
     }
+}
 
-    if let Some(ld) = ld {
-        if !report.measurement.eq(&ld) {
-            return LaunchDigestMismatchSnafu{
-                expected: ld,
-                got: report.measurement
-            }.fail();
+impl Rule for NumberRule {
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(want) = self.exact {
+            parts.push(want.to_string());
+        }
+        if let Some(allowed) = &self.one_of {
+            parts.push(format!("one of {:?}", allowed));
+        }
+        if let Some(min) = self.min {
+            parts.push(format!(">= {}", min));
         }
+        if let Some(max) = self.max {
+            parts.push(format!("<= {}", max));
+        }
+        describe_parts(parts)
     }
+}
+
+///Rule for a bit field like the guest policy. All set constraints must hold
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlagsRule {
+    ///The whole field must have this value
+    pub exact: Option<u64>,
+    ///Only compare the bits in `mask` against `value`
+    pub mask: Option<u64>,
+    pub value: u64,
+    ///Names of flags that must be set
+    pub required: Vec<String>,
+    ///Names of flags that must not be set
+    pub forbidden: Vec<String>,
+    ///The field must be at least as strict as this value: relaxing flags may only be set if they
+    ///are set here and restricting flags must be set if they are set here. Bits without a name
+    ///must be equal, as it is unknown whether they relax the isolation
+    pub at_least_as_strict_as: Option<u64>,
+}
 
+///Mask with the bits of the flags in `names`
+fn flag_mask(names: &[String], flags: &[Flag]) -> Result<u64, String> {
+    let mut mask = 0;
+    for name in names {
+        match flags.iter().find(|v| v.name == name) {
+            Some(flag) => mask |= flag.mask(),
+            None => {
+                let known: Vec<&str> = flags.iter().map(|v| v.name).collect();
+                return Err(format!(
+                    "unknown flag {}, expected one of {}",
+                    name,
+                    known.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(mask)
+}
+
+///Mask with the bits of the flags of the given kind
+fn kind_mask(kind: FlagKind, flags: &[Flag]) -> u64 {
+    flags
+        .iter()
+        .filter(|v| v.kind == kind)
+        .fold(0, |mask, v| mask | v.mask())
+}
+
+///Names of the flags in `mask`
+fn flag_names(mask: u64, flags: &[Flag]) -> String {
+    let names: Vec<&str> = flags
+        .iter()
+        .filter(|v| mask & v.mask() != 0)
+        .map(|v| v.name)
+        .collect();
+    names.join(", ")
+}
+
+///Which flags are set or missing in `got` compared to `want`. Differing bits without a name are
+///listed as one mask
+fn flag_diff(want: u64, got: u64, flags: &[Flag]) -> String {
+    let named = flags.iter().fold(0, |mask, v| mask | v.mask());
+    let mut parts = Vec::new();
+    if got & !want & named != 0 {
+        parts.push(format!("{} set", flag_names(got & !want, flags)));
+    }
+    if want & !got & named != 0 {
+        parts.push(format!("{} not set", flag_names(want & !got, flags)));
+    }
+    if (want ^ got) & !named != 0 {
+        parts.push(format!("other bits 0x{:x} differ", (want ^ got) & !named));
+    }
+    parts.join(", ")
+}
+
+impl FlagsRule {
+    pub fn exact(value: u64) -> Self {
+        FlagsRule {
+            exact: Some(value),
+            ..Default::default()
+        }
+    }
+
+    ///Accept `value` and every stricter value, see `at_least_as_strict_as`
+    pub fn at_least_as_strict_as(value: u64) -> Self {
+        FlagsRule {
+            at_least_as_strict_as: Some(value),
+            ..Default::default()
+        }
+    }
+
+    fn validate(&self, flags: &[Flag]) -> Result<(), String> {
+        let required = flag_mask(&self.required, flags)?;
+        let forbidden = flag_mask(&self.forbidden, flags)?;
+        if required & forbidden != 0 {
+            return Err(format!(
+                "flags {} are both required and forbidden",
+                flag_names(required & forbidden, flags)
+            ));
+        }
+        if self.value & !self.mask.unwrap_or(0) != 0 {
+            return Err(format!("value 0x{:x} has bits outside of mask", self.value));
+        }
+        Ok(())
+    }
+
+    ///`unchecked` are the bits without a name that `at_least_as_strict_as` does not compare,
+    ///as they are checked by another rule
+    fn check(&self, got: u64, flags: &[Flag], unchecked: u64) -> Result<(), String> {
+        if let Some(want) = self.exact {
+            if want != got {
+                return Err(format!(
+                    "expected 0x{:x} got 0x{:x}: {}",
+                    want,
+                    got,
+                    flag_diff(want, got, flags)
+                ));
+            }
+        }
+        if let Some(mask) = self.mask {
+            if got & mask != self.value {
+                return Err(format!(
+                    "bits 0x{:x} must be 0x{:x}, got 0x{:x}: {}",
+                    mask,
+                    self.value,
+                    got & mask,
+                    flag_diff(self.value, got & mask, flags)
+                ));
+            }
+        }
+        let missing = flag_mask(&self.required, flags)? & !got;
+        if missing != 0 {
+            return Err(format!(
+                "required flags {} are not set in 0x{:x}",
+                flag_names(missing, flags),
+                got
+            ));
+        }
+        let present = flag_mask(&self.forbidden, flags)? & got;
+        if present != 0 {
+            return Err(format!(
+                "forbidden flags {} are set in 0x{:x}",
+                flag_names(present, flags),
+                got
+            ));
+        }
+        if let Some(baseline) = self.at_least_as_strict_as {
+            let relaxed = got & !baseline & kind_mask(FlagKind::Relaxes, flags);
+            if relaxed != 0 {
+                return Err(format!(
+                    "flags {} are set in 0x{:x} but not in 0x{:x}",
+                    flag_names(relaxed, flags),
+                    got,
+                    baseline
+                ));
+            }
+            let dropped = baseline & !got & kind_mask(FlagKind::Restricts, flags);
+            if dropped != 0 {
+                return Err(format!(
+                    "flags {} are set in 0x{:x} but not in 0x{:x}",
+                    flag_names(dropped, flags),
+                    baseline,
+                    got
+                ));
+            }
+            let named = kind_mask(FlagKind::Relaxes, flags) | kind_mask(FlagKind::Restricts, flags);
+            let unknown = (got ^ baseline) & !named & !unchecked;
+            if unknown != 0 {
+                return Err(format!(
+                    "bits 0x{:x} without a name differ between 0x{:x} and 0x{:x}",
+                    unknown, got, baseline
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Rule for FlagsRule {
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(want) = self.exact {
+            parts.push(format!("0x{:x}", want));
+        }
+        if let Some(mask) = self.mask {
+            parts.push(format!("bits 0x{:x} equal to 0x{:x}", mask, self.value));
+        }
+        if !self.required.is_empty() {
+            parts.push(format!("{} set", self.required.join(", ")));
+        }
+        if !self.forbidden.is_empty() {
+            parts.push(format!("{} not set", self.forbidden.join(", ")));
+        }
+        if let Some(baseline) = self.at_least_as_strict_as {
+            parts.push(format!("at least as strict as 0x{:x}", baseline));
+        }
+        describe_parts(parts)
+    }
+}
+
+///Rule for the ABI version in the guest policy
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AbiRule {
+    pub min: Option<AbiVersion>,
+}
+
+impl AbiRule {
+    pub fn min(value: AbiVersion) -> Self {
+        AbiRule { min: Some(value) }
+    }
+
+    fn check(&self, got: AbiVersion) -> Result<(), String> {
+        if let Some(min) = self.min {
+            if got < min {
+                return Err(format!("ABI version {} is below the minimum {}", got, min));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Rule for AbiRule {
+    fn describe(&self) -> String {
+        describe_parts(self.min.iter().map(|v| format!("at least {}", v)).collect())
+    }
+}
+
+impl Rule for bool {
+    fn describe(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Rule for SigningKey {
+    fn describe(&self) -> String {
+        self.to_string()
+    }
+}
+
+fn check_equal<T: PartialEq + Display>(want: T, got: T) -> Result<(), String> {
+    if want != got {
+        return Err(format!("expected {} got {}", want, got));
+    }
     Ok(())
 }
 
-///verify that the signature on the report is valid
-///using the static amd certificate chain for the given product family
-///as well as the chip specific vcek_cert
-/// *DOES NOT* check the data contained in the report
-/// Returns Ok on success
-pub fn verify_report_signature(
+///Check a field that only exists since report version 3
+fn check_cpuid(rule: &NumberRule, got: Option<u8>, version: u32) -> Result<(), String> {
+    match got {
+        Some(got) => rule.check(got.into()),
+        None => Err(format!("report version {} has no CPUID fields", version)),
+    }
+}
+
+///Version per TCB component. Components that are not set are not checked
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcbLevels {
+    ///Only part of the TCB with `TcbLayout::Fmc`
+    pub fmc: Option<u8>,
+    pub bootloader: Option<u8>,
+    pub tee: Option<u8>,
+    pub snp: Option<u8>,
+    pub microcode: Option<u8>,
+}
+
+impl TcbLevels {
+    fn components(&self) -> [(&'static str, Option<u8>); 5] {
+        [
+            ("fmc", self.fmc),
+            ("bootloader", self.bootloader),
+            ("tee", self.tee),
+            ("snp", self.snp),
+            ("microcode", self.microcode),
+        ]
+    }
+}
+
+impl Display for TcbLevels {
+    ///Lists the components that are set, e.g. "bootloader 3, snp 20"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let set: Vec<String> = self
+            .components()
+            .iter()
+            .filter_map(|(name, v)| v.map(|v| format!("{} {}", name, v)))
+            .collect();
+        write!(f, "{}", set.join(", "))
+    }
+}
+
+///Interprets `value` in the layout of Milan and Genoa, as used by the vm config
+impl From<TcbVersion> for TcbLevels {
+    fn from(value: TcbVersion) -> Self {
+        TcbLayout::Legacy.decode(&value)
+    }
+}
+
+///Layout of the 8 byte TCB version, which differs between CPU generations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcbLayout {
+    ///Milan and Genoa: bootloader, tee, 4 reserved bytes, snp, microcode
+    Legacy,
+    ///Turin and later: fmc, bootloader, tee, snp, 3 reserved bytes, microcode. Not used by any
+    ///supported `ProductName` yet
+    Fmc,
+}
+
+impl TcbLayout {
+    pub fn for_product(product_name: ProductName) -> Self {
+        match product_name {
+            ProductName::Milan | ProductName::Genoa => TcbLayout::Legacy,
+        }
+    }
+
+    ///Components of `tcb`. The sev crate always parses the legacy layout, so other layouts are
+    ///decoded from the raw bytes
+    pub fn decode(&self, tcb: &TcbVersion) -> TcbLevels {
+        match self {
+            TcbLayout::Legacy => TcbLevels {
+                fmc: None,
+                bootloader: Some(tcb.bootloader),
+                tee: Some(tcb.tee),
+                snp: Some(tcb.snp),
+                microcode: Some(tcb.microcode),
+            },
+            TcbLayout::Fmc => {
+                let raw = bincode::serialize(tcb).expect("TcbVersion is plain bytes");
+                TcbLevels {
+                    fmc: Some(raw[0]),
+                    bootloader: Some(raw[1]),
+                    tee: Some(raw[2]),
+                    snp: Some(raw[3]),
+                    microcode: Some(raw[7]),
+                }
+            }
+        }
+    }
+}
+
+///Check that every component of `got` is at least the version in `min`
+fn check_min_levels(
+    min: &TcbLevels,
+    got: &TcbLevels,
     product_name: ProductName,
+) -> Result<(), String> {
+    for ((name, want), (_, got)) in min.components().into_iter().zip(got.components()) {
+        match (want, got) {
+            (Some(want), Some(got)) if got < want => {
+                return Err(format!(
+                    "{} version {} is below the minimum {}",
+                    name, got, want
+                ))
+            }
+            (Some(_), None) => {
+                return Err(format!(
+                    "{} is not part of the TCB on {}",
+                    name, product_name
+                ))
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+///Rule for one of the TCB versions in the report
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcbRule {
+    ///Minimum version per component
+    pub min: Option<TcbLevels>,
+    ///Additional minimum versions that only apply on the given CPU generation
+    pub min_for: BTreeMap<ProductName, TcbLevels>,
+}
+
+impl TcbRule {
+    pub fn min(version: TcbVersion) -> Self {
+        TcbRule {
+            min: Some(version.into()),
+            ..Default::default()
+        }
+    }
+
+    fn check(
+        &self,
+        got: &TcbVersion,
+        layout: TcbLayout,
+        product_name: ProductName,
+    ) -> Result<(), String> {
+        let got = layout.decode(got);
+        if let Some(min) = &self.min {
+            check_min_levels(min, &got, product_name)?;
+        }
+        if let Some(min) = self.min_for.get(&product_name) {
+            check_min_levels(min, &got, product_name)?;
+        }
+        Ok(())
+    }
+}
+
+impl Rule for TcbRule {
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(min) = &self.min {
+            parts.push(format!("at least {}", min));
+        }
+        for (product_name, min) in &self.min_for {
+            parts.push(format!("at least {} on {}", min, product_name));
+        }
+        describe_parts(parts)
+    }
+}
+
+///The four TCB versions in the report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcbKind {
+    Committed,
+    Current,
+    Launch,
+    Reported,
+}
+
+impl TcbKind {
+    fn name(&self) -> &'static str {
+        match self {
+            TcbKind::Committed => "committed",
+            TcbKind::Current => "current",
+            TcbKind::Launch => "launch",
+            TcbKind::Reported => "reported",
+        }
+    }
+
+    fn get<'a>(&self, report: &'a AttestationReport) -> &'a TcbVersion {
+        match self {
+            TcbKind::Committed => &report.committed_tcb,
+            TcbKind::Current => &report.current_tcb,
+            TcbKind::Launch => &report.launch_tcb,
+            TcbKind::Reported => &report.reported_tcb,
+        }
+    }
+}
+
+impl FromStr for TcbKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "committed" => Ok(TcbKind::Committed),
+            "current" => Ok(TcbKind::Current),
+            "launch" => Ok(TcbKind::Launch),
+            "reported" => Ok(TcbKind::Reported),
+            _ => Err(format!(
+                "unknown TCB {}, expected committed, current, launch or reported",
+                s
+            )),
+        }
+    }
+}
+
+///Comparison operator of a `TcbRelation`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcbOperator {
+    Equal,
+    AtMost,
+    AtLeast,
+}
+
+impl TcbOperator {
+    fn symbol(&self) -> &'static str {
+        match self {
+            TcbOperator::Equal => "==",
+            TcbOperator::AtMost => "<=",
+            TcbOperator::AtLeast => ">=",
+        }
+    }
+
+    fn holds(&self, left: Option<u8>, right: Option<u8>) -> bool {
+        match self {
+            TcbOperator::Equal => left == right,
+            TcbOperator::AtMost => left <= right,
+            TcbOperator::AtLeast => left >= right,
+        }
+    }
+}
+
+///Relation between two TCB versions of the report, e.g. "committed == reported" or
+///"current >= committed". The operator must hold for every component
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct TcbRelation {
+    pub left: TcbKind,
+    pub op: TcbOperator,
+    pub right: TcbKind,
+}
+
+impl FromStr for TcbRelation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let (left, op, right) = match parts[..] {
+            [left, op, right] => (left, op, right),
+            _ => {
+                return Err(format!(
+                    "expected a relation like \"committed == reported\", got \"{}\"",
+                    s
+                ))
+            }
+        };
+        let op = match op {
+            "==" => TcbOperator::Equal,
+            "<=" => TcbOperator::AtMost,
+            ">=" => TcbOperator::AtLeast,
+            _ => return Err(format!("unknown operator {}, expected ==, <= or >=", op)),
+        };
+        Ok(TcbRelation {
+            left: left.parse()?,
+            op,
+            right: right.parse()?,
+        })
+    }
+}
+
+impl TryFrom<String> for TcbRelation {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for TcbRelation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.left.name(),
+            self.op.symbol(),
+            self.right.name()
+        )
+    }
+}
+
+impl From<TcbRelation> for String {
+    fn from(value: TcbRelation) -> Self {
+        value.to_string()
+    }
+}
+
+impl TcbRelation {
+    fn check(&self, report: &AttestationReport, product_name: ProductName) -> Result<(), String> {
+        let layout = TcbLayout::for_product(product_name);
+        let left = layout.decode(self.left.get(report));
+        let right = layout.decode(self.right.get(report));
+        for ((name, l), (_, r)) in left.components().into_iter().zip(right.components()) {
+            if !self.op.holds(l, r) {
+                return Err(format!(
+                    "{} TCB has {} {} but {} TCB has {} {}",
+                    self.left.name(),
+                    name,
+                    l.unwrap_or_default(),
+                    self.right.name(),
+                    name,
+                    r.unwrap_or_default()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+///Acceptance criteria for the fields of an attestation report, usually loaded from a TOML file
+///(see `examples/verification-policy.toml`). Fields without a rule are not checked.
+///The report data is checked separately, as it depends on the session
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerificationPolicy {
+    ///Launch digest
+    pub measurement: Option<BytesRule<HexBytes<48>>>,
+    ///Data that was passed as HOST_DATA to QEMU during VM launch
+    pub host_data: Option<BytesRule<HexBytes<32>>>,
+    pub family_id: Option<BytesRule<HexBytes<IDBLOCK_ID_BYTES>>>,
+    pub image_id: Option<BytesRule<HexBytes<IDBLOCK_ID_BYTES>>>,
+    ///Digest of the key that signed the id block
+    pub id_key_digest: Option<BytesRule<HexBytes<48>>>,
+    ///Digest of the key that signed the id key
+    pub author_key_digest: Option<BytesRule<HexBytes<48>>>,
+    pub chip_id: Option<BytesRule<HexBytes<64>>>,
+    ///Whether the platform zeroes the chip id (MASK_CHIP_ID), i.e. whether `chip_id` is all zero
+    pub mask_chip_id: Option<bool>,
+    ///Random id of the guest, unique per launch
+    pub report_id: Option<BytesRule<HexBytes<32>>>,
+    ///Report id of the migration agent, all 0xff if the guest has none
+    pub report_id_ma: Option<BytesRule<HexBytes<32>>>,
+    pub guest_svn: Option<NumberRule>,
+    ///Layout version of the report
+    pub version: Option<NumberRule>,
+    ///VMPL from which the guest requested the report
+    pub vmpl: Option<NumberRule>,
+    ///Signature algorithm, see `SIG_ALGO_ECDSA_P384_SHA384`
+    pub sig_algo: Option<NumberRule>,
+    pub signing_key: Option<SigningKey>,
+    ///Whether the id block was signed with an author key
+    pub author_key_en: Option<bool>,
+    ///Whether the platform masks the chip key. Reports are not signed in this case
+    pub mask_chip_key: Option<bool>,
+    ///CPUID of the host. Only reports since version 3 contain it, so these rules fail for
+    ///older reports
+    pub cpuid_family: Option<NumberRule>,
+    pub cpuid_model: Option<NumberRule>,
+    pub cpuid_stepping: Option<NumberRule>,
+    ///Flags are named as in `GUEST_POLICY_FLAGS`
+    pub guest_policy: Option<FlagsRule>,
+    ///Minimum firmware ABI version that the guest policy demands
+    pub guest_abi: Option<AbiRule>,
+    ///Flags are named as in `PLATFORM_INFO_FLAGS`
+    pub platform_info: Option<FlagsRule>,
+    ///Rollback protected TCB, the platform cannot go below this version
+    pub committed_tcb: Option<TcbRule>,
+    ///TCB that the platform is currently running
+    pub current_tcb: Option<TcbRule>,
+    ///TCB at the time the VM was launched
+    pub launch_tcb: Option<TcbRule>,
+    ///TCB that was used to derive the VCEK that signed the report
+    pub reported_tcb: Option<TcbRule>,
+    ///Relations that must hold between the four TCB versions
+    pub tcb_consistency: Option<Vec<TcbRelation>>,
+}
+
+///Outcome of a single check, see `evaluate_report`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    ///Checked report field, or "report data" and "signature"
+    pub check: &'static str,
+    ///Accepted values, if the check has a description for them
+    pub expected: Option<String>,
+    ///Value in the report
+    pub got: Option<String>,
+    ///Why the check failed, `None` if it passed
+    pub failure: Option<String>,
+}
+
+///Outcome of all checks of a report
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct VerificationSummary {
+    pub checks: Vec<CheckResult>,
+}
+
+impl VerificationSummary {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|v| v.failure.is_none())
+    }
+
+    pub fn passes(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks.iter().filter(|v| v.failure.is_none())
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks.iter().filter(|v| v.failure.is_some())
+    }
+
+    ///Fails with `ReportVerificationError::ChecksFailed` unless all checks passed
+    pub fn into_result(self) -> Result<(), ReportVerificationError> {
+        ensure!(self.passed(), ChecksFailedSnafu { summary: self });
+        Ok(())
+    }
+}
+
+impl Display for VerificationSummary {
+    ///One line per check
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for v in &self.checks {
+            match &v.failure {
+                None => writeln!(f, "PASS {}", v.check)?,
+                Some(reason) => writeln!(f, "FAIL {}: {}", v.check, reason)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+///Apply `check` to the rule, if it is set. `got` is the value of the field in the report
+fn evaluate_rule<R: Rule>(
+    field: &'static str,
+    rule: &Option<R>,
+    got: impl Display,
+    check: impl FnOnce(&R) -> Result<(), String>,
+) -> Option<CheckResult> {
+    rule.as_ref().map(|rule| CheckResult {
+        check: field,
+        expected: Some(rule.describe()),
+        got: Some(got.to_string()),
+        failure: check(rule).err(),
+    })
+}
+
+fn hex_string(raw: &[u8]) -> String {
+    format!("0x{}", hex::encode(raw))
+}
+
+fn tcb_string(tcb: &TcbVersion, layout: TcbLayout) -> String {
+    layout.decode(tcb).to_string()
+}
+
+impl VerificationPolicy {
+    ///Parse and validate a policy file
+    pub fn load(path: &Path) -> Result<Self, Whatever> {
+        let policy: VerificationPolicy =
+            toml::from_str(&fs::read_to_string(path).whatever_context(format!(
+                "failed to read verification policy from {}",
+                path.display()
+            ))?)
+            .whatever_context("failed to parse verification policy as toml")?;
+        policy
+            .validate()
+            .whatever_context(format!("invalid verification policy {}", path.display()))?;
+        Ok(policy)
+    }
+
+    ///Checks of the report format that every report should pass: a known report version and
+    ///signature algorithm, a report signed by an unmasked VCEK and requested from VMPL 0
+    pub fn report_defaults() -> Self {
+        VerificationPolicy {
+            version: Some(NumberRule::min(MIN_REPORT_VERSION.into())),
+            vmpl: Some(NumberRule::exact(0)),
+            sig_algo: Some(NumberRule::exact(SIG_ALGO_ECDSA_P384_SHA384.into())),
+            signing_key: Some(SigningKey::Vcek),
+            mask_chip_key: Some(false),
+            ..Default::default()
+        }
+    }
+
+    ///Guest policy and ABI that are at least as strict as `guest_policy`, and no migration agent
+    ///unless `guest_policy` allows one. Neither is part of the launch digest
+    pub fn for_guest_policy(guest_policy: u64) -> Self {
+        let migrate_ma_allowed = GUEST_POLICY_FLAGS
+            .iter()
+            .any(|v| v.name == "migrate_ma_allowed" && guest_policy & v.mask() != 0);
+        VerificationPolicy {
+            guest_policy: Some(FlagsRule::at_least_as_strict_as(guest_policy)),
+            guest_abi: Some(AbiRule::min(AbiVersion::of_policy(guest_policy))),
+            report_id_ma: (!migrate_ma_allowed).then(|| BytesRule::exact([0xff; 32])),
+            ..Default::default()
+        }
+    }
+
+    ///The checks implied by a vm config: exact launch digest, guest policy and platform info that
+    ///are at least as strict as the configured ones, a minimum committed TCB and guest svn, the
+    ///VMPL of the vm config, no id block and no migration agent unless the guest policy allows
+    ///one. Includes `report_defaults`. Fails if the vm config names unknown platform info flags
+    pub fn from_vm_description(
+        vm_description: &VMDescription,
+        expected_ld: [u8; 48],
+    ) -> Result<Self, Whatever> {
+        let policy = VerificationPolicy {
+            measurement: Some(BytesRule::exact(expected_ld)),
+            platform_info: Some(FlagsRule {
+                required: vm_description.platform_info_required.clone(),
+                forbidden: vm_description.platform_info_forbidden.clone(),
+                ..FlagsRule::at_least_as_strict_as(vm_description.platform_info.0)
+            }),
+            committed_tcb: Some(TcbRule::min(vm_description.min_commited_tcb)),
+            guest_svn: Some(NumberRule::min(vm_description.min_guest_svn.into())),
+            vmpl: Some(NumberRule::exact(vm_description.vmpl.into())),
+            author_key_en: Some(false),
+            ..Default::default()
+        }
+        .or(VerificationPolicy::for_guest_policy(
+            vm_description.guest_policy.0,
+        ))
+        .or(VerificationPolicy::report_defaults());
+        policy.validate().whatever_context("invalid vm config")?;
+        Ok(policy)
+    }
+
+    ///Additionally require the values from the id block. The report only contains the digest of
+    ///the author key if the author key is enabled, so this also requires `author_key_en`
+    pub fn with_id_block(mut self, id_block_data: &IDBLockReportData) -> Self {
+        self.guest_svn = Some(NumberRule::exact(id_block_data.guest_svn.into()));
+        self.family_id = Some(BytesRule::exact(id_block_data.f_id));
+        self.image_id = Some(BytesRule::exact(id_block_data.i_id));
+        self.id_key_digest = Some(BytesRule::exact(id_block_data.id_key_digest));
+        self.author_key_digest = Some(BytesRule::exact(id_block_data.author_key_digest));
+        self.author_key_en = Some(true);
+        self
+    }
+
+    ///Additionally require HOST_DATA to be `host_data`, e.g. the owner public key for VMs that use
+    ///`OwnerKeySource::HostData`
+    pub fn with_host_data(mut self, host_data: [u8; 32]) -> Self {
+        self.host_data = Some(BytesRule::exact(host_data));
+        self
+    }
+
+    ///Use the rules of `self` and fall back to the rules of `defaults` for fields that `self`
+    ///does not cover
+    pub fn or(self, defaults: VerificationPolicy) -> Self {
+        VerificationPolicy {
+            measurement: self.measurement.or(defaults.measurement),
+            host_data: self.host_data.or(defaults.host_data),
+            family_id: self.family_id.or(defaults.family_id),
+            image_id: self.image_id.or(defaults.image_id),
+            id_key_digest: self.id_key_digest.or(defaults.id_key_digest),
+            author_key_digest: self.author_key_digest.or(defaults.author_key_digest),
+            chip_id: self.chip_id.or(defaults.chip_id),
+            mask_chip_id: self.mask_chip_id.or(defaults.mask_chip_id),
+            report_id: self.report_id.or(defaults.report_id),
+            report_id_ma: self.report_id_ma.or(defaults.report_id_ma),
+            guest_svn: self.guest_svn.or(defaults.guest_svn),
+            version: self.version.or(defaults.version),
+            vmpl: self.vmpl.or(defaults.vmpl),
+            sig_algo: self.sig_algo.or(defaults.sig_algo),
+            signing_key: self.signing_key.or(defaults.signing_key),
+            author_key_en: self.author_key_en.or(defaults.author_key_en),
+            mask_chip_key: self.mask_chip_key.or(defaults.mask_chip_key),
+            cpuid_family: self.cpuid_family.or(defaults.cpuid_family),
+            cpuid_model: self.cpuid_model.or(defaults.cpuid_model),
+            cpuid_stepping: self.cpuid_stepping.or(defaults.cpuid_stepping),
+            guest_policy: self.guest_policy.or(defaults.guest_policy),
+            guest_abi: self.guest_abi.or(defaults.guest_abi),
+            platform_info: self.platform_info.or(defaults.platform_info),
+            committed_tcb: self.committed_tcb.or(defaults.committed_tcb),
+            current_tcb: self.current_tcb.or(defaults.current_tcb),
+            launch_tcb: self.launch_tcb.or(defaults.launch_tcb),
+            reported_tcb: self.reported_tcb.or(defaults.reported_tcb),
+            tcb_consistency: self.tcb_consistency.or(defaults.tcb_consistency),
+        }
+    }
+
+    ///Check that the rules are consistent, e.g. that all flag names are known
+    pub fn validate(&self) -> Result<(), Whatever> {
+        let results = [
+            (
+                "measurement",
+                self.measurement.as_ref().map(|v| v.validate()),
+            ),
+            ("host_data", self.host_data.as_ref().map(|v| v.validate())),
+            ("family_id", self.family_id.as_ref().map(|v| v.validate())),
+            ("image_id", self.image_id.as_ref().map(|v| v.validate())),
+            (
+                "id_key_digest",
+                self.id_key_digest.as_ref().map(|v| v.validate()),
+            ),
+            (
+                "author_key_digest",
+                self.author_key_digest.as_ref().map(|v| v.validate()),
+            ),
+            ("chip_id", self.chip_id.as_ref().map(|v| v.validate())),
+            ("report_id", self.report_id.as_ref().map(|v| v.validate())),
+            (
+                "report_id_ma",
+                self.report_id_ma.as_ref().map(|v| v.validate()),
+            ),
+            ("guest_svn", self.guest_svn.as_ref().map(|v| v.validate())),
+            ("version", self.version.as_ref().map(|v| v.validate())),
+            ("vmpl", self.vmpl.as_ref().map(|v| v.validate())),
+            ("sig_algo", self.sig_algo.as_ref().map(|v| v.validate())),
+            (
+                "cpuid_family",
+                self.cpuid_family.as_ref().map(|v| v.validate()),
+            ),
+            (
+                "cpuid_model",
+                self.cpuid_model.as_ref().map(|v| v.validate()),
+            ),
+            (
+                "cpuid_stepping",
+                self.cpuid_stepping.as_ref().map(|v| v.validate()),
+            ),
+            (
+                "guest_policy",
+                self.guest_policy
+                    .as_ref()
+                    .map(|v| v.validate(GUEST_POLICY_FLAGS)),
+            ),
+            (
+                "platform_info",
+                self.platform_info
+                    .as_ref()
+                    .map(|v| v.validate(PLATFORM_INFO_FLAGS)),
+            ),
+        ];
+        for (field, result) in results {
+            if let Some(Err(e)) = result {
+                whatever!("invalid rule for {}: {}", field, e);
+            }
+        }
+        Ok(())
+    }
+
+    ///Check all rules against `report`, without stopping at the first failure. Rules that are
+    ///not set are left out. *DOES NOT* check the report signature
+    pub fn evaluate(
+        &self,
+        report: &AttestationReport,
+        product_name: ProductName,
+    ) -> Vec<CheckResult> {
+        let policy = report.policy.0;
+        let plat_info = report.plat_info.0;
+        let details = ReportDetails::new(report);
+        let chip_id_masked = report.chip_id == [0; 64];
+        let layout = TcbLayout::for_product(product_name);
+        let cpuid = |v: Option<u8>| v.map_or("not reported".to_string(), |v| format!("0x{:x}", v));
+        //The TCB layout and the VCEK depend on the CPU generation, so a report from another
+        //generation than the configured one must not be decoded as if it were from that one
+        let generation = details.cpuid_family.map(|family| CheckResult {
+            check: "CPU generation",
+            expected: Some(format!(
+                "CPUID family 0x{:x} ({})",
+                product_name.cpuid_family(),
+                product_name
+            )),
+            got: Some(cpuid(Some(family))),
+            failure: (family != product_name.cpuid_family()).then(|| {
+                format!(
+                    "report comes from a CPU of family 0x{:x}, but the host is configured as {}",
+                    family, product_name
+                )
+            }),
+        });
+        [
+            generation,
+            evaluate_rule("report version", &self.version, report.version, |v| {
+                v.check(report.version.into())
+            }),
+            evaluate_rule("vmpl", &self.vmpl, report.vmpl, |v| {
+                v.check(report.vmpl.into())
+            }),
+            evaluate_rule(
+                "signature algorithm",
+                &self.sig_algo,
+                report.sig_algo,
+                |v| v.check(report.sig_algo.into()),
+            ),
+            evaluate_rule("signing key", &self.signing_key, details.signing_key, |v| {
+                check_equal(*v, details.signing_key)
+            }),
+            evaluate_rule(
+                "mask chip key",
+                &self.mask_chip_key,
+                details.mask_chip_key,
+                |v| check_equal(*v, details.mask_chip_key),
+            ),
+            evaluate_rule(
+                "guest policy",
+                &self.guest_policy,
+                format!("0x{:x}", policy),
+                |v| v.check(policy, GUEST_POLICY_FLAGS, GUEST_POLICY_ABI_MASK),
+            ),
+            evaluate_rule(
+                "guest ABI",
+                &self.guest_abi,
+                AbiVersion::of_policy(policy),
+                |v| v.check(AbiVersion::of_policy(policy)),
+            ),
+            evaluate_rule("guest svn", &self.guest_svn, report.guest_svn, |v| {
+                v.check(report.guest_svn.into())
+            }),
+            evaluate_rule(
+                "family id",
+                &self.family_id,
+                hex_string(&report.family_id),
+                |v| v.check(&report.family_id),
+            ),
+            evaluate_rule(
+                "image id",
+                &self.image_id,
+                hex_string(&report.image_id),
+                |v| v.check(&report.image_id),
+            ),
+            evaluate_rule(
+                "id key digest",
+                &self.id_key_digest,
+                hex_string(&report.id_key_digest),
+                |v| v.check(&report.id_key_digest),
+            ),
+            evaluate_rule(
+                "author key digest",
+                &self.author_key_digest,
+                hex_string(&report.author_key_digest),
+                |v| v.check(&report.author_key_digest),
+            ),
+            evaluate_rule(
+                "author key enabled",
+                &self.author_key_en,
+                details.author_key_en,
+                |v| check_equal(*v, details.author_key_en),
+            ),
+            evaluate_rule(
+                "committed TCB",
+                &self.committed_tcb,
+                tcb_string(&report.committed_tcb, layout),
+                |v| v.check(&report.committed_tcb, layout, product_name),
+            ),
+            evaluate_rule(
+                "current TCB",
+                &self.current_tcb,
+                tcb_string(&report.current_tcb, layout),
+                |v| v.check(&report.current_tcb, layout, product_name),
+            ),
+            evaluate_rule(
+                "launch TCB",
+                &self.launch_tcb,
+                tcb_string(&report.launch_tcb, layout),
+                |v| v.check(&report.launch_tcb, layout, product_name),
+            ),
+            evaluate_rule(
+                "reported TCB",
+                &self.reported_tcb,
+                tcb_string(&report.reported_tcb, layout),
+                |v| v.check(&report.reported_tcb, layout, product_name),
+            ),
+            evaluate_rule(
+                "platform info",
+                &self.platform_info,
+                format!("0x{:x}", plat_info),
+                |v| v.check(plat_info, PLATFORM_INFO_FLAGS, 0),
+            ),
+            evaluate_rule("chip id", &self.chip_id, hex_string(&report.chip_id), |v| {
+                v.check(&report.chip_id)
+            }),
+            evaluate_rule("mask chip id", &self.mask_chip_id, chip_id_masked, |v| {
+                check_equal(*v, chip_id_masked)
+            }),
+            evaluate_rule(
+                "CPUID family",
+                &self.cpuid_family,
+                cpuid(details.cpuid_family),
+                |v| check_cpuid(v, details.cpuid_family, report.version),
+            ),
+            evaluate_rule(
+                "CPUID model",
+                &self.cpuid_model,
+                cpuid(details.cpuid_model),
+                |v| check_cpuid(v, details.cpuid_model, report.version),
+            ),
+            evaluate_rule(
+                "CPUID stepping",
+                &self.cpuid_stepping,
+                cpuid(details.cpuid_stepping),
+                |v| check_cpuid(v, details.cpuid_stepping, report.version),
+            ),
+            evaluate_rule(
+                "host data",
+                &self.host_data,
+                hex_string(&report.host_data),
+                |v| v.check(&report.host_data),
+            ),
+            evaluate_rule(
+                "report id",
+                &self.report_id,
+                hex_string(&report.report_id),
+                |v| v.check(&report.report_id),
+            ),
+            evaluate_rule(
+                "migration agent report id",
+                &self.report_id_ma,
+                hex_string(&report.report_id_ma),
+                |v| v.check(&report.report_id_ma),
+            ),
+            evaluate_rule(
+                "launch digest",
+                &self.measurement,
+                hex_string(&report.measurement),
+                |v| v.check(&report.measurement),
+            ),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(
+            self.tcb_consistency
+                .iter()
+                .flatten()
+                .map(|relation| CheckResult {
+                    check: "TCB consistency",
+                    expected: Some(relation.to_string()),
+                    got: Some(format!(
+                        "{} TCB {}, {} TCB {}",
+                        relation.left.name(),
+                        tcb_string(relation.left.get(report), layout),
+                        relation.right.name(),
+                        tcb_string(relation.right.get(report), layout)
+                    )),
+                    failure: relation.check(report, product_name).err(),
+                }),
+        )
+        .collect()
+    }
+
+    ///Check all rules against `report` and fail on the first violation. *DOES NOT* check the
+    ///report signature
+    pub fn check(
+        &self,
+        report: &AttestationReport,
+        product_name: ProductName,
+    ) -> Result<(), ReportVerificationError> {
+        match self
+            .evaluate(report, product_name)
+            .into_iter()
+            .find(|v| v.failure.is_some())
+        {
+            Some(CheckResult {
+                check,
+                failure: Some(reason),
+                ..
+            }) => PolicyViolationSnafu {
+                field: check,
+                reason,
+            }
+            .fail(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Ensures that the report satisfies `policy` and that the report data is valid.
+/// *DOES NOT* check the report signature
+/// # Arguments
+/// - `report` : The report that we want to check
+/// - `product_name` : CPU generation of the host, determines the layout of the TCB versions
+/// - `policy` : Acceptance criteria for the report fields. Use e.g. `VerificationPolicy::from_vm_description` to derive them from the vm config
+/// - `report_data_validator` : Function that checks if the report data is valid. The report data is guest defined data provided when requesting the attestation report. We currently use it to return a nonce send by the guest owner as well as the public DH key generated by the VM at runtime
+pub fn check_report_data<F>(
     report: &AttestationReport,
-    vcek_cert: Certificate,
-) -> Result<(), Whatever> {
+    product_name: ProductName,
+    policy: &VerificationPolicy,
+    report_data_validator: Option<F>,
+) -> Result<(), ReportVerificationError>
+where
+    F: Fn([u8; 64]) -> Result<(), ReportVerificationError>,
+{
+    policy.check(report, product_name)?;
+
+    if let Some(report_data_validator) = report_data_validator {
+        report_data_validator(report.report_data)?;
+    }
+
+    Ok(())
+}
+
+///The static AMD root and signing key certificates for the given product family
+pub fn amd_ca_chain(product_name: ProductName) -> Result<ca::Chain, Whatever> {
     let ark;
     let ask;
     match product_name {
@@ -379,78 +1779,58 @@ pub fn verify_report_signature(
             ask = genoa::ask().whatever_context("failed to parse ASK certificate")?;
         }
     }
+    Ok(ca::Chain { ark, ask })
+}
 
-    let ca = ca::Chain { ark, ask };
+///verify that the signature on the report is valid
+///using the static amd certificate chain for the given product family
+///as well as the chip specific vcek_cert
+/// *DOES NOT* check the data contained in the report
+/// Returns Ok on success
+pub fn verify_report_signature(
+    product_name: ProductName,
+    report: &AttestationReport,
+    vcek_cert: Certificate,
+) -> Result<(), Whatever> {
+    let ca = amd_ca_chain(product_name)?;
 
     let chain = Chain { ca, vek: vcek_cert };
 
     (&chain, report)
-        .verify().whatever_context("invalid attestation report signature")?;
+        .verify()
+        .whatever_context("invalid attestation report signature")?;
     Ok(())
 }
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub))]
 pub enum ReportVerificationError {
     #[snafu(display("Invalid attestation report signature"))]
-    InvalidSignature{source: Whatever},
+    InvalidSignature { source: Whatever },
 
-    #[snafu(display("Invalid policy, expected {:x?} got {:x?}",expected,got))]
-    PolicyMismatch{
-        expected: GuestPolicy,
-        got: GuestPolicy
+    #[snafu(display("Invalid {}: {}", field, reason))]
+    PolicyViolation {
+        ///Report field that violates the policy
+        field: &'static str,
+        reason: String,
     },
 
-    #[snafu(display("Invalid ID block data : {}", source.to_string()))]
-    InvalidIdBlock{
-        source: Whatever
-    },
-
-    #[snafu(display("TCB version does not match mininum required version, want at least {:x?} but got {:x?}", required_minimum, got))]
-    TcbVersionMismatch{
-        required_minimum: TcbVersion,
-        got: TcbVersion,
-    },
-
-    #[snafu(display("Invalid PlatformInfo, expected {} got {}", expected, got))]
-    PlatformInfoMismatch{
-        expected: PlatformInfo,
-        got: PlatformInfo,
-    },
-
-    #[snafu(display("Invalid HostData, expected 0x{} got 0x{}", hex::encode(expected), hex::encode(got)))]
-    HostDataMismatch{
-        expected: [u8; 32],
-        got: [u8; 32],
-    },
-
-    #[snafu(display("Invalid launch digest, expected 0x{} got 0x{}", hex::encode(expected), hex::encode(got)))]
-    LaunchDigestMismatch{
-        expected: [u8; 48],
-        got: [u8; 48],
-    },
-
-    #[snafu(display("Invalid report data, expected {} got {}",expected, got))]
-    ReportDataMismatch{
-        expected: String,
-        got: String,
-    }
+    #[snafu(display("{} of {} checks failed: {}", summary.failures().count(), summary.checks.len(),
+        summary.failures().map(|v| format!("{}: {}", v.check, v.failure.as_deref().unwrap_or_default())).collect::<Vec<_>>().join("; ")))]
+    ChecksFailed { summary: VerificationSummary },
 
+    #[snafu(display("Invalid report data, expected {} got {}", expected, got))]
+    ReportDataMismatch { expected: String, got: String },
 }
 
-///Verify the report signature and check that the given data fields match
-///See `verify_report_signature` and `check_report_data` for additiona
+///Verify the report signature and check that the report satisfies `policy`
+///See `verify_report_signature` and `check_report_data` for additional
 ///documentation
 pub fn verify_and_check_report<F>(
     report: &AttestationReport,
     product_name: ProductName,
     vcek_cert: Certificate,
-    idblock_data: Option<IDBLockReportData>,
-    policy: Option<GuestPolicy>,
-    tcb: Option<TcbVersion>,
-    plat_info: Option<PlatformInfo>,
+    policy: &VerificationPolicy,
     report_data_validator: Option<F>,
-    host_data: Option<[u8; 32]>,
-    ld: Option<[u8; 48]>,
 ) -> Result<(), ReportVerificationError>
 where
     F: Fn([u8; 64]) -> Result<(), ReportVerificationError>,
@@ -458,30 +1838,67 @@ where
     //checking the data before checking the signature makes it easier to find the root-cause for errors.
     //If we check the signature first, it could be invalid because of mismatching data or because
     //of an actually invalid signature/signature key
-    check_report_data(
-        report,
-        idblock_data,
-        policy,
-        tcb,
-        plat_info,
-        report_data_validator,
-        host_data,
-        ld,
-    )?;
-    verify_report_signature(product_name, report, vcek_cert).context(InvalidSignatureSnafu{})
+    check_report_data(report, product_name, policy, report_data_validator)?;
+    verify_report_signature(product_name, report, vcek_cert).context(InvalidSignatureSnafu {})
+}
+
+///Like `verify_and_check_report`, but evaluates every check, including the signature, instead
+///of stopping at the first failure. Use `VerificationSummary::into_result` to turn the outcome
+///into an error
+pub fn evaluate_report<F>(
+    report: &AttestationReport,
+    product_name: ProductName,
+    vcek_cert: Certificate,
+    policy: &VerificationPolicy,
+    report_data_validator: Option<F>,
+) -> VerificationSummary
+where
+    F: Fn([u8; 64]) -> Result<(), ReportVerificationError>,
+{
+    let mut checks = policy.evaluate(report, product_name);
+    if let Some(report_data_validator) = report_data_validator {
+        checks.push(CheckResult {
+            check: "report data",
+            expected: None,
+            got: Some(hex_string(&report.report_data)),
+            failure: report_data_validator(report.report_data)
+                .err()
+                .map(|e| e.to_string()),
+        });
+    }
+    checks.push(CheckResult {
+        check: "signature",
+        expected: Some(format!("signed by the VCEK of a {} CPU", product_name)),
+        got: None,
+        failure: verify_report_signature(product_name, report, vcek_cert)
+            .err()
+            .map(|e| e.to_string()),
+    });
+    VerificationSummary { checks }
 }
 
 #[cfg(test)]
 mod tests {
     use std::{fs::File, io::Read};
 
-    use sev::{certs::snp::Certificate, firmware::guest::AttestationReport};
+    use sev::{
+        certs::snp::Certificate,
+        firmware::{
+            guest::{AttestationReport, GuestPolicy, PlatformInfo},
+            host::TcbVersion,
+        },
+    };
     use snafu::{ResultExt, Whatever};
 
-    use crate::snp_validate_report::{verify_report_signature, ProductName};
+    use crate::calc_expected_ld::VMDescription;
+    use crate::snp_validate_report::{
+        evaluate_report, verify_report_signature, AbiRule, FlagsRule, ProductName, ReportDetails,
+        ReportVerificationError, SigningKey, TcbLayout, VerificationPolicy, GUEST_POLICY_ABI_MASK,
+        GUEST_POLICY_FLAGS,
+    };
 
-    const TEST_REPORT_PATH: &'static str = "./test-data/benign-report.json";
-    const TEST_VCEK_CERT_PATH: &'static str = "./test-data/vcek.crt";
+    const TEST_REPORT_PATH: &str = "./test-data/benign-report.json";
+    const TEST_VCEK_CERT_PATH: &str = "./test-data/vcek.crt";
 
     ///helper function that loads the testdata attestation report
     fn load_report() -> Result<AttestationReport, Whatever> {
@@ -508,19 +1925,361 @@ mod tests {
     //     Ok(())
     // }
 
-    #[test]
-    fn test_verify() -> Result<(), Whatever> {
-        let report = load_report()?;
+    ///helper function that loads the testdata VCEK certificate
+    fn load_cert() -> Result<Certificate, Whatever> {
         let mut cert_file =
             File::open(TEST_VCEK_CERT_PATH).whatever_context("failed to open test cert file")?;
         let mut cert_bytes = Vec::new();
         cert_file
             .read_to_end(&mut cert_bytes)
             .whatever_context("failed to read test cert files")?;
-        let cert =
-            Certificate::from_bytes(&cert_bytes).whatever_context("failed to parse test cert")?;
-        verify_report_signature(ProductName::Milan, &report, cert)?;
+        Certificate::from_bytes(&cert_bytes).whatever_context("failed to parse test cert")
+    }
+
+    #[test]
+    fn test_verify() -> Result<(), Whatever> {
+        let report = load_report()?;
+        verify_report_signature(ProductName::Milan, &report, load_cert()?)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verification_policy() -> Result<(), Whatever> {
+        let policy: VerificationPolicy = toml::from_str(
+            r#"
+            guest_svn = { max = 0 }
+            guest_policy = { required = ["smt_allowed"], forbidden = ["debug_allowed"] }
+            platform_info = { mask = 0x1, value = 0x1 }
+            committed_tcb.min = { snp = 20, microcode = 209 }
+            host_data.one_of = ["0x0000000000000000000000000000000000000000000000000000000000000000"]
+            "#,
+        )
+        .whatever_context("failed to parse policy")?;
+        policy.validate()?;
+        let mut report = load_report()?;
+        policy
+            .check(&report, ProductName::Milan)
+            .whatever_context("benign report must satisfy the policy")?;
+
+        report.policy = GuestPolicy(report.policy.0 | 1 << 19);
+        match policy.check(&report, ProductName::Milan) {
+            Err(ReportVerificationError::PolicyViolation { field, .. }) => {
+                assert_eq!(field, "guest policy")
+            }
+            other => panic!("debug policy must be rejected, got {:?}", other.err()),
+        }
+
+        let unknown: VerificationPolicy = toml::from_str("guest_policy = { required = [\"smt\"] }")
+            .whatever_context("failed to parse policy")?;
+        assert!(
+            unknown.validate().is_err(),
+            "unknown flag names must be rejected"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_guest_policy_rule() -> Result<(), Whatever> {
+        let mut report = load_report()?;
+        let launched = report.policy.0;
+        let policy = VerificationPolicy {
+            guest_policy: Some(FlagsRule::at_least_as_strict_as(launched | 1 << 20)),
+            guest_abi: Some(AbiRule::min("0.0".parse().unwrap())),
+            ..Default::default()
+        };
+        let reason = policy
+            .check(&report, ProductName::Milan)
+            .unwrap_err()
+            .to_string();
+        assert!(reason.contains("single_socket_required"), "{}", reason);
+
+        let policy = VerificationPolicy {
+            guest_policy: Some(FlagsRule::at_least_as_strict_as(launched)),
+            ..policy
+        };
+        report.policy = GuestPolicy(launched & !(1 << 16) | 1 << 20);
+        policy
+            .check(&report, ProductName::Milan)
+            .whatever_context("stricter guest policy must be accepted")?;
+
+        report.policy = GuestPolicy(launched | 1 << 19);
+        let reason = policy
+            .check(&report, ProductName::Milan)
+            .unwrap_err()
+            .to_string();
+        assert!(reason.contains("debug_allowed"), "{}", reason);
+
+        //unknown bits may relax the isolation, the ABI bits are checked by guest_abi
+        report.policy = GuestPolicy(launched | 1 << 25);
+        let reason = policy
+            .check(&report, ProductName::Milan)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            reason.contains("bits 0x2000000 without a name differ"),
+            "{}",
+            reason
+        );
+        report.policy = GuestPolicy(launched | 1 << 8 | 51);
+        policy
+            .check(&report, ProductName::Milan)
+            .whatever_context("higher ABI must be accepted")?;
+
+        report.policy = GuestPolicy(launched | 1 << 8 | 51);
+        let policy: VerificationPolicy = toml::from_str("guest_abi = { min = \"1.52\" }")
+            .whatever_context("failed to parse policy")?;
+        let reason = policy
+            .check(&report, ProductName::Milan)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            reason.contains("1.51 is below the minimum 1.52"),
+            "{}",
+            reason
+        );
+
+        let reason = FlagsRule::exact(launched)
+            .check(
+                launched & !(1 << 16) | 1,
+                GUEST_POLICY_FLAGS,
+                GUEST_POLICY_ABI_MASK,
+            )
+            .unwrap_err();
+        assert_eq!(
+            reason,
+            "expected 0x30000 got 0x20001: smt_allowed not set, other bits 0x1 differ"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_platform_info_rule() -> Result<(), Whatever> {
+        let vm_description = VMDescription {
+            platform_info: PlatformInfo(0x1),
+            platform_info_forbidden: vec!["ciphertext_hiding_enabled".to_string()],
+            ..Default::default()
+        };
+        let policy = VerificationPolicy::from_vm_description(&vm_description, [0; 48])?;
+        let mut report = load_report()?;
+        let mut platform_info_failure = |plat_info| {
+            report.plat_info = PlatformInfo(plat_info);
+            let results = policy.evaluate(&report, ProductName::Milan);
+            results
+                .into_iter()
+                .find(|v| v.check == "platform info")
+                .unwrap()
+                .failure
+        };
+        assert_eq!(
+            platform_info_failure(0x1 | 1 << 2 | 1 << 5),
+            None,
+            "hardened host must be accepted"
+        );
+        assert_eq!(
+            platform_info_failure(0x0),
+            None,
+            "host without SMT must be accepted"
+        );
+        let reason = platform_info_failure(0x1 | 1 << 4).unwrap();
+        assert!(reason.contains("ciphertext_hiding_enabled"), "{}", reason);
+        let reason = platform_info_failure(0x1 | 1 << 7).unwrap();
+        assert!(
+            reason.contains("bits 0x80 without a name differ"),
+            "{}",
+            reason
+        );
+
+        let vm_description = VMDescription {
+            platform_info_required: vec!["alias_check".to_string()],
+            ..Default::default()
+        };
+        assert!(VerificationPolicy::from_vm_description(&vm_description, [0; 48]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_details() -> Result<(), Whatever> {
+        let report = load_report()?;
+        let details = ReportDetails::new(&report);
+        assert!(!details.author_key_en && !details.mask_chip_key);
+        assert_eq!(details.signing_key, SigningKey::Vcek);
+        assert_eq!(
+            details.cpuid_family, None,
+            "version 2 reports have no CPUID"
+        );
+
+        let mut raw = bincode::serialize(&report).whatever_context("failed to serialize report")?;
+        raw[0] = 3;
+        raw[0x48] = 1 | 1 << 2;
+        raw[0x188] = 0x19;
+        let v3: AttestationReport =
+            bincode::deserialize(&raw).whatever_context("failed to deserialize report")?;
+        let details = ReportDetails::new(&v3);
+        assert!(details.author_key_en);
+        assert_eq!(details.signing_key, SigningKey::Vlek);
+        assert_eq!(details.cpuid_family, Some(0x19));
+
+        let vm_description = VMDescription {
+            guest_policy: report.policy,
+            platform_info: report.plat_info,
+            ..Default::default()
+        };
+        let failures = |vm_description: &VMDescription,
+                        report: &AttestationReport|
+         -> Result<Vec<&str>, Whatever> {
+            let policy =
+                VerificationPolicy::from_vm_description(vm_description, report.measurement)?;
+            Ok(policy
+                .evaluate(report, ProductName::Milan)
+                .into_iter()
+                .filter(|v| v.failure.is_some())
+                .map(|v| v.check)
+                .collect())
+        };
+        assert_eq!(
+            failures(&vm_description, &report)?,
+            ["vmpl"],
+            "the benign report was requested from VMPL 1"
+        );
+        assert_eq!(
+            failures(&vm_description, &v3)?,
+            ["vmpl", "signing key", "author key enabled"]
+        );
+
+        let old_initramfs = VMDescription {
+            vmpl: 1,
+            ..vm_description
+        };
+        assert!(
+            failures(&old_initramfs, &report)?.is_empty(),
+            "vmpl = 1 accepts reports of old servers"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_evaluate_all_checks() -> Result<(), Whatever> {
+        let policy: VerificationPolicy = toml::from_str(
+            r#"
+            guest_policy = { forbidden = ["smt_allowed"] }
+            platform_info = { required = ["tsme_enabled"] }
+            committed_tcb.min = { snp = 20 }
+            "#,
+        )
+        .whatever_context("failed to parse policy")?;
+        let mut report = load_report()?;
+        report.guest_svn = 1;
+        let summary = evaluate_report(
+            &report,
+            ProductName::Milan,
+            load_cert()?,
+            &policy,
+            Some(|_| Ok(())),
+        );
+        let failures: Vec<&str> = summary.failures().map(|v| v.check).collect();
+        assert_eq!(failures, ["guest policy", "platform info", "signature"]);
+        let passes: Vec<&str> = summary.passes().map(|v| v.check).collect();
+        assert_eq!(passes, ["committed TCB", "report data"]);
+        assert!(matches!(
+            summary.into_result(),
+            Err(ReportVerificationError::ChecksFailed { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tcb_policy() -> Result<(), Whatever> {
+        let policy: VerificationPolicy = toml::from_str(
+            r#"
+            tcb_consistency = ["committed == reported", "current >= committed"]
+            launch_tcb.min = { snp = 20 }
+            reported_tcb.min_for.Genoa = { microcode = 250 }
+            "#,
+        )
+        .whatever_context("failed to parse policy")?;
+        let mut report = load_report()?;
+        policy
+            .check(&report, ProductName::Milan)
+            .whatever_context("benign report must satisfy the policy")?;
+        assert!(
+            policy.check(&report, ProductName::Genoa).is_err(),
+            "Genoa rule must apply"
+        );
+
+        report.committed_tcb = TcbVersion::new(3, 0, 21, 209);
+        let failures: Vec<String> = policy
+            .evaluate(&report, ProductName::Milan)
+            .into_iter()
+            .filter_map(|v| v.failure)
+            .collect();
+        assert_eq!(
+            failures,
+            [
+                "committed TCB has snp 21 but reported TCB has snp 20",
+                "current TCB has snp 20 but committed TCB has snp 21"
+            ]
+        );
+
+        let fmc: VerificationPolicy = toml::from_str("current_tcb.min = { fmc = 1 }")
+            .whatever_context("failed to parse policy")?;
+        assert!(
+            fmc.check(&report, ProductName::Milan).is_err(),
+            "Milan has no fmc component"
+        );
+        let levels = TcbLayout::Fmc.decode(&TcbVersion::new(1, 2, 3, 4));
+        assert_eq!(
+            (levels.fmc, levels.bootloader, levels.microcode),
+            (Some(1), Some(2), Some(4))
+        );
+
+        assert!(toml::from_str::<VerificationPolicy>(
+            "tcb_consistency = [\"committed = reported\"]"
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cpu_generation() -> Result<(), Whatever> {
+        let report = load_report()?;
+        let cert = load_cert()?;
+        let policy = VerificationPolicy::report_defaults();
+        let checks = |report: &AttestationReport, product_name: ProductName| -> Vec<(&str, bool)> {
+            evaluate_report(
+                report,
+                product_name,
+                cert.clone(),
+                &policy,
+                None::<fn([u8; 64]) -> _>,
+            )
+            .checks
+            .into_iter()
+            .map(|v| (v.check, v.failure.is_none()))
+            .collect()
+        };
+        assert!(
+            !checks(&report, ProductName::Milan)
+                .iter()
+                .any(|v| v.0 == "CPU generation"),
+            "version 2 reports have no CPUID"
+        );
+
+        //Version 3 report of a Turin host (family 0x1a, model 0x02). Its TCB uses another layout
+        //and its VCEK is not issued for Genoa, so it must not be evaluated as a Genoa report
+        let mut raw = bincode::serialize(&report).whatever_context("failed to serialize report")?;
+        raw[0] = 3;
+        raw[0x188..0x18b].copy_from_slice(&[0x1a, 0x02, 0x01]);
+        let turin: AttestationReport =
+            bincode::deserialize(&raw).whatever_context("failed to deserialize report")?;
+        assert!(checks(&turin, ProductName::Genoa).contains(&("CPU generation", false)));
+        let err = policy.check(&turin, ProductName::Genoa).unwrap_err();
+        assert!(err.to_string().contains("family 0x1a"), "{}", err);
 
+        raw[0x188] = 0x19;
+        let milan: AttestationReport =
+            bincode::deserialize(&raw).whatever_context("failed to deserialize report")?;
+        assert!(checks(&milan, ProductName::Milan).contains(&("CPU generation", true)));
         Ok(())
     }
 }
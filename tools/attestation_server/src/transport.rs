@@ -0,0 +1,796 @@
+//! Transports for the attestation protocol.
+//!
+//! The protocol messages are independent of the way they are exchanged. Besides HTTP(S), we
+//! support AF_VSOCK, which allows host-local attestation without any guest networking.
+//! `ClientTransport` sends requests, `ServerTransport` receives them, so that the protocol
+//! logic on both ends is transport independent.
+//! On vsock, each connection carries exactly one request and one response, both framed as
+//! a big endian `u32` length followed by the JSON serialized `Envelope`
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, Cursor, Read, Write},
+    mem,
+    net::{SocketAddr, ToSocketAddrs},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use reqwest::{blocking::Client, Url};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sev::firmware::guest::AttestationReport;
+use snafu::{whatever, ResultExt, Whatever};
+use tiny_http::{ConfigListenAddr, Method, Request, Response, Server, ServerConfig, SslConfig};
+
+use crate::req_resp_ds::{
+    enabled_versions, Capability, Envelope, ProtocolError, ProtocolErrorKind, RequestMessage,
+    ResponseMessage, PROTOCOL_PATH, PROTOCOL_VERSION,
+};
+
+///URL scheme that selects the vsock transport on the client, e.g. `vsock://3:8080`
+pub const VSOCK_SCHEME: &str = "vsock";
+
+///Prefix that marks a listen address as a unix domain socket path
+const UNIX_PREFIX: &str = "unix:";
+///Prefix that marks a listen address as vsock `[<cid>:]<port>`
+const VSOCK_PREFIX: &str = "vsock:";
+
+///Upper bound for a single framed message. Prevents memory exhaustion by a malicious peer
+pub const MAX_FRAME_LEN: u32 = 1 << 20;
+
+///Write `value` as length prefixed JSON
+pub fn write_frame<W: Write, T: Serialize>(w: &mut W, value: &T) -> Result<(), Whatever> {
+    let json = serde_json::to_vec(value).whatever_context("failed to serialize message")?;
+    let len: u32 = match json.len().try_into() {
+        Ok(v) if v <= MAX_FRAME_LEN => v,
+        _ => whatever!("message of {} bytes exceeds frame limit", json.len()),
+    };
+    w.write_all(&len.to_be_bytes())
+        .whatever_context("failed to write frame length")?;
+    w.write_all(&json)
+        .whatever_context("failed to write frame content")?;
+    w.flush().whatever_context("failed to flush frame")
+}
+
+///Read a length prefixed JSON value
+pub fn read_frame<R: Read, T: DeserializeOwned>(r: &mut R) -> Result<T, Whatever> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)
+        .whatever_context("failed to read frame length")?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        whatever!("frame length {} exceeds limit {}", len, MAX_FRAME_LEN);
+    }
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf)
+        .whatever_context("failed to read frame content")?;
+    serde_json::from_slice(&buf).whatever_context("failed to parse frame content as json")
+}
+
+///Address of an AF_VSOCK socket. The string representation is `<cid>:<port>` or just `<port>`,
+///which uses `VMADDR_CID_ANY` as cid
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct VsockAddr {
+    pub cid: u32,
+    pub port: u32,
+}
+
+impl VsockAddr {
+    fn to_sockaddr(self) -> libc::sockaddr_vm {
+        //SAFETY: sockaddr_vm is a plain C struct, all zero is a valid value
+        let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        addr.svm_cid = self.cid;
+        addr.svm_port = self.port;
+        addr
+    }
+}
+
+impl FromStr for VsockAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cid, port) = match s.split_once(':') {
+            Some((cid, port)) => (
+                cid.parse()
+                    .map_err(|e| format!("invalid vsock cid {} : {}", cid, e))?,
+                port,
+            ),
+            None => (libc::VMADDR_CID_ANY, s),
+        };
+        let port = port
+            .parse()
+            .map_err(|e| format!("invalid vsock port {} : {}", port, e))?;
+        Ok(VsockAddr { cid, port })
+    }
+}
+
+impl TryFrom<String> for VsockAddr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<VsockAddr> for String {
+    fn from(value: VsockAddr) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for VsockAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.cid, self.port)
+    }
+}
+
+fn vsock_socket() -> io::Result<OwnedFd> {
+    //SAFETY: plain syscall without pointer arguments
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    //SAFETY: fd is a freshly created socket that we exclusively own
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+///Connected AF_VSOCK stream socket
+pub struct VsockStream {
+    inner: File,
+}
+
+impl VsockStream {
+    pub fn connect(addr: VsockAddr) -> Result<Self, Whatever> {
+        let fd = vsock_socket().whatever_context("failed to create vsock socket")?;
+        let sockaddr = addr.to_sockaddr();
+        //SAFETY: sockaddr is valid for the duration of the call and the length matches
+        let ret = unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                &sockaddr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error())
+                .whatever_context(format!("failed to connect to vsock {}", addr));
+        }
+        Ok(VsockStream {
+            inner: File::from(fd),
+        })
+    }
+
+    ///Set the read and write timeout of the socket. Blocking operations that exceed the
+    ///timeout fail with `WouldBlock`
+    pub fn set_timeout(&self, timeout: Duration) -> Result<(), Whatever> {
+        let tv = libc::timeval {
+            tv_sec: timeout.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+            tv_usec: timeout.subsec_micros().into(),
+        };
+        for opt in [libc::SO_RCVTIMEO, libc::SO_SNDTIMEO] {
+            //SAFETY: tv is valid for the duration of the call and the length matches
+            let ret = unsafe {
+                libc::setsockopt(
+                    self.inner.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    opt,
+                    &tv as *const libc::timeval as *const libc::c_void,
+                    mem::size_of::<libc::timeval>() as libc::socklen_t,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error())
+                    .whatever_context("failed to set vsock timeout");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+///Listening AF_VSOCK socket
+pub struct VsockListener {
+    fd: OwnedFd,
+}
+
+impl VsockListener {
+    pub fn bind(addr: VsockAddr) -> Result<Self, Whatever> {
+        let fd = vsock_socket().whatever_context("failed to create vsock socket")?;
+        let sockaddr = addr.to_sockaddr();
+        //SAFETY: sockaddr is valid for the duration of the call and the length matches
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &sockaddr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error())
+                .whatever_context(format!("failed to bind vsock {}", addr));
+        }
+        //SAFETY: plain syscall without pointer arguments
+        if unsafe { libc::listen(fd.as_raw_fd(), 16) } < 0 {
+            return Err(io::Error::last_os_error())
+                .whatever_context(format!("failed to listen on vsock {}", addr));
+        }
+        Ok(VsockListener { fd })
+    }
+
+    ///Wait for the next connection. Returns `Ok(None)` if `deadline` passed before a client connected
+    pub fn accept(&self, deadline: Option<Instant>) -> Result<Option<VsockStream>, Whatever> {
+        let timeout_ms: libc::c_int = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                //round up, so that we do not busy loop shortly before the deadline
+                remaining
+                    .as_millis()
+                    .saturating_add(1)
+                    .try_into()
+                    .unwrap_or(libc::c_int::MAX)
+            }
+            None => -1,
+        };
+        let mut pollfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        //SAFETY: pollfd is valid for the duration of the call and we pass exactly one entry
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(None);
+            }
+            return Err(err).whatever_context("failed to wait for vsock connection");
+        }
+        if ready == 0 {
+            return Ok(None);
+        }
+        //SAFETY: we do not want to learn the peer address, thus passing null is fine
+        let fd = unsafe {
+            libc::accept4(
+                self.fd.as_raw_fd(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+                .whatever_context("failed to accept vsock connection");
+        }
+        //SAFETY: fd is a freshly accepted socket that we exclusively own
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(Some(VsockStream {
+            inner: File::from(fd),
+        }))
+    }
+}
+
+///Client side of a transport: sends a request and waits for the response
+pub trait ClientTransport {
+    fn call(
+        &self,
+        request: &Envelope<RequestMessage>,
+    ) -> Result<Envelope<ResponseMessage>, Whatever>;
+}
+
+///Protocol messages as JSON via HTTP(S) POST requests
+pub struct HttpClientTransport {
+    client: Client,
+    endpoint: Url,
+}
+
+impl HttpClientTransport {
+    pub fn new(client: Client, server_url: &Url) -> Result<Self, Whatever> {
+        let endpoint = server_url
+            .join(PROTOCOL_PATH)
+            .whatever_context("failed to assemble protocol endpoint URL")?;
+        Ok(HttpClientTransport { client, endpoint })
+    }
+}
+
+impl ClientTransport for HttpClientTransport {
+    fn call(
+        &self,
+        request: &Envelope<RequestMessage>,
+    ) -> Result<Envelope<ResponseMessage>, Whatever> {
+        self.client
+            .post(self.endpoint.clone())
+            .json(request)
+            .send()
+            .whatever_context(format!("failed to send request to {}", self.endpoint))?
+            .json()
+            .whatever_context("failed to parse server response as json")
+    }
+}
+
+///Protocol messages as length prefixed JSON via AF_VSOCK. Uses a new connection for every request
+pub struct VsockClientTransport {
+    addr: VsockAddr,
+}
+
+impl VsockClientTransport {
+    pub fn new(addr: VsockAddr) -> Self {
+        VsockClientTransport { addr }
+    }
+
+    ///Parse a `vsock://<cid>:<port>` URL
+    pub fn from_url(url: &Url) -> Result<Self, Whatever> {
+        if url.scheme() != VSOCK_SCHEME {
+            whatever!("expected {}:// url, got {}", VSOCK_SCHEME, url);
+        }
+        let (cid, port) = match (url.host_str(), url.port()) {
+            (Some(cid), Some(port)) => (cid, port),
+            _ => whatever!("vsock url {} must have the form vsock://<cid>:<port>", url),
+        };
+        let cid = cid
+            .parse()
+            .whatever_context(format!("invalid vsock cid {}", cid))?;
+        Ok(VsockClientTransport::new(VsockAddr {
+            cid,
+            port: port.into(),
+        }))
+    }
+}
+
+impl ClientTransport for VsockClientTransport {
+    fn call(
+        &self,
+        request: &Envelope<RequestMessage>,
+    ) -> Result<Envelope<ResponseMessage>, Whatever> {
+        let mut stream = VsockStream::connect(self.addr)?;
+        write_frame(&mut stream, request).whatever_context("failed to send request")?;
+        read_frame(&mut stream).whatever_context("failed to receive response")
+    }
+}
+
+///Protocol session with a peer, using the negotiated protocol version
+pub struct Connection {
+    transport: Box<dyn ClientTransport>,
+    version: u32,
+    ///Capabilities announced by the server
+    capabilities: Vec<Capability>,
+}
+
+impl Connection {
+    ///Query the peer info, negotiate a protocol version of at least `min_version` and ensure that
+    ///the peer offers the `required` capability
+    pub fn connect(
+        transport: Box<dyn ClientTransport>,
+        required: Capability,
+        min_version: u32,
+    ) -> Result<Self, Whatever> {
+        let mut conn = Connection {
+            transport,
+            version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        };
+        let info = match conn.call(RequestMessage::Info)? {
+            ResponseMessage::Info(v) => v,
+            other => whatever!("unexpected response to info request: {:?}", other),
+        };
+        conn.version = match info.negotiate_version(min_version) {
+            Some(v) => v,
+            None => whatever!(
+                "server supports protocol versions {:?}, but we only accept {:?}",
+                info.supported_versions,
+                enabled_versions(min_version)
+            ),
+        };
+        conn.capabilities = info.capabilities;
+        conn.require(required)?;
+        Ok(conn)
+    }
+
+    ///Negotiated protocol version
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    ///Returns an error if the server does not offer the `required` capability
+    pub fn require(&self, required: Capability) -> Result<(), Whatever> {
+        if !self.capabilities.contains(&required) {
+            whatever!(
+                "server does not offer {:?}, available capabilities: {:?}",
+                required,
+                self.capabilities
+            );
+        }
+        Ok(())
+    }
+
+    ///Send a message and return the response. Error responses are converted into errors
+    pub fn call(&self, message: RequestMessage) -> Result<ResponseMessage, Whatever> {
+        let resp = self.transport.call(&Envelope::new(self.version, message))?;
+        match resp.message {
+            ResponseMessage::Error(e) => whatever!("server returned error: {}", e),
+            v => Ok(v),
+        }
+    }
+}
+
+///Address the server listens on
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum ListenAddr {
+    ///IPv4 or IPv6 socket address
+    Tcp(SocketAddr),
+    ///Path of a unix domain socket
+    Unix(PathBuf),
+    ///AF_VSOCK address. Only reachable from the host, does not require guest networking
+    Vsock(VsockAddr),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            return Ok(ListenAddr::Unix(path.into()));
+        }
+        if let Some(addr) = s.strip_prefix(VSOCK_PREFIX) {
+            return Ok(ListenAddr::Vsock(addr.parse()?));
+        }
+        match s.to_socket_addrs() {
+            Ok(mut addrs) => match addrs.next() {
+                Some(v) => Ok(ListenAddr::Tcp(v)),
+                None => Err(format!(
+                    "listen address {} did not resolve to any address",
+                    s
+                )),
+            },
+            Err(e) => Err(format!("invalid listen address {} : {}", s, e)),
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ListenAddr> for String {
+    fn from(value: ListenAddr) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+            ListenAddr::Vsock(addr) => write!(f, "{}{}", VSOCK_PREFIX, addr),
+        }
+    }
+}
+
+impl ListenAddr {
+    ///Address for the HTTP server. None if this address requires a different transport
+    pub fn http_addr(&self) -> Option<ConfigListenAddr> {
+        match self {
+            ListenAddr::Tcp(addr) => Some(ConfigListenAddr::IP(vec![*addr])),
+            ListenAddr::Unix(path) => Some(ConfigListenAddr::unix_from_path(path)),
+            ListenAddr::Vsock(_) => None,
+        }
+    }
+
+    ///Start serving protocol messages on this address. `ssl` and `ra_tls_report` are only
+    ///supported for HTTP, see `HttpServerTransport`
+    pub fn bind(
+        &self,
+        ssl: Option<SslConfig>,
+        ra_tls_report: Option<AttestationReport>,
+    ) -> Result<Box<dyn ServerTransport>, Whatever> {
+        match self {
+            ListenAddr::Vsock(addr) => {
+                if ssl.is_some() {
+                    whatever!("TLS is not supported via vsock");
+                }
+                Ok(Box::new(VsockServerTransport::new(VsockListener::bind(
+                    *addr,
+                )?)))
+            }
+            _ => {
+                let addr = self.http_addr().expect("not a vsock address");
+                let server = match Server::new(ServerConfig { addr, ssl }) {
+                    Ok(v) => v,
+                    Err(e) => whatever!("failed to start http server on {}: {:#?}", self, e),
+                };
+                Ok(Box::new(HttpServerTransport::new(server, ra_tls_report)))
+            }
+        }
+    }
+}
+
+///Protocol message received from a client, together with the means to answer it
+pub struct Incoming {
+    ///The parsed request or the error that should be sent back if parsing failed
+    pub request: Result<Envelope<RequestMessage>, ProtocolError>,
+    pub responder: Responder,
+}
+
+type ReplyFn = dyn FnOnce(&Envelope<ResponseMessage>);
+
+///Sends the response for a single request
+pub struct Responder(Box<ReplyFn>);
+
+impl Responder {
+    ///Send `response` to the client. Errors are only logged, failing to respond to a single
+    ///client should not stop the server
+    pub fn send(self, response: &Envelope<ResponseMessage>) {
+        (self.0)(response)
+    }
+}
+
+///Server side of a transport: receives requests and sends the responses
+pub trait ServerTransport {
+    ///Wait for the next protocol message. Returns None if `deadline` passed before a message arrived
+    fn next_message(&mut self, deadline: Option<Instant>) -> Option<Incoming>;
+}
+
+///Response for requests that could not be parsed or that were sent to the wrong place
+fn error_envelope(
+    kind: ProtocolErrorKind,
+    message: impl Into<String>,
+) -> Envelope<ResponseMessage> {
+    Envelope::new(
+        PROTOCOL_VERSION,
+        ResponseMessage::Error(ProtocolError::new(kind, message)),
+    )
+}
+
+///Protocol messages via HTTP(S) POST requests to `PROTOCOL_PATH`
+pub struct HttpServerTransport {
+    server: Server,
+    ///Report embedded in our TLS certificate. Only set in RA-TLS mode
+    ra_tls_report: Option<AttestationReport>,
+}
+
+impl HttpServerTransport {
+    pub fn new(server: Server, ra_tls_report: Option<AttestationReport>) -> Self {
+        HttpServerTransport {
+            server,
+            ra_tls_report,
+        }
+    }
+
+    ///Wait for the next HTTP request. Returns None if `deadline` passed before a request arrived
+    fn wait_for_request(&self, deadline: Option<Instant>) -> Option<Request> {
+        loop {
+            let result = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.server.recv_timeout(deadline - now)
+                }
+                None => self.server.recv().map(Some),
+            };
+            match result {
+                Ok(Some(rq)) => return Some(rq),
+                Ok(None) => (),
+                Err(e) => {
+                    eprintln!("error receiving request: {}", e);
+                }
+            };
+        }
+    }
+}
+
+impl ServerTransport for HttpServerTransport {
+    fn next_message(&mut self, deadline: Option<Instant>) -> Option<Incoming> {
+        loop {
+            let mut req = self.wait_for_request(deadline)?;
+            //The report is also part of the certificate, we just offer it here for convenience
+            if *req.method() == Method::Get {
+                if let Some(report) = &self.ra_tls_report {
+                    match json_response(report) {
+                        Ok(resp) => respond(req, resp),
+                        Err(e) => eprintln!("Error serializing report: {:#?}", e),
+                    }
+                    continue;
+                }
+            }
+            if req.url() != PROTOCOL_PATH || *req.method() != Method::Post {
+                respond_envelope(
+                    req,
+                    &error_envelope(
+                        ProtocolErrorKind::UnknownEndpoint,
+                        format!(
+                            "unknown endpoint, send protocol messages via POST to {}",
+                            PROTOCOL_PATH
+                        ),
+                    ),
+                );
+                continue;
+            }
+            let content_length = req.body_length();
+            let request = read_body(req.as_reader(), content_length);
+            return Some(Incoming {
+                request,
+                responder: Responder(Box::new(move |resp| respond_envelope(req, resp))),
+            });
+        }
+    }
+}
+
+///Parse an HTTP request body as JSON. Like frames, bodies are limited to `MAX_FRAME_LEN` bytes,
+///also if the client does not send a Content-Length
+fn read_body<R: Read, T: DeserializeOwned>(
+    r: R,
+    content_length: Option<usize>,
+) -> Result<T, ProtocolError> {
+    let too_large = || {
+        ProtocolError::new(
+            ProtocolErrorKind::MalformedRequest,
+            format!("request body exceeds limit {}", MAX_FRAME_LEN),
+        )
+    };
+    if content_length.unwrap_or(0) > MAX_FRAME_LEN as usize {
+        return Err(too_large());
+    }
+    let mut buf = Vec::new();
+    r.take(u64::from(MAX_FRAME_LEN) + 1)
+        .read_to_end(&mut buf)
+        .map_err(|e| ProtocolError::new(ProtocolErrorKind::MalformedRequest, e.to_string()))?;
+    if buf.len() > MAX_FRAME_LEN as usize {
+        return Err(too_large());
+    }
+    serde_json::from_slice(&buf)
+        .map_err(|e| ProtocolError::new(ProtocolErrorKind::MalformedRequest, e.to_string()))
+}
+
+///Send a response and log errors. Failing to respond to a single client should not stop the server
+fn respond<R: Read>(req: Request, resp: Response<R>) {
+    if let Err(e) = req.respond(resp) {
+        eprintln!("Error sending response: {:#?}", e);
+    }
+}
+
+fn respond_envelope(req: Request, resp: &Envelope<ResponseMessage>) {
+    let status = match &resp.message {
+        ResponseMessage::Error(e) => e.kind.http_status(),
+        _ => 200,
+    };
+    match json_response(resp) {
+        Ok(v) => respond(req, v.with_status_code(status)),
+        Err(e) => eprintln!("Error serializing response: {:#?}", e),
+    }
+}
+
+fn json_response<T: Serialize>(value: &T) -> Result<Response<Cursor<Vec<u8>>>, Whatever> {
+    let json =
+        serde_json::to_string(value).whatever_context("failed to serialize response as json")?;
+    let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .expect("should never happen");
+    Ok(Response::from_string(json).with_header(header))
+}
+
+///Time a vsock client may take to send its request or receive our response. As we serve
+///one connection at a time, this prevents a single client from blocking the server
+const VSOCK_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+///Protocol messages as length prefixed JSON via AF_VSOCK, one message per connection
+pub struct VsockServerTransport {
+    listener: VsockListener,
+}
+
+impl VsockServerTransport {
+    pub fn new(listener: VsockListener) -> Self {
+        VsockServerTransport { listener }
+    }
+}
+
+impl ServerTransport for VsockServerTransport {
+    fn next_message(&mut self, deadline: Option<Instant>) -> Option<Incoming> {
+        loop {
+            if deadline.is_some_and(|v| Instant::now() >= v) {
+                return None;
+            }
+            let mut stream: VsockStream = match self.listener.accept(deadline) {
+                Ok(Some(v)) => v,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("error accepting vsock connection: {:#?}", e);
+                    continue;
+                }
+            };
+            println!("Got vsock connection");
+            if let Err(e) = stream.set_timeout(VSOCK_CONNECTION_TIMEOUT) {
+                eprintln!("Error configuring vsock connection: {:#?}", e);
+                continue;
+            }
+            let request = read_frame::<_, Envelope<RequestMessage>>(&mut stream).map_err(|e| {
+                ProtocolError::new(ProtocolErrorKind::MalformedRequest, e.to_string())
+            });
+            return Some(Incoming {
+                request,
+                responder: Responder(Box::new(move |resp| {
+                    if let Err(e) = write_frame(&mut stream, resp) {
+                        eprintln!("Error sending response: {:#?}", e);
+                    }
+                })),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{read_body, read_frame, write_frame, VsockAddr, MAX_FRAME_LEN};
+    use crate::req_resp_ds::{Envelope, RequestMessage};
+
+    #[test]
+    fn frame_roundtrip() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &Envelope::new(1, RequestMessage::Info)).unwrap();
+        let got: Envelope<RequestMessage> = read_frame(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(got.version, 1);
+        assert!(matches!(got.message, RequestMessage::Info));
+
+        let mut oversized = (MAX_FRAME_LEN + 1).to_be_bytes().to_vec();
+        oversized.extend_from_slice(b"{}");
+        assert!(read_frame::<_, serde_json::Value>(&mut Cursor::new(&oversized)).is_err());
+    }
+
+    #[test]
+    fn body_limit() {
+        let body = serde_json::to_vec(&Envelope::new(1, RequestMessage::Info)).unwrap();
+        let got: Envelope<RequestMessage> = read_body(&body[..], Some(body.len())).unwrap();
+        assert!(matches!(got.message, RequestMessage::Info));
+
+        let large = MAX_FRAME_LEN as usize + 1;
+        assert!(read_body::<_, serde_json::Value>(&body[..], Some(large)).is_err());
+        //chunked bodies have no Content-Length
+        let mut oversized = b"[".to_vec();
+        oversized.resize(large, b' ');
+        oversized.push(b']');
+        let err = read_body::<_, serde_json::Value>(&oversized[..], None).unwrap_err();
+        assert!(err.message.contains("exceeds limit"), "{}", err.message);
+    }
+
+    #[test]
+    fn parse_vsock_addr() {
+        assert_eq!(
+            "3:8080".parse::<VsockAddr>().unwrap(),
+            VsockAddr { cid: 3, port: 8080 }
+        );
+        assert_eq!(
+            "8080".parse::<VsockAddr>().unwrap(),
+            VsockAddr {
+                cid: libc::VMADDR_CID_ANY,
+                port: 8080
+            }
+        );
+        assert!("x:1".parse::<VsockAddr>().is_err());
+    }
+}
@@ -0,0 +1,473 @@
+//! Machine readable outcome of a report verification, for CI and deployment pipelines.
+//!
+//! A `Verdict` contains the decoded report, every check with expected and actual value, the
+//! certificate chain and the final result. It is written as JSON or as a JUnit test suite. The
+//! JSON layout is a stable interface: new fields may be added, but existing fields only change
+//! together with `VERDICT_FORMAT_VERSION`
+use std::{fmt::Display, fs, path::Path, str::FromStr};
+
+use openssl::{hash::MessageDigest, x509::X509NameRef};
+use serde::Serialize;
+use sev::{certs::snp::Certificate, firmware::guest::AttestationReport};
+use snafu::{ResultExt, Whatever};
+
+use crate::snp_validate_report::{
+    amd_ca_chain, AbiVersion, CheckResult, Flag, HexBytes, ProductName, ReportDetails, TcbLayout,
+    TcbLevels, VerificationSummary, GUEST_POLICY_FLAGS, PLATFORM_INFO_FLAGS,
+};
+
+///Version of the JSON layout of `Verdict`
+pub const VERDICT_FORMAT_VERSION: u32 = 1;
+
+///Output format of a verdict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerdictFormat {
+    #[default]
+    Json,
+    ///One test case per check
+    Junit,
+}
+
+impl FromStr for VerdictFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(VerdictFormat::Json),
+            "junit" => Ok(VerdictFormat::Junit),
+            _ => Err(format!(
+                "unknown verdict format {}, expected json or junit",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for VerdictFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerdictFormat::Json => write!(f, "json"),
+            VerdictFormat::Junit => write!(f, "junit"),
+        }
+    }
+}
+
+///Bit field of the report together with the names of the known flags that are set
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DecodedFlags {
+    pub value: u64,
+    pub set: Vec<&'static str>,
+}
+
+impl DecodedFlags {
+    fn new(value: u64, flags: &[Flag]) -> Self {
+        DecodedFlags {
+            value,
+            set: flags
+                .iter()
+                .filter(|v| value & v.mask() != 0)
+                .map(|v| v.name)
+                .collect(),
+        }
+    }
+}
+
+///Fields of an attestation report, with byte strings hex encoded
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DecodedReport {
+    pub version: u32,
+    pub guest_svn: u32,
+    pub guest_policy: DecodedFlags,
+    ///Minimum firmware ABI version from the guest policy
+    pub guest_abi: AbiVersion,
+    pub family_id: HexBytes<16>,
+    pub image_id: HexBytes<16>,
+    pub vmpl: u32,
+    pub sig_algo: u32,
+    #[serde(flatten)]
+    pub details: ReportDetails,
+    pub current_tcb: TcbLevels,
+    pub platform_info: DecodedFlags,
+    pub report_data: HexBytes<64>,
+    pub measurement: HexBytes<48>,
+    pub host_data: HexBytes<32>,
+    pub id_key_digest: HexBytes<48>,
+    pub author_key_digest: HexBytes<48>,
+    pub report_id: HexBytes<32>,
+    pub report_id_ma: HexBytes<32>,
+    pub reported_tcb: TcbLevels,
+    pub chip_id: HexBytes<64>,
+    pub committed_tcb: TcbLevels,
+    ///Firmware version as major.minor.build
+    pub current_version: String,
+    pub committed_version: String,
+    pub launch_tcb: TcbLevels,
+    pub signature_r: HexBytes<72>,
+    pub signature_s: HexBytes<72>,
+}
+
+impl DecodedReport {
+    ///The TCB versions are decoded in the layout of `product_name`, see `TcbLayout::for_product`
+    pub fn new(report: &AttestationReport, product_name: ProductName) -> Self {
+        let layout = TcbLayout::for_product(product_name);
+        DecodedReport {
+            version: report.version,
+            guest_svn: report.guest_svn,
+            guest_policy: DecodedFlags::new(report.policy.0, GUEST_POLICY_FLAGS),
+            guest_abi: AbiVersion::of_policy(report.policy.0),
+            family_id: HexBytes(report.family_id),
+            image_id: HexBytes(report.image_id),
+            vmpl: report.vmpl,
+            sig_algo: report.sig_algo,
+            details: ReportDetails::new(report),
+            current_tcb: layout.decode(&report.current_tcb),
+            platform_info: DecodedFlags::new(report.plat_info.0, PLATFORM_INFO_FLAGS),
+            report_data: HexBytes(report.report_data),
+            measurement: HexBytes(report.measurement),
+            host_data: HexBytes(report.host_data),
+            id_key_digest: HexBytes(report.id_key_digest),
+            author_key_digest: HexBytes(report.author_key_digest),
+            report_id: HexBytes(report.report_id),
+            report_id_ma: HexBytes(report.report_id_ma),
+            reported_tcb: layout.decode(&report.reported_tcb),
+            chip_id: HexBytes(report.chip_id),
+            committed_tcb: layout.decode(&report.committed_tcb),
+            current_version: format!(
+                "{}.{}.{}",
+                report.current_major, report.current_minor, report.current_build
+            ),
+            committed_version: format!(
+                "{}.{}.{}",
+                report.committed_major, report.committed_minor, report.committed_build
+            ),
+            launch_tcb: layout.decode(&report.launch_tcb),
+            signature_r: HexBytes(*report.signature.r()),
+            signature_s: HexBytes(*report.signature.s()),
+        }
+    }
+}
+
+///One certificate of the chain that was used to verify the report signature
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CertificateInfo {
+    ///ARK, ASK or VCEK
+    pub role: &'static str,
+    pub subject: String,
+    pub issuer: String,
+    pub serial: String,
+    pub not_before: String,
+    pub not_after: String,
+    pub sha256_fingerprint: HexBytes<32>,
+    pub pem: String,
+}
+
+fn name_to_string(name: &X509NameRef) -> String {
+    let entries: Vec<String> = name
+        .entries()
+        .map(|v| {
+            let key = v.object().nid().short_name().unwrap_or("?");
+            let value = v
+                .data()
+                .as_utf8()
+                .map(|v| v.to_string())
+                .unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect();
+    entries.join(", ")
+}
+
+impl CertificateInfo {
+    fn new(role: &'static str, cert: &Certificate) -> Result<Self, Whatever> {
+        let x509: &openssl::x509::X509 = cert.into();
+        let fingerprint = x509
+            .digest(MessageDigest::sha256())
+            .whatever_context("failed to hash certificate")?;
+        let serial = x509
+            .serial_number()
+            .to_bn()
+            .and_then(|v| v.to_hex_str().map(|v| v.to_string()))
+            .whatever_context("failed to decode certificate serial number")?;
+        let pem = x509
+            .to_pem()
+            .whatever_context("failed to encode certificate as PEM")?;
+        Ok(CertificateInfo {
+            role,
+            subject: name_to_string(x509.subject_name()),
+            issuer: name_to_string(x509.issuer_name()),
+            serial,
+            not_before: x509.not_before().to_string(),
+            not_after: x509.not_after().to_string(),
+            sha256_fingerprint: HexBytes(
+                fingerprint
+                    .as_ref()
+                    .try_into()
+                    .whatever_context("unexpected SHA-256 digest length")?,
+            ),
+            pem: String::from_utf8_lossy(&pem).into_owned(),
+        })
+    }
+}
+
+///Outcome of the verification of one report
+#[derive(Serialize, Debug, Clone)]
+pub struct Verdict {
+    ///See `VERDICT_FORMAT_VERSION`
+    pub format_version: u32,
+    ///True if all checks passed. This is the final verdict
+    pub passed: bool,
+    ///CPU generation that determines the certificate chain
+    pub product: ProductName,
+    ///Launch digest computed from the vm config
+    pub expected_launch_digest: Option<HexBytes<48>>,
+    pub report: DecodedReport,
+    ///ARK, ASK and VCEK
+    pub cert_chain: Vec<CertificateInfo>,
+    pub checks: Vec<CheckResult>,
+}
+
+///Escape `s` for use in XML text and attributes. Characters that XML 1.0 does not allow at all,
+///like most control characters, are replaced by U+FFFD
+fn xml_escape(s: &str) -> String {
+    s.chars()
+        .map(|v| match v {
+            '\t' | '\n' | '\r' => v,
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => char::REPLACEMENT_CHARACTER,
+            v => v,
+        })
+        .collect::<String>()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+impl Verdict {
+    ///`summary` is the outcome of `evaluate_report` for `report`
+    pub fn new(
+        report: &AttestationReport,
+        product_name: ProductName,
+        vcek_cert: &Certificate,
+        expected_launch_digest: Option<[u8; 48]>,
+        summary: VerificationSummary,
+    ) -> Result<Self, Whatever> {
+        let ca = amd_ca_chain(product_name)?;
+        Ok(Verdict {
+            format_version: VERDICT_FORMAT_VERSION,
+            passed: summary.passed(),
+            product: product_name,
+            expected_launch_digest: expected_launch_digest.map(HexBytes),
+            report: DecodedReport::new(report, product_name),
+            cert_chain: vec![
+                CertificateInfo::new("ARK", &ca.ark)?,
+                CertificateInfo::new("ASK", &ca.ask)?,
+                CertificateInfo::new("VCEK", vcek_cert)?,
+            ],
+            checks: summary.checks,
+        })
+    }
+
+    ///JUnit XML with one test case per check
+    pub fn to_junit(&self) -> String {
+        let failures = self.checks.iter().filter(|v| v.failure.is_some()).count();
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        out += &format!(
+            "<testsuite name=\"snpguard attestation\" tests=\"{}\" failures=\"{}\" errors=\"0\">\n",
+            self.checks.len(),
+            failures
+        );
+        out += "  <properties>\n";
+        let mut properties = vec![
+            ("product", self.product.to_string()),
+            ("chip_id", hex::encode(self.report.chip_id.0)),
+            ("measurement", hex::encode(self.report.measurement.0)),
+        ];
+        if let Some(ld) = &self.expected_launch_digest {
+            properties.push(("expected_launch_digest", hex::encode(ld.0)));
+        }
+        for (name, value) in properties {
+            out += &format!(
+                "    <property name=\"{}\" value=\"{}\"/>\n",
+                name,
+                xml_escape(&value)
+            );
+        }
+        out += "  </properties>\n";
+        for check in &self.checks {
+            out += &format!(
+                "  <testcase classname=\"attestation_report\" name=\"{}\">",
+                xml_escape(check.check)
+            );
+            if let Some(reason) = &check.failure {
+                let details = format!(
+                    "expected: {}\ngot: {}",
+                    check.expected.as_deref().unwrap_or("-"),
+                    check.got.as_deref().unwrap_or("-")
+                );
+                out += &format!(
+                    "\n    <failure message=\"{}\">{}</failure>\n  ",
+                    xml_escape(reason),
+                    xml_escape(&details)
+                );
+            }
+            out += "</testcase>\n";
+        }
+        out += "</testsuite>\n";
+        out
+    }
+
+    pub fn render(&self, format: VerdictFormat) -> Result<String, Whatever> {
+        match format {
+            VerdictFormat::Json => {
+                serde_json::to_string_pretty(self).whatever_context("failed to serialize verdict")
+            }
+            VerdictFormat::Junit => Ok(self.to_junit()),
+        }
+    }
+
+    pub fn write(&self, path: &Path, format: VerdictFormat) -> Result<(), Whatever> {
+        fs::write(path, self.render(format)?)
+            .whatever_context(format!("failed to write verdict to {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use sev::{certs::snp::Certificate, firmware::guest::AttestationReport};
+
+    use super::{Verdict, VerdictFormat};
+    use crate::snp_validate_report::{
+        evaluate_report, CheckResult, ProductName, ReportVerificationError, VerificationPolicy,
+    };
+
+    ///Evaluate the benign report against `policy` and turn the outcome into a verdict
+    fn verdict(
+        policy: &str,
+        report_data_validator: fn([u8; 64]) -> Result<(), ReportVerificationError>,
+    ) -> Verdict {
+        let report: AttestationReport =
+            serde_json::from_slice(&fs::read("./test-data/benign-report.json").unwrap()).unwrap();
+        let cert = Certificate::from_bytes(&fs::read("./test-data/vcek.crt").unwrap()).unwrap();
+        let policy: VerificationPolicy = toml::from_str(policy).unwrap();
+        let summary = evaluate_report(
+            &report,
+            ProductName::Milan,
+            cert.clone(),
+            &policy,
+            Some(report_data_validator),
+        );
+        Verdict::new(&report, ProductName::Milan, &cert, Some([0; 48]), summary).unwrap()
+    }
+
+    ///Child elements of `node` named `name`
+    fn children<'a, 'input>(
+        node: roxmltree::Node<'a, 'input>,
+        name: &'a str,
+    ) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> {
+        node.children().filter(move |v| v.has_tag_name(name))
+    }
+
+    #[test]
+    fn json_verdict() {
+        let passing = verdict("guest_svn = { exact = 0 }", |_| Ok(()));
+        let json: serde_json::Value =
+            serde_json::from_str(&passing.render(VerdictFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["passed"], true);
+        assert_eq!(json["report"]["guest_policy"]["set"][0], "smt_allowed");
+        let roles: Vec<&str> = json["cert_chain"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, ["ARK", "ASK", "VCEK"]);
+        assert!(json["checks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|v| v["failure"].is_null()));
+
+        let failing = verdict("guest_svn = { exact = 1 }", |_| Ok(()));
+        let json: serde_json::Value =
+            serde_json::from_str(&failing.render(VerdictFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["passed"], false);
+        let checks = json["checks"].as_array().unwrap();
+        let failed: Vec<&serde_json::Value> =
+            checks.iter().filter(|v| !v["failure"].is_null()).collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0]["expected"], "1");
+        assert_eq!(failed[0]["got"], "0");
+        let signature = checks.iter().find(|v| v["check"] == "signature").unwrap();
+        assert!(signature["failure"].is_null(), "signature must be valid");
+    }
+
+    #[test]
+    fn junit_verdict() {
+        let mut verdict = verdict("guest_svn = { exact = 1 }", |_| {
+            Err(ReportVerificationError::ReportDataMismatch {
+                expected: "nonce".to_string(),
+                got: "other nonce".to_string(),
+            })
+        });
+        //reasons may contain characters that need escaping
+        verdict.checks.push(CheckResult {
+            check: "escaping",
+            expected: Some("<a & 'b'>".to_string()),
+            got: None,
+            failure: Some("\"quoted\" & <tagged>".to_string()),
+        });
+        //as do control characters, e.g. from a report data validator that prints colors
+        verdict.checks.push(CheckResult {
+            check: "control characters",
+            expected: None,
+            got: None,
+            failure: Some("\u{1b}[31mmismatch\u{0}".to_string()),
+        });
+        let xml = verdict.render(VerdictFormat::Junit).unwrap();
+        let document = roxmltree::Document::parse(&xml).unwrap();
+        let suite = document.root_element();
+        assert!(suite.has_tag_name("testsuite"));
+        let cases: Vec<roxmltree::Node> = children(suite, "testcase").collect();
+        assert_eq!(
+            suite.attribute("tests"),
+            Some(cases.len().to_string().as_str())
+        );
+        let failures: Vec<(&str, roxmltree::Node)> = cases
+            .iter()
+            .filter_map(|v| Some((v.attribute("name")?, children(*v, "failure").next()?)))
+            .collect();
+        assert_eq!(
+            suite.attribute("failures"),
+            Some(failures.len().to_string().as_str())
+        );
+        let failed: Vec<&str> = failures.iter().map(|v| v.0).collect();
+        assert_eq!(
+            failed,
+            ["guest svn", "report data", "escaping", "control characters"]
+        );
+        assert_eq!(
+            failures[2].1.attribute("message"),
+            Some("\"quoted\" & <tagged>")
+        );
+        assert_eq!(failures[2].1.text(), Some("expected: <a & 'b'>\ngot: -"));
+        assert_eq!(
+            failures[3].1.attribute("message"),
+            Some("\u{fffd}[31mmismatch\u{fffd}")
+        );
+        assert!(cases
+            .iter()
+            .any(|v| v.attribute("name") == Some("signature") && !v.has_children()));
+
+        let properties = children(suite, "properties").next().unwrap();
+        let measurement = children(properties, "property")
+            .find(|v| v.attribute("name") == Some("measurement"))
+            .unwrap();
+        assert_eq!(
+            measurement.attribute("value"),
+            Some(hex::encode(verdict.report.measurement.0).as_str())
+        );
+    }
+}